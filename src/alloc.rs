@@ -0,0 +1,128 @@
+// a small stand-in for the unstable std::alloc::Allocator trait, so Rc can
+// be generic over where its allocation lives on stable rust
+//
+// Global is the default and just forwards to the global allocator, so
+// Rc<T> (= Rc<T, Global>) behaves exactly like it did before
+//
+// the rc-pool feature's recycling lives in Rc itself, not here, see pool
+use std::alloc::Layout;
+use std::fmt;
+use std::ptr::NonNull;
+
+#[cfg(feature = "rc-pool")]
+pub(crate) mod pool;
+
+/// returned when an allocator can't satisfy a request
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AllocError;
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("memory allocation failed")
+    }
+}
+
+impl std::error::Error for AllocError {}
+
+/// # Safety
+///
+/// memory handed out by allocate must stay valid until it's passed back to
+/// deallocate on the same allocator (or a clone of it), since an Rc and all
+/// its clones share the allocation
+pub unsafe trait Allocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError>;
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        let ptr = self.allocate(layout)?;
+        unsafe { ptr.as_ptr().write_bytes(0, layout.size()) };
+        Ok(ptr)
+    }
+
+    /// # Safety
+    ///
+    /// ptr must have come from allocate on this allocator with this layout
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout);
+
+    // whether memory from this allocator comes from the global allocator,
+    // so Rc may keep freed blocks in the pool instead of deallocating them
+    // only Global (and references to it) may say so
+    #[doc(hidden)]
+    #[cfg(feature = "rc-pool")]
+    fn is_global(&self) -> bool {
+        false
+    }
+}
+
+/// the global allocator, i.e. whatever std::alloc::alloc uses
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Global;
+
+// every layout we get has a non-zero size because of the Rc header
+unsafe impl Allocator for Global {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        NonNull::new(unsafe { std::alloc::alloc(layout) }).ok_or(AllocError)
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        NonNull::new(unsafe { std::alloc::alloc_zeroed(layout) }).ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        std::alloc::dealloc(ptr.as_ptr(), layout)
+    }
+
+    #[cfg(feature = "rc-pool")]
+    fn is_global(&self) -> bool {
+        true
+    }
+}
+
+// lets an Rc hold a handle to an allocator that lives somewhere else,
+// e.g. Rc<Node, &Bump>
+unsafe impl<A: Allocator + ?Sized> Allocator for &A {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        (**self).allocate(layout)
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        (**self).allocate_zeroed(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        (**self).deallocate(ptr, layout)
+    }
+
+    #[cfg(feature = "rc-pool")]
+    fn is_global(&self) -> bool {
+        (**self).is_global()
+    }
+}
+
+/// adapts an allocator implementing the unstable std::alloc::Allocator, so
+/// it can back an Rc, e.g. Rc::new_in(value, StdAlloc(&arena))
+#[cfg(feature = "nightly-allocator-api")]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct StdAlloc<A>(pub A);
+
+// std's allocators already promise that memory stays valid until it's
+// deallocated on the allocator or one of its clones
+#[cfg(feature = "nightly-allocator-api")]
+unsafe impl<A: std::alloc::Allocator> Allocator for StdAlloc<A> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        self.0
+            .allocate(layout)
+            .map(NonNull::cast)
+            .map_err(|_| AllocError)
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        self.0
+            .allocate_zeroed(layout)
+            .map(NonNull::cast)
+            .map_err(|_| AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.0.deallocate(ptr, layout)
+    }
+}
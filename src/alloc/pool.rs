@@ -0,0 +1,124 @@
+// allocation recycling, behind the rc-pool feature
+//
+// instead of going back to the global allocator, the blocks of sized Rcs
+// from the global allocator are kept on a per-thread free list when the
+// last reference goes, one list per layout, and Rc::new hands them out
+// again to the next Rc of the same type
+// programs that churn through lots of short lived Rcs of a few node types
+// end up mostly reusing the same handful of blocks
+// nothing else is pooled: slices, str and everything else allocated through
+// Global come and go as usual, so the lists only ever hold a few layouts
+//
+// the list holds at most capacity blocks in total, anything beyond that is
+// freed as usual, and whatever is left when the thread exits is freed then
+use crate::refcell::RefCell;
+use std::alloc::Layout;
+use std::collections::HashMap;
+use std::ptr::NonNull;
+
+const DEFAULT_CAPACITY: usize = 256;
+
+struct Pool {
+    capacity: usize,
+    len: usize,
+    // keyed by (size, align), the parts of a Layout that matter
+    classes: HashMap<(usize, usize), Vec<NonNull<u8>>>,
+}
+
+impl Pool {
+    // free blocks until at most keep are left
+    fn trim(&mut self, keep: usize) {
+        for (&(size, align), blocks) in self.classes.iter_mut() {
+            while self.len > keep {
+                let block = match blocks.pop() {
+                    Some(block) => block,
+                    None => break,
+                };
+                self.len -= 1;
+                // every block was allocated with exactly this layout
+                unsafe {
+                    std::alloc::dealloc(
+                        block.as_ptr(),
+                        Layout::from_size_align_unchecked(size, align),
+                    )
+                };
+            }
+        }
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.trim(0);
+    }
+}
+
+thread_local! {
+    static POOL: RefCell<Pool> = RefCell::new(Pool {
+        capacity: DEFAULT_CAPACITY,
+        len: 0,
+        classes: HashMap::new(),
+    });
+}
+
+// a previously freed block with this layout, if there is one
+pub(crate) fn take(layout: Layout) -> Option<NonNull<u8>> {
+    // the pool may already be gone during thread shutdown
+    POOL.try_with(|pool| {
+        let mut pool = pool
+            .borrow_mut()
+            .expect("pool is never borrowed across calls");
+        let block = pool
+            .classes
+            .get_mut(&(layout.size(), layout.align()))?
+            .pop()?;
+        pool.len -= 1;
+        Some(block)
+    })
+    .ok()
+    .flatten()
+}
+
+// keeps block for later, or gives it back if the pool is full
+//
+// safety: block must have come from the global allocator with layout
+pub(crate) unsafe fn give(block: NonNull<u8>, layout: Layout) -> Result<(), NonNull<u8>> {
+    POOL.try_with(|pool| {
+        let mut pool = pool
+            .borrow_mut()
+            .expect("pool is never borrowed across calls");
+        if pool.len >= pool.capacity {
+            return Err(block);
+        }
+        pool.len += 1;
+        pool.classes
+            .entry((layout.size(), layout.align()))
+            .or_default()
+            .push(block);
+        Ok(())
+    })
+    .unwrap_or(Err(block))
+}
+
+/// how many freed Rc allocations this thread keeps around for reuse
+///
+/// lowering it frees whatever doesn't fit anymore
+pub fn set_pool_capacity(capacity: usize) {
+    POOL.with(|pool| {
+        let mut pool = pool
+            .borrow_mut()
+            .expect("pool is never borrowed across calls");
+        pool.capacity = capacity;
+        pool.trim(capacity);
+    })
+}
+
+/// give every allocation this thread is keeping for reuse back to the
+/// global allocator
+pub fn drain_pool() {
+    POOL.with(|pool| {
+        pool.borrow_mut()
+            .expect("pool is never borrowed across calls")
+            .trim(0)
+    })
+}
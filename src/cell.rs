@@ -49,6 +49,7 @@ impl<T> Cell<T> {
 
 #[cfg(test)]
 mod test {
+    #[allow(unused_imports)]
     use super::*;
 
     // should not compile
@@ -0,0 +1,414 @@
+// a cycle collector for GcRc, behind the cycle-collect feature
+//
+// a GcRc is an Rc that registers itself with a per-thread collector, and
+// collect() frees the groups of GcRcs that only keep each other alive
+// everything else is still plain reference counting: a GcRc whose count
+// drops to zero is freed straight away, collect() is only for cycles
+//
+// rooting, i.e. how collect() knows what's still in use, works by trial
+// deletion instead of by scanning the stack, which rust can't do:
+// - every registered allocation's value is traced, and each edge to another
+//   registered allocation is counted as an internal reference to it
+// - any strong count the internal references don't explain has to come
+//   from outside, a local variable, a plain Rc, a static, ..., so an
+//   allocation with strong_count > internal is a root
+// - everything reachable from a root through traced edges is alive, the
+//   rest is garbage
+// an edge Trace misses (a RefCell that's mutably borrowed, or a GcRc behind
+// a plain Rc) only makes its target look more external, so missing edges
+// can keep garbage around but never free something in use
+//
+// garbage is freed by taking the values out of their allocations and
+// dropping them, which drops the GcRcs they hold and lets the counts fall
+// to zero as usual, so each destructor runs exactly once
+// a destructor that looks at another GcRc from the same garbage group may
+// find its value already gone, so borrow panics there
+// a value that's borrowed while collect() runs is never taken, whatever its
+// Trace says
+//
+// registered allocations that were freed the normal way stay in the
+// registry, their Weak keeping the block around, until the next collect(),
+// or until new() finds the registry has doubled since it was last pruned, so
+// a program that never calls collect() keeps it in proportion to what's live
+use crate::rc::{Rc, Weak};
+use crate::refcell::{Ref, RefCell};
+use crate::trace::{ErasedRc, Trace};
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+
+thread_local! {
+    static REGISTRY: RefCell<Registry> = RefCell::new(Registry {
+        entries: Vec::new(),
+        pruned_len: 0,
+    });
+}
+
+struct Registry {
+    entries: Vec<Box<dyn Registered>>,
+    // how many entries were left after the last prune or collect()
+    pruned_len: usize,
+}
+
+// a registry smaller than this is never pruned, which would be mostly
+// overhead for a handful of entries
+const MIN_PRUNE_LEN: usize = 32;
+
+impl Registry {
+    fn push(&mut self, entry: Box<dyn Registered>) {
+        if self.entries.len() >= 2 * self.pruned_len.max(MIN_PRUNE_LEN / 2) {
+            self.entries.retain(|entry| entry.is_alive());
+            self.pruned_len = self.entries.len();
+        }
+        self.entries.push(entry);
+    }
+}
+
+struct GcBox<T> {
+    // None once the collector has taken the value
+    value: RefCell<Option<T>>,
+}
+
+impl<T: Trace> Trace for GcBox<T> {
+    fn trace(&self, visitor: &mut dyn FnMut(&dyn ErasedRc)) {
+        self.value.trace(visitor)
+    }
+}
+
+/// a reference counted pointer that collect() can free when it's part of
+/// an unreachable cycle
+pub struct GcRc<T: Trace + 'static> {
+    rc: Rc<GcBox<T>>,
+}
+
+impl<T: Trace + 'static> GcRc<T> {
+    /// allocate value and register it with this thread's collector
+    pub fn new(value: T) -> Self {
+        let rc = Rc::new(GcBox {
+            value: RefCell::new(Some(value)),
+        });
+        let weak = Rc::downgrade(&rc);
+        REGISTRY.with(|registry| {
+            registry
+                .borrow_mut()
+                .expect("the registry is never borrowed across calls")
+                .push(Box::new(weak))
+        });
+        GcRc { rc }
+    }
+
+    /// the value, or None if the collector already freed it, which can only
+    /// be seen from the destructors of the garbage it freed
+    pub fn try_borrow(&self) -> Option<GcRef<'_, T>> {
+        let value = self.rc.value.borrow()?;
+        if value.is_some() {
+            Some(GcRef { value })
+        } else {
+            None
+        }
+    }
+
+    /// the value
+    ///
+    /// panics if the collector already freed it, see try_borrow
+    pub fn borrow(&self) -> GcRef<'_, T> {
+        self.try_borrow()
+            .expect("value was freed by the cycle collector")
+    }
+
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        Rc::ptr_eq(&this.rc, &other.rc)
+    }
+
+    pub fn strong_count(this: &Self) -> usize {
+        Rc::strong_count(&this.rc)
+    }
+}
+
+impl<T: Trace + 'static> Clone for GcRc<T> {
+    fn clone(&self) -> Self {
+        GcRc {
+            rc: self.rc.clone(),
+        }
+    }
+}
+
+impl<T: Trace + 'static> Trace for GcRc<T> {
+    fn trace(&self, visitor: &mut dyn FnMut(&dyn ErasedRc)) {
+        visitor(&self.rc)
+    }
+}
+
+impl<T: Trace + fmt::Debug + 'static> fmt::Debug for GcRc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_borrow() {
+            Some(value) => fmt::Debug::fmt(&*value, f),
+            None => f.write_str("(collected)"),
+        }
+    }
+}
+
+/// a borrow of a GcRc's value, which keeps collect() from taking it
+pub struct GcRef<'a, T> {
+    value: Ref<'a, Option<T>>,
+}
+
+impl<T> Deref for GcRef<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+            .as_ref()
+            .expect("checked when the GcRef was made")
+    }
+}
+
+// the registry's view of an allocation
+trait Registered {
+    fn upgrade(&self) -> Option<Box<dyn Live>>;
+    fn is_alive(&self) -> bool;
+}
+
+impl<T: Trace + 'static> Registered for Weak<GcBox<T>> {
+    fn upgrade(&self) -> Option<Box<dyn Live>> {
+        Weak::upgrade(self).map(|rc| Box::new(rc) as Box<dyn Live>)
+    }
+
+    fn is_alive(&self) -> bool {
+        self.strong_count() > 0
+    }
+}
+
+trait Live {
+    fn address(&self) -> usize;
+    fn strong_count(&self) -> usize;
+    fn trace(&self, visitor: &mut dyn FnMut(&dyn ErasedRc));
+    // None if the value is borrowed or already gone
+    fn take(&self) -> Option<Box<dyn Any>>;
+    fn downgrade(&self) -> Box<dyn Registered>;
+}
+
+impl<T: Trace + 'static> Live for Rc<GcBox<T>> {
+    fn address(&self) -> usize {
+        // the same as ErasedRc::address, so edges can be matched up
+        Rc::as_ptr(self) as usize
+    }
+
+    fn strong_count(&self) -> usize {
+        Rc::strong_count(self)
+    }
+
+    fn trace(&self, visitor: &mut dyn FnMut(&dyn ErasedRc)) {
+        (**self).trace(visitor)
+    }
+
+    fn take(&self) -> Option<Box<dyn Any>> {
+        let value = self.value.borrow_mut()?.take()?;
+        Some(Box::new(value))
+    }
+
+    fn downgrade(&self) -> Box<dyn Registered> {
+        Box::new(Rc::downgrade(self))
+    }
+}
+
+/// free every registered allocation that's only kept alive by a cycle,
+/// returning how many values were dropped
+pub fn collect() -> usize {
+    // taken out for the duration, so Trace impls and destructors can make
+    // new GcRcs
+    let registered = REGISTRY.with(|registry| {
+        std::mem::take(
+            &mut registry
+                .borrow_mut()
+                .expect("the registry is never borrowed across calls")
+                .entries,
+        )
+    });
+    // each holds one strong reference of its own from here on
+    let live: Vec<Box<dyn Live>> = registered.iter().filter_map(|r| r.upgrade()).collect();
+    drop(registered);
+
+    let index: HashMap<usize, usize> = live
+        .iter()
+        .enumerate()
+        .map(|(i, node)| (node.address(), i))
+        .collect();
+    let mut edges = vec![Vec::new(); live.len()];
+    let mut internal = vec![0; live.len()];
+    for (from, node) in live.iter().enumerate() {
+        node.trace(&mut |rc| {
+            if let Some(&to) = index.get(&rc.address()) {
+                edges[from].push(to);
+                internal[to] += 1;
+            }
+        });
+    }
+
+    // mark from the roots, with an explicit stack for long chains
+    let mut alive = vec![false; live.len()];
+    let mut stack: Vec<usize> = (0..live.len())
+        // minus the reference live holds
+        .filter(|&i| live[i].strong_count() - 1 > internal[i])
+        .collect();
+    while let Some(i) = stack.pop() {
+        if !alive[i] {
+            alive[i] = true;
+            stack.extend(edges[i].iter().copied().filter(|&to| !alive[to]));
+        }
+    }
+
+    let mut garbage = Vec::new();
+    let mut survivors = Vec::new();
+    for (node, alive) in live.iter().zip(alive) {
+        match if alive { None } else { node.take() } {
+            Some(value) => garbage.push(value),
+            None => survivors.push(node.downgrade()),
+        }
+    }
+    let collected = garbage.len();
+    drop(live);
+    // destructors run here, with the registry usable again
+    REGISTRY.with(|registry| {
+        let mut registry = registry
+            .borrow_mut()
+            .expect("the registry is never borrowed across calls");
+        registry.entries.extend(survivors);
+        registry.pruned_len = registry.entries.len();
+    });
+    drop(garbage);
+    collected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    struct Node {
+        next: RefCell<Option<GcRc<Node>>>,
+        drops: std::rc::Rc<Cell<usize>>,
+    }
+
+    impl Trace for Node {
+        fn trace(&self, visitor: &mut dyn FnMut(&dyn ErasedRc)) {
+            self.next.trace(visitor)
+        }
+    }
+
+    impl Drop for Node {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+        }
+    }
+
+    fn node(drops: &std::rc::Rc<Cell<usize>>) -> GcRc<Node> {
+        GcRc::new(Node {
+            next: RefCell::new(None),
+            drops: drops.clone(),
+        })
+    }
+
+    fn link(from: &GcRc<Node>, to: &GcRc<Node>) {
+        *from.borrow().next.borrow_mut().unwrap() = Some(to.clone());
+    }
+
+    // each test runs on its own thread, so has its own registry
+    #[test]
+    fn empty_registry() {
+        assert_eq!(collect(), 0);
+        assert_eq!(collect(), 0);
+    }
+
+    #[test]
+    fn isolated_cycle_is_collected() {
+        let drops = std::rc::Rc::new(Cell::new(0));
+        let a = node(&drops);
+        let b = node(&drops);
+        link(&a, &b);
+        link(&b, &a);
+        let weak = Rc::downgrade(&a.rc);
+        drop((a, b));
+        assert_eq!(drops.get(), 0);
+
+        assert_eq!(collect(), 2);
+        assert_eq!(drops.get(), 2);
+        assert!(weak.upgrade().is_none());
+        // and they're gone from the registry
+        assert_eq!(collect(), 0);
+        assert_eq!(drops.get(), 2);
+    }
+
+    #[test]
+    fn rooted_cycle_is_kept() {
+        let drops = std::rc::Rc::new(Cell::new(0));
+        let a = node(&drops);
+        let b = node(&drops);
+        link(&a, &b);
+        link(&b, &a);
+        drop(b);
+
+        assert_eq!(collect(), 0);
+        assert_eq!(drops.get(), 0);
+        // both still usable, through the cycle too
+        let b = a.borrow().next.borrow().unwrap().clone().unwrap();
+        assert!(b.borrow().next.borrow().unwrap().is_some());
+        assert_eq!(GcRc::strong_count(&a), 2);
+        drop(b);
+
+        drop(a);
+        assert_eq!(collect(), 2);
+        assert_eq!(drops.get(), 2);
+    }
+
+    #[test]
+    fn kept_alive_by_plain_rc() {
+        let drops = std::rc::Rc::new(Cell::new(0));
+        let a = node(&drops);
+        link(&a, &a);
+        let outside = Rc::new(a);
+        assert_eq!(collect(), 0);
+        drop(outside);
+        assert_eq!(collect(), 1);
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[test]
+    fn acyclic_garbage_is_freed_without_collect() {
+        let drops = std::rc::Rc::new(Cell::new(0));
+        let a = node(&drops);
+        let b = node(&drops);
+        link(&a, &b);
+        drop((a, b));
+        assert_eq!(drops.get(), 2);
+        assert_eq!(collect(), 0);
+    }
+
+    fn registered() -> usize {
+        REGISTRY.with(|registry| registry.borrow().unwrap().entries.len())
+    }
+
+    #[test]
+    fn registry_stays_bounded_without_collect() {
+        let drops = std::rc::Rc::new(Cell::new(0));
+        let kept: Vec<_> = (0..10).map(|_| node(&drops)).collect();
+        let rounds = if cfg!(miri) { 200 } else { 10_000 };
+        for _ in 0..rounds {
+            drop(node(&drops));
+            assert!(
+                registered() <= 2 * MIN_PRUNE_LEN,
+                "{} registered",
+                registered()
+            );
+        }
+        assert_eq!(drops.get(), rounds);
+        // the live ones were never pruned: a cycle among them is still found
+        link(&kept[0], &kept[1]);
+        link(&kept[1], &kept[0]);
+        drop(kept);
+        assert_eq!(drops.get(), rounds + 8);
+        assert_eq!(collect(), 2);
+        assert_eq!(drops.get(), rounds + 10);
+    }
+}
@@ -0,0 +1,42 @@
+// the parts of building an Rc<[T]> or sync::Arc<[T]> in place that don't
+// care which one it is: both allocate a header of counts followed by the
+// elements in one block, and fill the elements one at a time
+use std::alloc::Layout;
+use std::mem::MaybeUninit;
+
+// the layout of a header H followed by len elements, padded out to T's
+// alignment
+// for a repr(C) struct with the slice as its last field this is exactly what
+// Layout::for_value reports once it's built
+pub(crate) fn layout<H, T>(len: usize) -> Layout {
+    Layout::new::<H>()
+        .extend(Layout::array::<T>(len).expect("capacity overflow"))
+        .expect("capacity overflow")
+        .0
+        .pad_to_align()
+}
+
+// writes f(i) into every slot in order
+// if f panics, the elements written so far are dropped and the slots after
+// them left alone; freeing the block is up to whoever owns it
+pub(crate) fn fill<T, F: FnMut(usize) -> T>(slots: &mut [MaybeUninit<T>], mut f: F) {
+    struct Guard<'a, T> {
+        slots: &'a mut [MaybeUninit<T>],
+        written: usize,
+    }
+
+    impl<T> Drop for Guard<'_, T> {
+        fn drop(&mut self) {
+            for slot in &mut self.slots[..self.written] {
+                unsafe { slot.assume_init_drop() };
+            }
+        }
+    }
+
+    let mut guard = Guard { slots, written: 0 };
+    while guard.written < guard.slots.len() {
+        guard.slots[guard.written].write(f(guard.written));
+        guard.written += 1;
+    }
+    std::mem::forget(guard);
+}
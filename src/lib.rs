@@ -1,6 +1,33 @@
+#![cfg_attr(
+    feature = "nightly",
+    feature(
+        coerce_unsized,
+        unsize,
+        dispatch_from_dyn,
+        dropck_eyepatch,
+        layout_for_ptr,
+        min_specialization,
+        rustc_attrs
+    ),
+    allow(internal_features)
+)]
+#![cfg_attr(feature = "nightly-allocator-api", feature(allocator_api))]
+#![cfg_attr(all(test, feature = "nightly"), feature(arbitrary_self_types))]
+
+pub mod alloc;
 pub mod cell;
+#[cfg(feature = "cycle-collect")]
+pub mod collector;
+mod header_slice;
+pub mod once_cell;
 pub mod rc;
 pub mod refcell;
+pub mod shared;
+pub mod sync;
+pub mod trace;
+
+#[cfg(feature = "serde")]
+mod serde_impls;
 
 #[cfg(test)]
 mod tests {
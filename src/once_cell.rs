@@ -0,0 +1,180 @@
+// a cell that's written at most once, after which shared references to the
+// value can be handed out freely, since it never changes again
+//
+// single threaded like Cell: the UnsafeCell makes it !Sync, so nothing can
+// be writing it while get hands out a reference on another thread
+use std::cell::UnsafeCell;
+use std::convert::Infallible;
+use std::fmt;
+
+pub struct OnceCell<T> {
+    // None until set, and never touched again after that except through
+    // &mut self
+    value: UnsafeCell<Option<T>>,
+}
+
+impl<T> OnceCell<T> {
+    pub const fn new() -> Self {
+        OnceCell {
+            value: UnsafeCell::new(None),
+        }
+    }
+
+    /// the value, if it's been set
+    pub fn get(&self) -> Option<&T> {
+        // once it's Some nothing writes it again while self is shared, so
+        // the reference stays valid for as long as &self
+        unsafe { &*self.value.get() }.as_ref()
+    }
+
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        self.value.get_mut().as_mut()
+    }
+
+    /// set the value, or give it back if the cell already has one
+    pub fn set(&self, value: T) -> Result<(), T> {
+        if self.get().is_some() {
+            return Err(value);
+        }
+        // it's None, so no references into it are around to invalidate
+        unsafe { *self.value.get() = Some(value) };
+        Ok(())
+    }
+
+    /// the value, running f to set it first if it hasn't been
+    ///
+    /// panics if f sets the cell itself, e.g. through get_or_init, since
+    /// that value may already be borrowed
+    pub fn get_or_init<F: FnOnce() -> T>(&self, f: F) -> &T {
+        match self.get_or_try_init(|| Ok::<T, Infallible>(f())) {
+            Ok(value) => value,
+            Err(never) => match never {},
+        }
+    }
+
+    /// like get_or_init, but if f fails the cell stays empty and the error
+    /// is returned, so a later call can try again
+    pub fn get_or_try_init<E, F: FnOnce() -> Result<T, E>>(&self, f: F) -> Result<&T, E> {
+        if let Some(value) = self.get() {
+            return Ok(value);
+        }
+        let value = f()?;
+        assert!(self.set(value).is_ok(), "OnceCell set while initializing");
+        Ok(self.get().unwrap())
+    }
+
+    /// the value, leaving the cell empty
+    pub fn take(&mut self) -> Option<T> {
+        self.value.get_mut().take()
+    }
+
+    pub fn into_inner(self) -> Option<T> {
+        self.value.into_inner()
+    }
+}
+
+impl<T> Default for OnceCell<T> {
+    fn default() -> Self {
+        OnceCell::new()
+    }
+}
+
+impl<T> From<T> for OnceCell<T> {
+    fn from(value: T) -> Self {
+        OnceCell {
+            value: UnsafeCell::new(Some(value)),
+        }
+    }
+}
+
+impl<T: Clone> Clone for OnceCell<T> {
+    fn clone(&self) -> Self {
+        OnceCell {
+            value: UnsafeCell::new(self.get().cloned()),
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for OnceCell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_tuple("OnceCell");
+        match self.get() {
+            Some(value) => d.field(value),
+            None => d.field(&format_args!("<uninit>")),
+        };
+        d.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::Cell;
+
+    #[test]
+    fn set_and_get() {
+        let cell = OnceCell::new();
+        assert_eq!(cell.get(), None);
+        assert_eq!(cell.set(String::from("first")), Ok(()));
+        // references from get stay good across failed sets
+        let first = cell.get().unwrap();
+        assert_eq!(
+            cell.set(String::from("second")),
+            Err(String::from("second"))
+        );
+        assert_eq!(first, "first");
+        assert_eq!(cell.into_inner().as_deref(), Some("first"));
+    }
+
+    #[test]
+    fn get_or_init_runs_once() {
+        let cell = OnceCell::new();
+        let runs = Cell::new(0);
+        let init = || {
+            runs.set(runs.get() + 1);
+            42
+        };
+        assert_eq!(*cell.get_or_init(init), 42);
+        assert_eq!(*cell.get_or_init(init), 42);
+        assert_eq!(runs.get(), 1);
+        assert_eq!(cell.set(0), Err(0));
+    }
+
+    #[test]
+    fn get_or_try_init() {
+        let cell = OnceCell::new();
+        let runs = Cell::new(0);
+        let fail = || {
+            runs.set(runs.get() + 1);
+            Err("not yet")
+        };
+        assert_eq!(cell.get_or_try_init(fail), Err("not yet"));
+        // a failure leaves it empty, for the next try
+        assert_eq!(cell.get(), None);
+        assert_eq!(
+            cell.get_or_try_init(|| Ok::<_, ()>(String::from("ok"))),
+            Ok(&String::from("ok"))
+        );
+        // and once it's set, f isn't called at all
+        assert_eq!(cell.get_or_try_init(fail), Ok(&String::from("ok")));
+        assert_eq!(runs.get(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "set while initializing")]
+    fn reentrant_init_panics() {
+        let cell = OnceCell::new();
+        cell.get_or_init(|| *cell.get_or_init(|| 1) + 1);
+    }
+
+    #[test]
+    fn mut_access() {
+        let mut cell = OnceCell::from(vec![1]);
+        cell.get_mut().unwrap().push(2);
+        assert_eq!(format!("{:?}", cell), "OnceCell([1, 2])");
+        assert_eq!(cell.take(), Some(vec![1, 2]));
+        assert_eq!(format!("{:?}", cell), "OnceCell(<uninit>)");
+        assert_eq!(cell.set(vec![3]), Ok(()));
+        assert_eq!(cell.clone().into_inner(), Some(vec![3]));
+    }
+}
@@ -12,13 +12,79 @@
 // !Sync + !Send
 // not thread safe
 //
+use crate::alloc::{AllocError, Allocator, Global};
 use crate::cell::Cell;
+use std::alloc::Layout;
+use std::any::Any;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fmt;
 use std::marker::PhantomData;
-use std::ptr::NonNull;
+use std::mem::{self, ManuallyDrop, MaybeUninit};
+use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::path::{Path, PathBuf};
+use std::ptr::{self, NonNull};
 
-struct RcInner<T> {
+mod by_address;
+mod count;
+#[cfg(feature = "cycle-debug")]
+mod cycle;
+#[cfg(feature = "leak-track")]
+mod leak_track;
+mod linked;
+mod proj;
+mod reflexive;
+mod unique;
+mod weak_cell;
+mod weak_self;
+
+#[cfg(feature = "rc-pool")]
+pub use crate::alloc::pool::{drain_pool, set_pool_capacity};
+pub use by_address::RcByAddress;
+pub use count::Count;
+#[cfg(feature = "leak-track")]
+pub use leak_track::{live_allocations, live_count, LiveAlloc};
+pub use linked::LinkedNode;
+pub use proj::RcProj;
+pub use reflexive::ReflexiveEq;
+pub use unique::UniqueRc;
+pub use weak_cell::WeakCell;
+pub use weak_self::{HasWeakSelf, WeakSelf};
+
+// repr(C) so the header always comes first and the value is the last field,
+// which is what lets T be unsized and lets us compute the layout of an
+// RcInner<[T], C> by hand
+//
+// strong counts the Rcs, weak counts the Weaks plus one extra weak shared by
+// all the Rcs together
+// the value is dropped when strong hits 0, but the allocation has to stay
+// around until weak hits 0 too, since Weaks still look at the counts
+// the extra weak means the Rcs keep the allocation alive as one unit, so
+// Rc::drop only has to think about freeing it after the value is gone
+//
+// C is the integer type of the counts, see Count
+#[repr(C)]
+struct RcInner<T: ?Sized, C = usize> {
+    strong: Cell<C>,
+    weak: Cell<C>,
     value: T,
-    refcount: Cell<usize>,
+}
+
+impl<T: ?Sized, C: Count> RcInner<T, C> {
+    // the counts, borrowed on their own straight from the raw pointer
+    // going through &RcInner would also claim the value is valid and not
+    // mutably borrowed, neither of which holds once it's been dropped or
+    // while get_mut_unchecked hands it out
+    //
+    // safety: ptr must point to a live allocation that outlives 'a
+    unsafe fn strong<'a>(ptr: *const Self) -> &'a Cell<C> {
+        &*ptr::addr_of!((*ptr).strong)
+    }
+
+    unsafe fn weak<'a>(ptr: *const Self) -> &'a Cell<C> {
+        &*ptr::addr_of!((*ptr).weak)
+    }
 }
 
 // Rust does no know that this type owns a T
@@ -48,58 +114,2300 @@ struct RcInner<T> {
 //
 // the marker makes sure Rust knows to check if T is dropped
 // it lets the compiler know we own T
+// with the nightly feature, Drop is also marked may_dangle, see below
 
-// in the real std lib, Rc supports T: ?Sized i.e. T can be unsized
-pub struct Rc<T> {
-    inner: NonNull<RcInner<T>>,
-    _marker: PhantomData<RcInner<T>>,
+// like the real std lib, Rc supports T: ?Sized i.e. T can be unsized
+// inner is then a fat pointer carrying the slice length or vtable
+//
+// A is where the RcInner is allocated; every clone carries its own copy of
+// the allocator (or handle to it) so the last one to drop can give the
+// memory back to the same place
+//
+// C is the type of the counts: the default usize can never overflow in
+// practice, but programs with millions of tiny nodes can pick u32 to make
+// every allocation smaller
+//
+// inner is a NonNull, never null, so the compiler can use null for None:
+// see the doc comment below, which is a promise to users
+/// a single threaded reference counted pointer
+///
+/// `Option<Rc<T>>` is guaranteed to be the same size as `Rc<T>`, a single
+/// pointer (two for unsized T) when the allocator is the default Global, so
+/// optional links in trees and lists cost nothing extra
+pub struct Rc<T: ?Sized, A: Allocator = Global, C: Count = usize> {
+    inner: NonNull<RcInner<T, C>>,
+    _marker: PhantomData<RcInner<T, C>>,
+    alloc: A,
 }
 
 impl<T> Rc<T> {
+    #[cfg_attr(feature = "leak-track", track_caller)]
     pub fn new(value: T) -> Self {
-        let inner = Box::new(RcInner {
-            value,
-            refcount: Cell::new(1),
+        // reuse the block of an Rc<T> that's already gone, if there is one
+        #[cfg(feature = "rc-pool")]
+        {
+            if let Some(mem) = crate::alloc::pool::take(Layout::new::<RcInner<T>>()) {
+                let inner = init_counts(mem, |mem| mem as *mut RcInner<T>);
+                unsafe {
+                    ptr::addr_of_mut!((*inner.as_ptr()).value).write(value);
+                    return Rc::from_inner_in(inner, Global);
+                }
+            }
+        }
+        Rc::new_with(value, Global)
+    }
+
+    /// like new, but gives back an error instead of aborting the process if
+    /// the allocation fails
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    pub fn try_new(value: T) -> Result<Self, AllocError> {
+        Rc::try_new_with(value, Global)
+    }
+
+    /// like new_uninit, but gives back an error if the allocation fails
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    pub fn try_new_uninit() -> Result<Rc<MaybeUninit<T>>, AllocError> {
+        Rc::try_new(MaybeUninit::uninit())
+    }
+
+    /// allocate space for a T without writing it yet, so a large value can
+    /// be built directly inside the allocation instead of moved into it
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    pub fn new_uninit() -> Rc<MaybeUninit<T>> {
+        Rc::new(MaybeUninit::uninit())
+    }
+
+    /// allocate the header and len uninitialized elements in one block, so
+    /// an Rc<[T]> can be filled in place without a staging Vec
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    pub fn new_uninit_slice(len: usize) -> Rc<[MaybeUninit<T>]> {
+        Rc::uninit_slice_with(len, Global)
+    }
+
+    /// like new_uninit, but the value's bytes are all zero
+    /// handy for plain-old-data and buffers handed to FFI
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    pub fn new_zeroed() -> Rc<MaybeUninit<T>> {
+        let layout = Layout::new::<RcInner<MaybeUninit<T>>>();
+        #[cfg(feature = "rc-pool")]
+        {
+            if let Some(mem) = crate::alloc::pool::take(layout) {
+                unsafe { mem.as_ptr().write_bytes(0, layout.size()) };
+                let inner = init_counts(mem, |mem| mem as *mut RcInner<MaybeUninit<T>>);
+                return unsafe { Rc::from_inner_in(inner, Global) };
+            }
+        }
+        let inner = allocate_for_layout(&Global, layout, true, |mem| {
+            mem as *mut RcInner<MaybeUninit<T>>
+        });
+        unsafe { Rc::from_inner_in(inner, Global) }
+    }
+
+    /// give up this Rc without dropping it, for handing to C code as an
+    /// opaque handle
+    /// the strong reference it held now belongs to the pointer, and has to
+    /// be given back with from_raw or the value leaks
+    pub fn into_raw(this: Self) -> *const T {
+        let ptr = Rc::as_ptr(&this);
+        mem::forget(this);
+        ptr
+    }
+
+    /// take back the strong reference given up by into_raw
+    ///
+    /// # Safety
+    ///
+    /// ptr must have come from Rc::into_raw, and each pointer may only be
+    /// turned back into an Rc once
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        Rc::from_inner_in(NonNull::new_unchecked(inner_from_value(ptr)), Global)
+    }
+}
+
+impl<T, C: Count> Rc<T, Global, C> {
+    /// like new, but for an Rc with C sized counts, e.g.
+    /// `Rc::<T, Global, u32>::new_counted(value)`
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    pub fn new_counted(value: T) -> Self {
+        Rc::new_with(value, Global)
+    }
+}
+
+impl<T, A: Allocator> Rc<T, A> {
+    /// like new, but the RcInner is allocated from alloc
+    #[cfg(feature = "allocator-api")]
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    pub fn new_in(value: T, alloc: A) -> Self {
+        Rc::new_with(value, alloc)
+    }
+
+    /// like try_new, but the RcInner is allocated from alloc
+    #[cfg(feature = "allocator-api")]
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    pub fn try_new_in(value: T, alloc: A) -> Result<Self, AllocError> {
+        Rc::try_new_with(value, alloc)
+    }
+
+    /// like new_uninit_slice, but the block is allocated from alloc
+    #[cfg(feature = "allocator-api")]
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    pub fn new_uninit_slice_in(len: usize, alloc: A) -> Rc<[MaybeUninit<T>], A> {
+        Rc::uninit_slice_with(len, alloc)
+    }
+}
+
+impl<T, A: Allocator, C: Count> Rc<T, A, C> {
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    fn new_with(value: T, alloc: A) -> Self {
+        match Rc::try_new_with(value, alloc) {
+            Ok(rc) => rc,
+            Err(_) => std::alloc::handle_alloc_error(Layout::new::<RcInner<T, C>>()),
+        }
+    }
+
+    // on failure value is simply dropped here, and nothing was allocated
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    fn try_new_with(value: T, alloc: A) -> Result<Self, AllocError> {
+        let inner =
+            try_allocate_for_layout(&alloc, Layout::new::<RcInner<T, C>>(), false, |mem| {
+                mem as *mut RcInner<T, C>
+            })?;
+        unsafe {
+            ptr::addr_of_mut!((*inner.as_ptr()).value).write(value);
+            Ok(Rc::from_inner_in(inner, alloc))
+        }
+    }
+
+    /// move the value out if this is the only Rc, otherwise hand the Rc back
+    /// any Weaks stop upgrading once the value has been taken
+    pub fn try_unwrap(this: Self) -> Result<T, Self> {
+        if Rc::strong_count(&this) != 1 {
+            return Err(this);
+        }
+        let (inner, alloc) = Rc::into_inner_with_allocator(this);
+        unsafe {
+            let value = ptr::read(ptr::addr_of!((*inner.as_ptr()).value));
+            // same as the last Rc dropping, except the value was moved out
+            // instead of dropped
+            RcInner::strong(inner.as_ptr()).set(C::ZERO);
+            release_weak(inner, &alloc);
+            Ok(value)
+        }
+    }
+
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    fn uninit_slice_with(len: usize, alloc: A) -> Rc<[MaybeUninit<T>], A, C> {
+        // the slice length becomes the metadata of the fat pointer
+        let inner = allocate_for_layout(&alloc, slice_layout::<T, C>(len), false, |mem| {
+            ptr::slice_from_raw_parts_mut(mem as *mut MaybeUninit<T>, len)
+                as *mut RcInner<[MaybeUninit<T>], C>
         });
+        unsafe { Rc::from_inner_in(inner, alloc) }
+    }
+}
+
+// the RcInner whose value ptr points at, undoing Rc::as_ptr
+// repr(C) puts the value right after the header, padded to T's alignment
+fn inner_from_value<T, C: Count>(ptr: *const T) -> *mut RcInner<T, C> {
+    let (_, offset) = Layout::new::<RcInner<(), C>>()
+        .extend(Layout::new::<T>())
+        .expect("the layout of an existing RcInner");
+    ptr.wrapping_byte_sub(offset)
+        .cast::<RcInner<T, C>>()
+        .cast_mut()
+}
+
+// the layout of an RcInner<[T], C> holding len elements
+fn slice_layout<T, C: Count>(len: usize) -> Layout {
+    crate::header_slice::layout::<RcInner<(), C>, T>(len)
+}
+
+// allocates room for an RcInner<T> and sets up the counts, leaving the
+// value for the caller to write
+// mem_to_inner turns the raw allocation into a (possibly fat) pointer
+#[cfg_attr(feature = "leak-track", track_caller)]
+fn allocate_for_layout<T: ?Sized, A: Allocator, C: Count>(
+    alloc: &A,
+    layout: Layout,
+    zeroed: bool,
+    mem_to_inner: impl FnOnce(*mut u8) -> *mut RcInner<T, C>,
+) -> NonNull<RcInner<T, C>> {
+    match try_allocate_for_layout(alloc, layout, zeroed, mem_to_inner) {
+        Ok(inner) => inner,
+        Err(_) => std::alloc::handle_alloc_error(layout),
+    }
+}
+
+#[cfg_attr(feature = "leak-track", track_caller)]
+fn try_allocate_for_layout<T: ?Sized, A: Allocator, C: Count>(
+    alloc: &A,
+    layout: Layout,
+    zeroed: bool,
+    mem_to_inner: impl FnOnce(*mut u8) -> *mut RcInner<T, C>,
+) -> Result<NonNull<RcInner<T, C>>, AllocError> {
+    let mem = if zeroed {
+        alloc.allocate_zeroed(layout)?
+    } else {
+        alloc.allocate(layout)?
+    };
+    Ok(init_counts(mem, mem_to_inner))
+}
+
+// sets up the counts in a block fresh from the allocator (or the pool)
+#[cfg_attr(feature = "leak-track", track_caller)]
+fn init_counts<T: ?Sized, C: Count>(
+    mem: NonNull<u8>,
+    mem_to_inner: impl FnOnce(*mut u8) -> *mut RcInner<T, C>,
+) -> NonNull<RcInner<T, C>> {
+    let inner = mem_to_inner(mem.as_ptr());
+    #[cfg(feature = "leak-track")]
+    leak_track::register(mem.as_ptr() as usize, std::any::type_name::<T>());
+    // the counts are written after zeroing, so even a zeroed Rc starts at 1
+    unsafe {
+        ptr::addr_of_mut!((*inner).strong).write(Cell::new(C::ONE));
+        ptr::addr_of_mut!((*inner).weak).write(Cell::new(C::ONE));
+        NonNull::new_unchecked(inner)
+    }
+}
+
+impl<T: ?Sized, A: Allocator, C: Count> Rc<T, A, C> {
+    // the allocation outlives every Rc pointing to it
+    fn strong(&self) -> &Cell<C> {
+        unsafe { RcInner::strong(self.inner.as_ptr()) }
+    }
+
+    fn weak(&self) -> &Cell<C> {
+        unsafe { RcInner::weak(self.inner.as_ptr()) }
+    }
+
+    // takes over an RcInner whose strong count already accounts for this Rc
+    unsafe fn from_inner_in(inner: NonNull<RcInner<T, C>>, alloc: A) -> Self {
         Rc {
-            // Box does not give us a null pointer
-            inner: unsafe { NonNull::new_unchecked(Box::into_raw(inner)) },
+            inner,
             _marker: PhantomData,
+            alloc,
+        }
+    }
+
+    // the opposite of from_inner_in: gives up this Rc without touching the
+    // strong count, handing its reference over to the caller
+    fn into_inner_with_allocator(this: Self) -> (NonNull<RcInner<T, C>>, A) {
+        let this = ManuallyDrop::new(this);
+        // this is never used or dropped again, so moving alloc out is fine
+        (this.inner, unsafe { ptr::read(&this.alloc) })
+    }
+
+    /// the allocator this Rc's allocation came from
+    #[cfg(feature = "allocator-api")]
+    pub fn allocator(this: &Self) -> &A {
+        &this.alloc
+    }
+
+    /// a pointer to the value, which stays valid as long as some Rc does
+    pub fn as_ptr(this: &Self) -> *const T {
+        unsafe { ptr::addr_of!((*this.inner.as_ptr()).value) }
+    }
+
+    /// true if both Rcs point to the same allocation, like comparing &T
+    /// with std::ptr::eq
+    /// only the address is compared, not any vtable
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        this.inner.as_ptr() as *const () == other.inner.as_ptr() as *const ()
+    }
+
+    /// how many Rcs point to this allocation
+    pub fn strong_count(this: &Self) -> usize {
+        this.strong().get().as_usize()
+    }
+
+    /// how many Weaks point to this allocation
+    pub fn weak_count(this: &Self) -> usize {
+        // don't count the weak shared by the Rcs
+        this.weak().get().decrement().as_usize()
+    }
+
+    /// make a Weak pointing to the same allocation
+    /// the Weak doesn't keep the value alive, only the allocation
+    pub fn downgrade(this: &Self) -> Weak<T, A, C>
+    where
+        A: Clone,
+    {
+        this.weak().set(this.weak().get().increment());
+        Weak {
+            inner: this.inner,
+            alloc: this.alloc.clone(),
+        }
+    }
+
+    /// returns a mutable reference to the value if this is the only Rc
+    /// pointing at it
+    pub fn get_mut(this: &mut Self) -> Option<&mut T> {
+        // the counts can't change while we hold &mut to the only Rc, and
+        // with no Weaks around nobody can upgrade behind our back either
+        if this.strong().get() == C::ONE && this.weak().get() == C::ONE {
+            Some(unsafe { Rc::get_mut_unchecked(this) })
+        } else {
+            None
+        }
+    }
+
+    /// a mutable reference to the value, without checking for other Rcs or
+    /// Weaks
+    ///
+    /// # Safety
+    ///
+    /// no other Rc or Weak to this allocation may be dereferenced (or
+    /// upgraded) while the returned reference is alive, e.g. because the Rc
+    /// was just made with new_uninit and hasn't been cloned
+    pub unsafe fn get_mut_unchecked(this: &mut Self) -> &mut T {
+        // only borrow the value: other Rcs may still be reading the counts
+        &mut (*this.inner.as_ptr()).value
+    }
+}
+
+impl<T, A: Allocator, C: Count> Rc<MaybeUninit<T>, A, C> {
+    /// # Safety
+    ///
+    /// the value must have been fully initialized, e.g. through
+    /// `Rc::get_mut` or by writing through `as_mut_ptr`
+    pub unsafe fn assume_init(self) -> Rc<T, A, C> {
+        // the strong count moves over to the new Rc, so don't decrement it here
+        let (inner, alloc) = Rc::into_inner_with_allocator(self);
+        // MaybeUninit<T> has the same layout as T, so RcInner<MaybeUninit<T>>
+        // has the same layout as RcInner<T>
+        Rc::from_inner_in(inner.cast::<RcInner<T, C>>(), alloc)
+    }
+}
+
+impl<T, A: Allocator, C: Count> Rc<[MaybeUninit<T>], A, C> {
+    /// # Safety
+    ///
+    /// every element of the slice must have been initialized
+    pub unsafe fn assume_init(self) -> Rc<[T], A, C> {
+        let (inner, alloc) = Rc::into_inner_with_allocator(self);
+        // same length metadata, and MaybeUninit<T> has the same layout as T
+        let inner = NonNull::new_unchecked(inner.as_ptr() as *mut RcInner<[T], C>);
+        Rc::from_inner_in(inner, alloc)
+    }
+}
+
+impl<T> From<Vec<T>> for Rc<[T]> {
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    fn from(mut v: Vec<T>) -> Self {
+        let mut rc = Rc::new_uninit_slice(v.len());
+        let slots = unsafe { Rc::get_mut_unchecked(&mut rc) };
+        unsafe {
+            // move the elements over, then make the Vec forget about them
+            // so it only frees its buffer
+            ptr::copy_nonoverlapping(v.as_ptr(), slots.as_mut_ptr() as *mut T, v.len());
+            v.set_len(0);
+            rc.assume_init()
+        }
+    }
+}
+
+impl<T, const N: usize> From<[T; N]> for Rc<[T]> {
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    fn from(array: [T; N]) -> Self {
+        let (inner, alloc) = Rc::into_inner_with_allocator(Rc::new(array));
+        // RcInner is repr(C), so an RcInner<[T; N]> is laid out exactly like
+        // an RcInner<[T]> of length N; only the pointer needs the length
+        unsafe {
+            let slice = ptr::slice_from_raw_parts_mut(inner.as_ptr() as *mut T, N);
+            Rc::from_inner_in(NonNull::new_unchecked(slice as *mut RcInner<[T]>), alloc)
+        }
+    }
+}
+
+// the other way around: the fat pointer's length is checked against N and
+// then dropped, the counts and allocation carry on as they are
+impl<T, A: Allocator, C: Count, const N: usize> TryFrom<Rc<[T], A, C>> for Rc<[T; N], A, C> {
+    type Error = Rc<[T], A, C>;
+
+    fn try_from(rc: Rc<[T], A, C>) -> Result<Self, Self::Error> {
+        if rc.len() != N {
+            return Err(rc);
+        }
+        let (inner, alloc) = Rc::into_inner_with_allocator(rc);
+        unsafe { Ok(Rc::from_inner_in(inner.cast::<RcInner<[T; N], C>>(), alloc)) }
+    }
+}
+
+impl<T> Rc<[T]> {
+    /// an Rc<[T]> of len elements, element i being f(i), built in place
+    /// without a staging Vec
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    pub fn from_fn<F: FnMut(usize) -> T>(len: usize, f: F) -> Self {
+        // if f panics, fill drops the elements written so far and rc frees
+        // the allocation
+        let mut rc = Rc::new_uninit_slice(len);
+        crate::header_slice::fill(unsafe { Rc::get_mut_unchecked(&mut rc) }, f);
+        unsafe { rc.assume_init() }
+    }
+}
+
+impl<T: Clone> From<&[T]> for Rc<[T]> {
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    fn from(v: &[T]) -> Self {
+        Rc::from_fn(v.len(), |i| v[i].clone())
+    }
+}
+
+impl From<&str> for Rc<str> {
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    fn from(s: &str) -> Self {
+        let bytes = Rc::<[u8]>::from(s.as_bytes());
+        let (inner, alloc) = Rc::into_inner_with_allocator(bytes);
+        // str is just [u8] known to be utf-8, same layout and length metadata
+        unsafe {
+            Rc::from_inner_in(
+                NonNull::new_unchecked(inner.as_ptr() as *mut RcInner<str>),
+                alloc,
+            )
+        }
+    }
+}
+
+impl From<String> for Rc<str> {
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    fn from(s: String) -> Self {
+        Rc::from(&s[..])
+    }
+}
+
+// OsStr, Path and CStr are all a byte slice underneath, with the same
+// length metadata, so like str they're built as an Rc<[u8]> and retyped
+// the bytes come from the type's own accessor, so they're exactly the
+// representation it expects
+impl From<&OsStr> for Rc<OsStr> {
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    fn from(s: &OsStr) -> Self {
+        let bytes = Rc::<[u8]>::from(s.as_encoded_bytes());
+        let (inner, alloc) = Rc::into_inner_with_allocator(bytes);
+        unsafe {
+            Rc::from_inner_in(
+                NonNull::new_unchecked(inner.as_ptr() as *mut RcInner<OsStr>),
+                alloc,
+            )
+        }
+    }
+}
+
+impl From<OsString> for Rc<OsStr> {
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    fn from(s: OsString) -> Self {
+        Rc::from(&*s)
+    }
+}
+
+impl From<&Path> for Rc<Path> {
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    fn from(path: &Path) -> Self {
+        // Path is a transparent wrapper around OsStr
+        let (inner, alloc) = Rc::into_inner_with_allocator(Rc::<OsStr>::from(path.as_os_str()));
+        unsafe {
+            Rc::from_inner_in(
+                NonNull::new_unchecked(inner.as_ptr() as *mut RcInner<Path>),
+                alloc,
+            )
+        }
+    }
+}
+
+impl From<PathBuf> for Rc<Path> {
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    fn from(path: PathBuf) -> Self {
+        Rc::from(&*path)
+    }
+}
+
+impl From<&CStr> for Rc<CStr> {
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    fn from(s: &CStr) -> Self {
+        // the length of a CStr includes its nul, so that has to be copied too
+        let bytes = Rc::<[u8]>::from(s.to_bytes_with_nul());
+        let (inner, alloc) = Rc::into_inner_with_allocator(bytes);
+        unsafe {
+            Rc::from_inner_in(
+                NonNull::new_unchecked(inner.as_ptr() as *mut RcInner<CStr>),
+                alloc,
+            )
+        }
+    }
+}
+
+impl From<CString> for Rc<CStr> {
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    fn from(s: CString) -> Self {
+        Rc::from(&*s)
+    }
+}
+
+// any error can be put behind an Rc<dyn Error>, like with Box<dyn Error>
+// unsizing Rc itself needs the nightly feature, but its raw pointer can be
+// unsized on stable
+impl<'a, E: Error + 'a> From<E> for Rc<dyn Error + 'a> {
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    fn from(err: E) -> Self {
+        let (inner, alloc) = Rc::into_inner_with_allocator(Rc::new(err));
+        let inner: NonNull<RcInner<dyn Error + 'a>> = inner;
+        unsafe { Rc::from_inner_in(inner, alloc) }
+    }
+}
+
+impl<A: Allocator, C: Count> Rc<dyn Error, A, C> {
+    /// the concrete error back, if that's what this is
+    pub fn downcast<E: Error + 'static>(self) -> Result<Rc<E, A, C>, Self> {
+        if self.is::<E>() {
+            let (inner, alloc) = Rc::into_inner_with_allocator(self);
+            // same allocation, just without the vtable
+            Ok(unsafe { Rc::from_inner_in(inner.cast(), alloc) })
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl<A: Allocator, C: Count> Rc<dyn Any, A, C> {
+    /// the concrete value back, if that's what this is
+    pub fn downcast<T: Any>(self) -> Result<Rc<T, A, C>, Self> {
+        if self.is::<T>() {
+            let (inner, alloc) = Rc::into_inner_with_allocator(self);
+            Ok(unsafe { Rc::from_inner_in(inner.cast(), alloc) })
+        } else {
+            Err(self)
         }
     }
 }
 
-impl<T> Clone for Rc<T> {
+impl<T: ?Sized, A: Allocator, C: Count> AsRef<T> for Rc<T, A, C> {
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+// for x in &rc, like for &Vec<T>
+impl<'a, T, A: Allocator, C: Count> IntoIterator for &'a Rc<[T], A, C> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<T: ?Sized, A: Allocator + Clone, C: Count> Clone for Rc<T, A, C> {
     fn clone(&self) -> Self {
-        let inner = unsafe { self.inner.as_ref() };
-        inner.refcount.set(inner.refcount.get() + 1);
+        self.strong().set(self.strong().get().increment());
         Rc {
             inner: self.inner,
             _marker: PhantomData,
+            alloc: self.alloc.clone(),
         }
     }
 }
 
-impl<T> std::ops::Deref for Rc<T> {
+// lets Rc<T> coerce to Rc<dyn Trait> or Rc<[T]> from Rc<[T; N]>, the same
+// way &T and Box<T> do
+// only inner changes type: the pointer just gains the vtable or length
+#[cfg(feature = "nightly")]
+impl<T, U, A, C> std::ops::CoerceUnsized<Rc<U, A, C>> for Rc<T, A, C>
+where
+    T: ?Sized + std::marker::Unsize<U>,
+    U: ?Sized,
+    A: Allocator,
+    C: Count,
+{
+}
+
+// lets Rc<Self> be used as a method receiver on trait objects
+// only for Global, since dispatch needs Rc to be a lone pointer
+#[cfg(feature = "nightly")]
+impl<T, U> std::ops::DispatchFromDyn<Rc<U>> for Rc<T>
+where
+    T: ?Sized + std::marker::Unsize<U>,
+    U: ?Sized,
+{
+}
+
+impl<T: ?Sized, A: Allocator, C: Count> std::ops::Deref for Rc<T, A, C> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
-        // self.inner is a Box that is only deallocated when the last rc goes away
-        &unsafe { self.inner.as_ref() }.value
+        // self.inner is only deallocated when the last rc goes away
+        unsafe { &(*self.inner.as_ptr()).value }
+    }
+}
+
+// formatting an Rc formats the value, the pointer is an implementation detail
+impl<T: ?Sized + fmt::Debug, A: Allocator, C: Count> fmt::Debug for Rc<T, A, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display, A: Allocator, C: Count> fmt::Display for Rc<T, A, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+// lets a shared error sit anywhere in a source() chain
+// only for sized T, like Box: if Rc<dyn Error> were an Error itself, the
+// From<E> for Rc<dyn Error> below would overlap with From<T> for T
+// an Rc<dyn Error> can still go in a chain as &*rc
+impl<T: Error, A: Allocator, C: Count> Error for Rc<T, A, C> {
+    #[allow(deprecated)]
+    fn description(&self) -> &str {
+        (**self).description()
+    }
+
+    #[allow(deprecated)]
+    fn cause(&self) -> Option<&dyn Error> {
+        (**self).cause()
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        (**self).source()
+    }
+}
+
+// two Rcs are equal when their values are, so a == b looks through the
+// pointers the same way it does for &T and Box<T>
+impl<T, A, C> PartialEq for Rc<T, A, C>
+where
+    T: ?Sized + PartialEq,
+    A: Allocator,
+    C: Count,
+{
+    fn eq(&self, other: &Self) -> bool {
+        RcEqIdent::eq(self, other)
+    }
+
+    #[allow(clippy::partialeq_ne_impl)]
+    fn ne(&self, other: &Self) -> bool {
+        RcEqIdent::ne(self, other)
+    }
+}
+
+impl<T: ?Sized + Eq, A: Allocator, C: Count> Eq for Rc<T, A, C> {}
+
+// rc == value, without having to write *rc == value
+impl<T, A, C> PartialEq<T> for Rc<T, A, C>
+where
+    T: ?Sized + PartialEq,
+    A: Allocator,
+    C: Count,
+{
+    fn eq(&self, other: &T) -> bool {
+        **self == *other
+    }
+}
+
+// two Rcs pointing at the same value are equal without looking at it, but
+// only if T: Eq promises every value equals itself, which an f64 NaN doesn't
+// picking the comparison based on whether T: Eq needs specialization, so
+// == only takes the shortcut on nightly; on stable Rc::ptr_eq_or_eq takes it
+// for any T: ReflexiveEq
+trait RcEqIdent<T: ?Sized + PartialEq, A: Allocator, C: Count> {
+    fn eq(&self, other: &Rc<T, A, C>) -> bool;
+    fn ne(&self, other: &Rc<T, A, C>) -> bool;
+}
+
+#[cfg(not(feature = "nightly"))]
+impl<T: ?Sized + PartialEq, A: Allocator, C: Count> RcEqIdent<T, A, C> for Rc<T, A, C> {
+    fn eq(&self, other: &Rc<T, A, C>) -> bool {
+        **self == **other
+    }
+
+    fn ne(&self, other: &Rc<T, A, C>) -> bool {
+        **self != **other
+    }
+}
+
+#[cfg(feature = "nightly")]
+impl<T: ?Sized + PartialEq, A: Allocator, C: Count> RcEqIdent<T, A, C> for Rc<T, A, C> {
+    default fn eq(&self, other: &Rc<T, A, C>) -> bool {
+        **self == **other
+    }
+
+    default fn ne(&self, other: &Rc<T, A, C>) -> bool {
+        **self != **other
+    }
+}
+
+// Eq itself can't be specialized on, so std's trick of a marker trait
+// implemented for every T: Eq stands in for it
+// sync::Arc's comparisons use it too
+#[cfg(feature = "nightly")]
+#[rustc_unsafe_specialization_marker]
+pub(crate) trait MarkerEq: PartialEq<Self> {}
+
+#[cfg(feature = "nightly")]
+impl<T: Eq + ?Sized> MarkerEq for T {}
+
+#[cfg(feature = "nightly")]
+impl<T: ?Sized + MarkerEq, A: Allocator, C: Count> RcEqIdent<T, A, C> for Rc<T, A, C> {
+    fn eq(&self, other: &Rc<T, A, C>) -> bool {
+        Rc::ptr_eq(self, other) || **self == **other
+    }
+
+    fn ne(&self, other: &Rc<T, A, C>) -> bool {
+        !Rc::ptr_eq(self, other) && **self != **other
+    }
+}
+
+impl<T: ?Sized, A: Allocator, C: Count> Rc<T, A, C> {
+    // the whole of Drop, shared by both versions of the impl below
+    fn release_strong(&mut self) {
+        // the counts are only ever touched through raw pointers to the
+        // fields, never a reference to the whole RcInner: that would also
+        // assert the value is intact, which stops being true halfway through
+        // this function
+        let strong = self.strong().get().decrement();
+        self.strong().set(strong);
+        if strong == C::ZERO {
+            // no more Rcs, so nobody can reach the value anymore
+            // upgrades fail from here on since strong is already 0, even if
+            // the value's own drop tries to upgrade a Weak to itself
+            unsafe { ptr::drop_in_place(ptr::addr_of_mut!((*self.inner.as_ptr()).value)) };
+            // give up the weak shared by all the Rcs, freeing the allocation
+            // if there are no Weaks left either
+            unsafe { release_weak(self.inner, &self.alloc) };
+        }
+    }
+}
+
+#[cfg(not(feature = "nightly"))]
+impl<T: ?Sized, A: Allocator, C: Count> Drop for Rc<T, A, C> {
+    fn drop(&mut self) {
+        self.release_strong()
     }
 }
 
-impl<T> Drop for Rc<T> {
+// may_dangle promises drop check that dropping an Rc<T> never touches
+// anything T borrows, only drops the T itself, so e.g. an Rc<Foo<'a>> may be
+// dropped after whatever Foo borrows from, as long as Foo has no Drop of its
+// own that could look at it
+// the PhantomData<RcInner<T, C>> is what still tells drop check about that
+// T being dropped
+#[cfg(feature = "nightly")]
+unsafe impl<#[may_dangle] T: ?Sized, A: Allocator, C: Count> Drop for Rc<T, A, C> {
     fn drop(&mut self) {
-        let inner = unsafe { self.inner.as_ref() };
-        let count = inner.refcount.get();
-        match count {
-            // no more references to the inner value
-            1 => {
-                drop(inner);
-                drop(unsafe { Box::from_raw(self.inner.as_ptr()) })
+        self.release_strong()
+    }
+}
+
+// pinning an Rc never pins the value behind it in place (moving the Rc
+// only moves the pointer), so Rc is Unpin whatever T is, same as Box
+impl<T: ?Sized, A: Allocator, C: Count> Unpin for Rc<T, A, C> {}
+
+// the raw pointer inside makes the compiler assume Rc and Weak aren't unwind
+// safe, but all they do is share a T, so they're exactly as unwind safe as
+// &T is, same as in std
+// the counts can't be left half updated by a panic: every count change is a
+// single set that happens before or after any user code runs
+impl<T, A, C> UnwindSafe for Rc<T, A, C>
+where
+    T: RefUnwindSafe + ?Sized,
+    A: Allocator + UnwindSafe,
+    C: Count,
+{
+}
+
+impl<T, A, C> RefUnwindSafe for Rc<T, A, C>
+where
+    T: RefUnwindSafe + ?Sized,
+    A: Allocator + UnwindSafe,
+    C: Count,
+{
+}
+
+impl<T, A, C> UnwindSafe for Weak<T, A, C>
+where
+    T: RefUnwindSafe + ?Sized,
+    A: Allocator + UnwindSafe,
+    C: Count,
+{
+}
+
+impl<T, A, C> RefUnwindSafe for Weak<T, A, C>
+where
+    T: RefUnwindSafe + ?Sized,
+    A: Allocator + UnwindSafe,
+    C: Count,
+{
+}
+
+// drops one weak reference, deallocating once the last one is gone
+//
+// safety: inner must be a live allocation from alloc, and the caller must
+// own one of its weak references
+unsafe fn release_weak<T: ?Sized, A: Allocator, C: Count>(
+    inner: NonNull<RcInner<T, C>>,
+    alloc: &A,
+) {
+    let weak = RcInner::weak(inner.as_ptr()).get().decrement();
+    RcInner::weak(inner.as_ptr()).set(weak);
+    if weak == C::ZERO {
+        // the Rcs' shared weak is gone too, so strong is 0 and the value
+        // has already been dropped
+        // the layout only depends on the pointer metadata, which is the
+        // same layout the allocation was made with
+        // stable has no way to get it without a reference, and a reference
+        // to a dropped value is only tolerated, so nightly skips it
+        #[cfg(feature = "nightly")]
+        let layout = Layout::for_value_raw(inner.as_ptr());
+        #[cfg(not(feature = "nightly"))]
+        let layout = Layout::for_value(inner.as_ref());
+        #[cfg(feature = "leak-track")]
+        leak_track::deregister(inner.as_ptr() as *mut u8 as usize);
+        // a thin pointer means T is sized, so the block fits the next
+        // Rc::new of the same type
+        #[cfg(feature = "rc-pool")]
+        {
+            if alloc.is_global()
+                && mem::size_of::<*mut RcInner<T, C>>() == mem::size_of::<*mut u8>()
+                && crate::alloc::pool::give(inner.cast(), layout).is_ok()
+            {
+                return;
+            }
+        }
+        alloc.deallocate(inner.cast(), layout);
+    }
+}
+
+// a pointer to an Rc's allocation that doesn't keep the value alive
+// upgrade gives an Rc back as long as some other Rc still exists
+//
+// useful to break cycles, e.g. child -> parent links in a tree, where strong
+// pointers both ways would keep the whole tree alive forever
+/// a pointer to an Rc's allocation that doesn't keep the value alive
+///
+/// like Rc, `Option<Weak<T>>` is guaranteed to be the same size as
+/// `Weak<T>`: a Weak from Weak::new points at a sentinel address, never null
+pub struct Weak<T: ?Sized, A: Allocator = Global, C: Count = usize> {
+    // usize::MAX if this Weak came from Weak::new and has no allocation
+    // no real allocation can live there since RcInner is at least 2-aligned
+    inner: NonNull<RcInner<T, C>>,
+    alloc: A,
+}
+
+impl<T> Weak<T> {
+    /// a Weak that doesn't point to anything, so upgrade always gives None
+    /// useful as a placeholder before the real target exists
+    pub fn new() -> Self {
+        Weak {
+            inner: NonNull::new(ptr::without_provenance_mut(usize::MAX))
+                .expect("usize::MAX is not null"),
+            alloc: Global,
+        }
+    }
+}
+
+impl<T> Weak<T> {
+    /// give up this Weak without dropping it, keeping its weak reference
+    /// alive for the pointer
+    /// a Weak from Weak::new gives back a dangling pointer that from_raw
+    /// recognizes, so it must never be dereferenced
+    pub fn into_raw(this: Self) -> *const T {
+        let this = ManuallyDrop::new(this);
+        if this.is_dangling() {
+            // keep the sentinel as it is, the value offset only makes sense
+            // for a real allocation
+            this.inner.as_ptr() as *const T
+        } else {
+            // the allocation outlives the weak reference, even if the value
+            // is gone, and addr_of never reads the value
+            unsafe { ptr::addr_of!((*this.inner.as_ptr()).value) }
+        }
+    }
+
+    /// take back the weak reference given up by into_raw
+    ///
+    /// # Safety
+    ///
+    /// ptr must have come from Weak::into_raw, and each pointer may only be
+    /// turned back into a Weak once
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        let inner = if ptr as *const () as usize == usize::MAX {
+            ptr.cast::<RcInner<T>>().cast_mut()
+        } else {
+            inner_from_value(ptr)
+        };
+        Weak {
+            inner: NonNull::new_unchecked(inner),
+            alloc: Global,
+        }
+    }
+}
+
+impl<T> Default for Weak<T> {
+    fn default() -> Self {
+        Weak::new()
+    }
+}
+
+impl<T: ?Sized, A: Allocator, C: Count> Weak<T, A, C> {
+    fn is_dangling(&self) -> bool {
+        self.inner.as_ptr() as *mut () as usize == usize::MAX
+    }
+
+    // None for a dangling Weak, otherwise the strong and weak counts
+    // the value may already have been dropped, so it's never looked at
+    fn counts(&self) -> Option<(&Cell<C>, &Cell<C>)> {
+        if self.is_dangling() {
+            None
+        } else {
+            // the allocation stays around as long as any Weak does
+            let inner = self.inner.as_ptr();
+            Some(unsafe { (RcInner::strong(inner), RcInner::weak(inner)) })
+        }
+    }
+
+    /// true if both Weaks point to the same allocation, or both came from
+    /// Weak::new
+    /// works even after the value has been dropped, since the allocation
+    /// (and so its address) stays around while the Weaks do
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        self.inner.as_ptr() as *const () == other.inner.as_ptr() as *const ()
+    }
+
+    /// how many Rcs point to the allocation, 0 for a Weak from Weak::new
+    pub fn strong_count(&self) -> usize {
+        self.counts()
+            .map_or(0, |(strong, _)| strong.get().as_usize())
+    }
+
+    /// how many Weaks point to the allocation
+    /// 0 once the value is gone, since at that point nothing can be done
+    /// with them other than dropping
+    pub fn weak_count(&self) -> usize {
+        match self.counts() {
+            // while there are Rcs, one of the weaks is theirs
+            Some((strong, weak)) if strong.get() != C::ZERO => weak.get().decrement().as_usize(),
+            _ => 0,
+        }
+    }
+
+    /// get an Rc to the value back, if it hasn't been dropped yet
+    pub fn upgrade(&self) -> Option<Rc<T, A, C>>
+    where
+        A: Clone,
+    {
+        let (strong, _) = self.counts()?;
+        if strong.get() == C::ZERO {
+            return None;
+        }
+        strong.set(strong.get().increment());
+        Some(unsafe { Rc::from_inner_in(self.inner, self.alloc.clone()) })
+    }
+}
+
+impl<T: ?Sized, A: Allocator + Clone, C: Count> Clone for Weak<T, A, C> {
+    fn clone(&self) -> Self {
+        // a dangling Weak has no counts to bump
+        if let Some((_, weak)) = self.counts() {
+            weak.set(weak.get().increment());
+        }
+        Weak {
+            inner: self.inner,
+            alloc: self.alloc.clone(),
+        }
+    }
+}
+
+impl<T: ?Sized, A: Allocator, C: Count> Weak<T, A, C> {
+    fn release(&mut self) {
+        if !self.is_dangling() {
+            unsafe { release_weak(self.inner, &self.alloc) }
+        }
+    }
+}
+
+#[cfg(not(feature = "nightly"))]
+impl<T: ?Sized, A: Allocator, C: Count> Drop for Weak<T, A, C> {
+    fn drop(&mut self) {
+        self.release()
+    }
+}
+
+// a Weak never drops the value, so it doesn't even need the PhantomData
+#[cfg(feature = "nightly")]
+unsafe impl<#[may_dangle] T: ?Sized, A: Allocator, C: Count> Drop for Weak<T, A, C> {
+    fn drop(&mut self) {
+        self.release()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn new_uninit_then_assume_init() {
+        let mut rc = Rc::<String>::new_uninit();
+        Rc::get_mut(&mut rc).unwrap().write(String::from("hello"));
+        let rc = unsafe { rc.assume_init() };
+        assert_eq!(*rc, "hello");
+
+        let rc2 = rc.clone();
+        drop(rc);
+        assert_eq!(*rc2, "hello");
+    }
+
+    #[test]
+    fn get_mut_unchecked_after_new_uninit() {
+        let mut rc = Rc::<[u64; 8]>::new_uninit();
+        let array = unsafe { Rc::get_mut_unchecked(&mut rc) }.as_mut_ptr() as *mut u64;
+        for i in 0..8 {
+            unsafe { array.add(i).write(i as u64 * 2) };
+        }
+        let mut rc = unsafe { rc.assume_init() };
+        assert_eq!(rc[7], 14);
+
+        // allowed with other Rcs around, as long as they aren't used
+        let other = rc.clone();
+        unsafe { Rc::get_mut_unchecked(&mut rc)[0] = 100 };
+        assert_eq!(other[0], 100);
+        assert_eq!(Rc::strong_count(&other), 2);
+    }
+
+    #[test]
+    fn get_mut_unchecked_on_uninit_slice() {
+        let mut rc = Rc::<String>::new_uninit_slice(3);
+        for (i, slot) in unsafe { Rc::get_mut_unchecked(&mut rc) }
+            .iter_mut()
+            .enumerate()
+        {
+            slot.write(i.to_string());
+        }
+        let rc = unsafe { rc.assume_init() };
+        assert_eq!(&*rc, ["0", "1", "2"]);
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        let rc = Rc::new(String::from("handle"));
+        let ptr = Rc::into_raw(rc.clone());
+        assert_eq!(unsafe { &*ptr }, "handle");
+        assert_eq!(Rc::strong_count(&rc), 2);
+        let back = unsafe { Rc::from_raw(ptr) };
+        assert!(Rc::ptr_eq(&rc, &back));
+        drop(back);
+        assert_eq!(Rc::strong_count(&rc), 1);
+    }
+
+    #[test]
+    fn weak_raw_round_trip() {
+        let rc = Rc::new(7u8);
+        let ptr = Weak::into_raw(Rc::downgrade(&rc));
+        assert_eq!(ptr, Rc::as_ptr(&rc));
+        // the weak count went along with the pointer
+        assert_eq!(Rc::weak_count(&rc), 1);
+        let weak = unsafe { Weak::from_raw(ptr) };
+        assert_eq!(*weak.upgrade().unwrap(), 7);
+        assert_eq!(Rc::weak_count(&rc), 1);
+
+        // still fine once the value is gone, as long as nothing reads it
+        drop(rc);
+        let weak = unsafe { Weak::from_raw(Weak::into_raw(weak)) };
+        assert!(weak.upgrade().is_none());
+        drop(weak);
+
+        let dangling = Weak::into_raw(Weak::<u64>::new());
+        let weak = unsafe { Weak::from_raw(dangling) };
+        assert!(weak.upgrade().is_none());
+        assert!(weak.ptr_eq(&Weak::new()));
+    }
+
+    #[test]
+    fn slice_to_array() {
+        let rc: Rc<[String]> = Rc::from(vec![String::from("a"), String::from("b")]);
+        let weak = Rc::downgrade(&rc);
+        let rc = Rc::<[String; 3]>::try_from(rc).unwrap_err();
+        assert_eq!(rc.len(), 2);
+
+        let array = Rc::<[String; 2]>::try_from(rc).unwrap();
+        assert_eq!(array[1], "b");
+        // still the same allocation, with the same counts
+        assert_eq!(Rc::strong_count(&array), 1);
+        assert_eq!(Rc::weak_count(&array), 1);
+        assert_eq!(weak.upgrade().unwrap().as_ptr(), array.as_ptr());
+        let empty: Rc<[u8]> = Rc::from(Vec::new());
+        assert_eq!(*Rc::<[u8; 0]>::try_from(empty).unwrap(), [0u8; 0]);
+    }
+
+    #[test]
+    fn as_ptr_points_at_the_value() {
+        let rc = Rc::new(5);
+        let clone = rc.clone();
+        assert_eq!(Rc::as_ptr(&rc), &*clone as *const i32);
+        assert_eq!(unsafe { *Rc::as_ptr(&rc) }, 5);
+        let s: Rc<str> = Rc::from("abc");
+        assert_eq!(unsafe { &*Rc::as_ptr(&s) }, "abc");
+    }
+
+    #[test]
+    fn get_mut_only_when_unique() {
+        let mut rc = Rc::new(5);
+        *Rc::get_mut(&mut rc).unwrap() += 1;
+        let rc2 = rc.clone();
+        assert!(Rc::get_mut(&mut rc).is_none());
+        drop(rc2);
+        assert_eq!(*Rc::get_mut(&mut rc).unwrap(), 6);
+    }
+
+    #[test]
+    fn new_zeroed_is_all_zero() {
+        let rc = Rc::<[u8; 16]>::new_zeroed();
+        let rc = unsafe { rc.assume_init() };
+        assert_eq!(*rc, [0; 16]);
+
+        // the counts are not part of the zeroed bytes
+        let mut rc2 = rc.clone();
+        assert!(Rc::get_mut(&mut rc2).is_none());
+        drop(rc);
+        assert!(Rc::get_mut(&mut rc2).is_some());
+    }
+
+    // hands out memory from the global allocator, remembering every
+    // block so it can tell whether they all came back
+    #[derive(Default)]
+    struct CountingAlloc {
+        live: std::cell::RefCell<HashMap<usize, Layout>>,
+        allocations: std::cell::Cell<usize>,
+    }
+
+    unsafe impl Allocator for CountingAlloc {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+            let ptr = Global.allocate(layout)?;
+            self.live.borrow_mut().insert(ptr.as_ptr() as usize, layout);
+            self.allocations.set(self.allocations.get() + 1);
+            Ok(ptr)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            let expected = self.live.borrow_mut().remove(&(ptr.as_ptr() as usize));
+            assert_eq!(expected, Some(layout));
+            Global.deallocate(ptr, layout)
+        }
+    }
+
+    // an allocator that is always out of memory
+    #[derive(Clone, Default)]
+    struct FailingAlloc {
+        deallocations: std::rc::Rc<std::cell::Cell<usize>>,
+    }
+
+    unsafe impl Allocator for FailingAlloc {
+        fn allocate(&self, _: Layout) -> Result<NonNull<u8>, AllocError> {
+            Err(AllocError)
+        }
+
+        unsafe fn deallocate(&self, _: NonNull<u8>, _: Layout) {
+            self.deallocations.set(self.deallocations.get() + 1);
+        }
+    }
+
+    #[test]
+    fn try_new() {
+        let rc = Rc::try_new(String::from("hello")).unwrap();
+        assert_eq!(*rc, "hello");
+
+        let mut rc = Rc::<u32>::try_new_uninit().unwrap();
+        Rc::get_mut(&mut rc).unwrap().write(7);
+        assert_eq!(*unsafe { rc.assume_init() }, 7);
+    }
+
+    #[test]
+    fn try_new_allocation_failure() {
+        let drops = Cell::new(0);
+        let alloc = FailingAlloc::default();
+        let result = Rc::<_, _>::try_new_with(DropCount(&drops), alloc.clone());
+        assert!(matches!(result, Err(AllocError)));
+        // the value was dropped exactly once, and nothing was freed since
+        // nothing was allocated
+        assert_eq!(drops.get(), 1);
+        assert_eq!(alloc.deallocations.get(), 0);
+        assert_eq!(AllocError.to_string(), "memory allocation failed");
+    }
+
+    #[test]
+    fn try_unwrap() {
+        let rc = Rc::new(String::from("hello"));
+        let rc2 = rc.clone();
+        let rc = Rc::try_unwrap(rc).err().unwrap();
+        drop(rc2);
+
+        let weak = Rc::downgrade(&rc);
+        assert_eq!(Rc::try_unwrap(rc).ok().unwrap(), "hello");
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn downgrade_and_upgrade() {
+        let rc = Rc::new(String::from("hello"));
+        let weak = Rc::downgrade(&rc);
+        assert_eq!(*weak.upgrade().unwrap(), "hello");
+        drop(rc);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn weak_keeps_allocation_not_value() {
+        let alloc = CountingAlloc::default();
+        let drops = Cell::new(0);
+        let rc = Rc::<_, _>::new_with(DropCount(&drops), &alloc);
+        let rc2 = rc.clone();
+        let weak = Rc::downgrade(&rc);
+
+        drop(rc);
+        assert_eq!(drops.get(), 0);
+        // the value is dropped as soon as the last Rc goes, even though a
+        // Weak still points at it
+        drop(rc2);
+        assert_eq!(drops.get(), 1);
+        assert_eq!(alloc.live.borrow().len(), 1);
+        assert!(weak.upgrade().is_none());
+        assert_eq!(weak.strong_count(), 0);
+
+        // and the allocation once the last Weak goes
+        drop(weak);
+        assert_eq!(drops.get(), 1);
+        assert!(alloc.live.borrow().is_empty());
+    }
+
+    // upgrades a Weak to itself from its own destructor
+    struct Upgrader<'a> {
+        me: std::cell::RefCell<Option<Weak<Upgrader<'a>, &'a CountingAlloc>>>,
+        upgraded: &'a Cell<Option<bool>>,
+    }
+
+    impl Drop for Upgrader<'_> {
+        fn drop(&mut self) {
+            let me = self.me.borrow();
+            let me = me.as_ref().unwrap();
+            self.upgraded.set(Some(me.upgrade().is_some()));
+        }
+    }
+
+    #[test]
+    fn drop_cannot_upgrade_itself() {
+        let alloc = CountingAlloc::default();
+        let upgraded = Cell::new(None);
+        let rc = Rc::<_, _>::new_with(
+            Upgrader {
+                me: std::cell::RefCell::new(None),
+                upgraded: &upgraded,
+            },
+            &alloc,
+        );
+        *rc.me.borrow_mut() = Some(Rc::downgrade(&rc));
+        let outside = Rc::downgrade(&rc);
+
+        drop(rc);
+        assert_eq!(upgraded.get(), Some(false));
+        // the value's own Weak went with it, the outside one still holds
+        // the allocation
+        assert_eq!(alloc.live.borrow().len(), 1);
+        assert_eq!(outside.weak_count(), 0);
+        drop(outside);
+        assert!(alloc.live.borrow().is_empty());
+
+        // the same when the value's own Weak is the last one: it's dropped
+        // while the Rcs' shared weak still keeps the allocation around
+        upgraded.set(None);
+        let rc = Rc::<_, _>::new_with(
+            Upgrader {
+                me: std::cell::RefCell::new(None),
+                upgraded: &upgraded,
+            },
+            &alloc,
+        );
+        *rc.me.borrow_mut() = Some(Rc::downgrade(&rc));
+        drop(rc);
+        assert_eq!(upgraded.get(), Some(false));
+        assert!(alloc.live.borrow().is_empty());
+    }
+
+    #[test]
+    fn no_weaks_frees_with_last_rc() {
+        let alloc = CountingAlloc::default();
+        let drops = Cell::new(0);
+        let rc = Rc::<_, _>::new_with(DropCount(&drops), &alloc);
+        let weak = Rc::downgrade(&rc);
+        drop(weak);
+        drop(rc);
+        assert_eq!(drops.get(), 1);
+        assert!(alloc.live.borrow().is_empty());
+    }
+
+    #[test]
+    fn dangling_weak() {
+        let weak = Weak::<String>::new();
+        assert!(weak.upgrade().is_none());
+        assert!(Weak::<[u8; 1024]>::default().upgrade().is_none());
+        drop(weak);
+    }
+
+    #[test]
+    fn clone_weak() {
+        let alloc = CountingAlloc::default();
+        let rc = Rc::<_, _>::new_with(String::from("hello"), &alloc);
+        let weak = Rc::downgrade(&rc);
+        let weak2 = weak.clone();
+        assert_eq!(Rc::weak_count(&rc), 2);
+        assert!(weak.ptr_eq(&weak2));
+        assert_eq!(*weak2.upgrade().unwrap(), "hello");
+
+        // the value goes with the last Rc, the allocation with the last Weak
+        drop(rc);
+        assert!(weak2.upgrade().is_none());
+        drop(weak);
+        assert_eq!(alloc.live.borrow().len(), 1);
+        drop(weak2);
+        assert!(alloc.live.borrow().is_empty());
+
+        let dangling = Weak::<String>::new();
+        let dangling2 = dangling.clone();
+        assert!(dangling.ptr_eq(&dangling2));
+        assert_eq!(dangling2.weak_count(), 0);
+    }
+
+    #[test]
+    fn counts() {
+        let rc = Rc::new(1);
+        let rc2 = rc.clone();
+        let weak = Rc::downgrade(&rc);
+        let weak2 = Rc::downgrade(&rc2);
+        assert_eq!((Rc::strong_count(&rc), Rc::weak_count(&rc)), (2, 2));
+        assert_eq!((weak.strong_count(), weak.weak_count()), (2, 2));
+
+        drop(weak2);
+        drop(rc);
+        assert_eq!((Rc::strong_count(&rc2), Rc::weak_count(&rc2)), (1, 1));
+        assert_eq!((weak.strong_count(), weak.weak_count()), (1, 1));
+
+        // the allocation is still there, but the counts report it as dead
+        drop(rc2);
+        assert_eq!((weak.strong_count(), weak.weak_count()), (0, 0));
+
+        let dangling = Weak::<i32>::new();
+        assert_eq!((dangling.strong_count(), dangling.weak_count()), (0, 0));
+    }
+
+    #[test]
+    fn ptr_eq() {
+        let rc = Rc::new(1);
+        let same = Rc::new(1);
+        assert!(Rc::ptr_eq(&rc, &rc.clone()));
+        assert!(!Rc::ptr_eq(&rc, &same));
+
+        let weak = Rc::downgrade(&rc);
+        assert!(weak.ptr_eq(&Rc::downgrade(&rc)));
+        assert!(!weak.ptr_eq(&Rc::downgrade(&same)));
+        assert!(!weak.ptr_eq(&Weak::new()));
+        assert!(Weak::<i32>::new().ptr_eq(&Weak::new()));
+
+        // still the same allocation after the value is gone
+        let weak2 = Rc::downgrade(&rc);
+        drop(rc);
+        assert!(weak.ptr_eq(&weak2));
+    }
+
+    struct DropCount<'a>(&'a Cell<usize>);
+
+    impl Drop for DropCount<'_> {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn new_uninit_slice_fill_in_place() {
+        let mut rc = Rc::<String>::new_uninit_slice(3);
+        for (i, slot) in Rc::get_mut(&mut rc).unwrap().iter_mut().enumerate() {
+            slot.write(i.to_string());
+        }
+        let rc = unsafe { rc.assume_init() };
+        assert_eq!(rc.len(), 3);
+        assert_eq!(&*rc, ["0", "1", "2"]);
+
+        let rc2 = rc.clone();
+        drop(rc);
+        assert_eq!(rc2[2], "2");
+    }
+
+    #[test]
+    fn new_uninit_slice_bulk() {
+        let mut rc = Rc::<u64>::new_uninit_slice(1000);
+        for (i, slot) in Rc::get_mut(&mut rc).unwrap().iter_mut().enumerate() {
+            slot.write(i as u64 * 3);
+        }
+        let rc = unsafe { rc.assume_init() };
+        for (i, x) in rc.iter().enumerate() {
+            assert_eq!(*x, i as u64 * 3);
+        }
+
+        // the elements live in the same block, right after the header
+        let header = std::mem::size_of::<RcInner<()>>();
+        let inner = rc.inner.as_ptr() as *const u8 as usize;
+        assert_eq!(rc.as_ptr() as usize, inner + header);
+        assert_eq!(
+            Layout::for_value(unsafe { rc.inner.as_ref() }),
+            slice_layout::<u64, usize>(1000)
+        );
+    }
+
+    #[test]
+    fn from_vec() {
+        let drops = Cell::new(0);
+        let rc: Rc<[DropCount]> = Rc::from(vec![DropCount(&drops), DropCount(&drops)]);
+        assert_eq!(rc.len(), 2);
+        assert_eq!(drops.get(), 0);
+        drop(rc);
+        assert_eq!(drops.get(), 2);
+
+        let rc: Rc<[String]> = Rc::from(Vec::new());
+        assert!(rc.is_empty());
+    }
+
+    // counts how often it's cloned, and panics on the clone that would make
+    // it the limit'th
+    struct CloneCount<'a> {
+        clones: &'a Cell<usize>,
+        drops: &'a Cell<usize>,
+        limit: usize,
+    }
+
+    impl Clone for CloneCount<'_> {
+        fn clone(&self) -> Self {
+            self.clones.set(self.clones.get() + 1);
+            assert!(self.clones.get() < self.limit, "clone limit");
+            CloneCount { ..*self }
+        }
+    }
+
+    impl Drop for CloneCount<'_> {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+        }
+    }
+
+    #[test]
+    fn from_slice() {
+        let (clones, drops) = (Cell::new(0), Cell::new(0));
+        let elem = CloneCount {
+            clones: &clones,
+            drops: &drops,
+            limit: usize::MAX,
+        };
+        let v = vec![elem.clone(), elem.clone(), elem];
+        clones.set(0);
+
+        let rc = Rc::<[CloneCount]>::from(&v[..]);
+        assert_eq!(rc.len(), 3);
+        assert_eq!(clones.get(), 3);
+        drop(v);
+        assert_eq!(drops.get(), 3);
+        drop(rc);
+        assert_eq!(drops.get(), 6);
+
+        let rc = Rc::<[String]>::from(&[][..]);
+        assert!(rc.is_empty());
+        let rc = Rc::<[i32]>::from(&[1, 2, 3][..]);
+        assert_eq!(*rc, [1, 2, 3]);
+    }
+
+    #[test]
+    fn from_slice_clone_panics() {
+        let (clones, drops) = (Cell::new(0), Cell::new(0));
+        let v: Vec<_> = (0..4)
+            .map(|_| CloneCount {
+                clones: &clones,
+                drops: &drops,
+                limit: 3,
+            })
+            .collect();
+        // the third clone panics; the two before it must be dropped, and
+        // the allocation freed (which miri checks)
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            Rc::<[CloneCount]>::from(&v[..])
+        }));
+        assert!(result.is_err());
+        assert_eq!(clones.get(), 3);
+        assert_eq!(drops.get(), 2);
+        drop(v);
+        assert_eq!(drops.get(), 6);
+    }
+
+    #[test]
+    fn from_array() {
+        let rc = Rc::<[i32]>::from([1, 2, 3]);
+        assert_eq!(*rc, [1, 2, 3]);
+
+        // DropCount isn't Clone, so the elements can only have been moved
+        let drops = Cell::new(0);
+        let rc = Rc::<[DropCount]>::from([DropCount(&drops), DropCount(&drops)]);
+        let weak = Rc::downgrade(&rc);
+        assert_eq!(rc.len(), 2);
+        assert_eq!(drops.get(), 0);
+        drop(rc);
+        assert_eq!(drops.get(), 2);
+        drop(weak);
+
+        let rc = Rc::<[String]>::from([]);
+        assert!(rc.is_empty());
+        let rc = Rc::<[()]>::from([(); 5]);
+        assert_eq!(rc.len(), 5);
+    }
+
+    #[test]
+    fn from_os_str() {
+        let rc: Rc<OsStr> = Rc::from(OsStr::new("héllo"));
+        assert_eq!(&*rc, "héllo");
+        let rc: Rc<OsStr> = Rc::from(OsString::from("world"));
+        assert_eq!(rc.to_str(), Some("world"));
+        let rc: Rc<OsStr> = Rc::from(OsStr::new(""));
+        assert!(rc.is_empty());
+    }
+
+    #[test]
+    fn from_path() {
+        fn file_name(path: impl AsRef<Path>) -> Option<String> {
+            path.as_ref()
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        }
+
+        let rc: Rc<Path> = Rc::from(Path::new("/etc/hosts"));
+        assert_eq!(&*rc, Path::new("/etc/hosts"));
+        assert_eq!(file_name(rc.clone()), Some(String::from("hosts")));
+        assert_eq!(rc.parent(), Some(Path::new("/etc")));
+
+        let rc: Rc<Path> = Rc::from(PathBuf::from("a").join("b"));
+        assert_eq!(rc.components().count(), 2);
+        let rc: Rc<Path> = Rc::from(PathBuf::new());
+        assert_eq!(rc.as_os_str().len(), 0);
+        assert_eq!(file_name(rc), None);
+    }
+
+    #[test]
+    fn from_c_str() {
+        let c = CString::new("hello").unwrap();
+        let rc: Rc<CStr> = Rc::from(c.as_c_str());
+        assert_eq!(rc.to_bytes_with_nul(), b"hello\0");
+        assert_eq!(rc.to_str(), Ok("hello"));
+        assert_eq!(&*rc, c.as_c_str());
+
+        let rc: Rc<CStr> = Rc::from(c);
+        assert_eq!(rc.to_bytes(), b"hello");
+        let rc: Rc<CStr> = Rc::from(CString::default());
+        assert_eq!(rc.to_bytes_with_nul(), b"\0");
+        assert!(rc.to_bytes().is_empty());
+    }
+
+    #[test]
+    fn from_fn() {
+        let rc = Rc::from_fn(5, |i| i as i32 * 10);
+        assert_eq!(*rc, [0, 10, 20, 30, 40]);
+        let rc: Rc<[String]> = Rc::from_fn(0, |_| unreachable!());
+        assert!(rc.is_empty());
+
+        let mut calls = Vec::new();
+        let rc = Rc::from_fn(3, |i| {
+            calls.push(i);
+            i.to_string()
+        });
+        assert_eq!(calls, [0, 1, 2]);
+        assert_eq!(rc[2], "2");
+    }
+
+    #[test]
+    fn from_fn_panics() {
+        let drops = Cell::new(0);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            Rc::from_fn(5, |i| {
+                assert!(i < 3, "stop at 3");
+                DropCount(&drops)
+            })
+        }));
+        assert!(result.is_err());
+        // exactly the three that were made, and none of the slots after them
+        assert_eq!(drops.get(), 3);
+    }
+
+    #[test]
+    fn iterate_shared_slice() {
+        let rc: Rc<[i32]> = Rc::from(vec![1, 2, 3, 4]);
+        let mut sum = 0;
+        for x in &rc {
+            sum += x;
+        }
+        assert_eq!(sum, 10);
+        assert_eq!(
+            (&rc).into_iter().rev().copied().collect::<Vec<_>>(),
+            [4, 3, 2, 1]
+        );
+        assert_eq!(Rc::strong_count(&rc), 1);
+    }
+
+    #[test]
+    fn from_str() {
+        let rc: Rc<str> = Rc::from("hello");
+        assert_eq!(&*rc, "hello");
+        let rc: Rc<str> = Rc::from(String::new());
+        assert_eq!(&*rc, "");
+    }
+
+    #[test]
+    fn new_uninit_slice_empty() {
+        let rc = unsafe { Rc::<String>::new_uninit_slice(0).assume_init() };
+        assert!(rc.is_empty());
+    }
+
+    #[test]
+    fn new_uninit_slice_drops() {
+        let drops = Cell::new(0);
+
+        // uninit slots are never dropped, even if they were written
+        let mut rc = Rc::new_uninit_slice(2);
+        Rc::get_mut(&mut rc).unwrap()[0].write(DropCount(&drops));
+        drop(rc);
+        assert_eq!(drops.get(), 0);
+
+        let mut rc = Rc::new_uninit_slice(4);
+        for slot in Rc::get_mut(&mut rc).unwrap() {
+            slot.write(DropCount(&drops));
+        }
+        let rc = unsafe { rc.assume_init() };
+        let rc2 = rc.clone();
+        drop(rc);
+        assert_eq!(drops.get(), 0);
+        drop(rc2);
+        assert_eq!(drops.get(), 4);
+    }
+
+    #[test]
+    fn default_allocator_keeps_rc_spelling() {
+        let rc: Rc<i32> = Rc::new(1);
+        let rc2: Rc<i32, Global> = rc.clone();
+        assert_eq!(*rc + *rc2, 2);
+        assert_eq!(
+            std::mem::size_of::<Rc<i32>>(),
+            std::mem::size_of::<*const ()>()
+        );
+    }
+
+    #[test]
+    fn unwind_safe() {
+        fn assert_unwind_safe<T: UnwindSafe + RefUnwindSafe>() {}
+        assert_unwind_safe::<Rc<i32>>();
+        assert_unwind_safe::<Rc<str>>();
+        assert_unwind_safe::<Weak<String>>();
+
+        // a RefCell hands out &mut T through a shared reference, which a
+        // caught panic can leave half updated, so like std's it needs
+        // AssertUnwindSafe to cross catch_unwind
+        let rc = Rc::new(crate::refcell::RefCell::new(vec![1]));
+        let weak = Rc::downgrade(&rc);
+        let captured = rc.clone();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || {
+            let mut v = captured.borrow_mut().unwrap();
+            v.push(2);
+            panic!("oops");
+        }));
+        assert!(result.is_err());
+        // the closure's clone and its borrow were both dropped while unwinding
+        assert_eq!(Rc::strong_count(&rc), 1);
+        assert_eq!(*rc.borrow().unwrap(), [1, 2]);
+        assert!(weak.upgrade().is_some());
+    }
+
+    #[test]
+    fn unpin() {
+        fn assert_unpin<T: Unpin + ?Sized>() {}
+        assert_unpin::<Rc<i32>>();
+        assert_unpin::<Rc<std::marker::PhantomPinned>>();
+        assert_unpin::<Rc<dyn std::future::Future<Output = ()>>>();
+
+        // so Pin<Rc<T>> can be unwrapped again, even for a !Unpin T
+        let pinned = std::pin::Pin::new(Rc::new(5));
+        assert_eq!(*std::pin::Pin::into_inner(pinned), 5);
+    }
+
+    // compiles only because Rc<T> is covariant in T, like &T and Box<T>
+    fn shorten<'a>(rc: Rc<&'static str>) -> Rc<&'a str> {
+        rc
+    }
+
+    fn shorten_weak<'a>(weak: Weak<&'static str>) -> Weak<&'a str> {
+        weak
+    }
+
+    #[test]
+    fn covariant() {
+        let rc = Rc::new("hello");
+        let weak = shorten_weak(Rc::downgrade(&rc));
+        let local = String::from("world");
+        let shorter = [shorten(rc), Rc::new(&local[..])];
+        assert_eq!(*shorter[0], "hello");
+        assert_eq!(*weak.upgrade().unwrap(), "hello");
+    }
+
+    #[test]
+    fn option_rc_is_pointer_sized() {
+        use std::fmt::Debug;
+        use std::mem::size_of;
+
+        // the documented guarantee
+        assert_eq!(size_of::<Option<Rc<u8>>>(), size_of::<Rc<u8>>());
+        assert_eq!(size_of::<Option<Weak<u8>>>(), size_of::<Weak<u8>>());
+        assert_eq!(size_of::<Option<Rc<str>>>(), size_of::<Rc<str>>());
+        assert_eq!(
+            size_of::<Option<Rc<dyn Debug>>>(),
+            size_of::<Rc<dyn Debug>>()
+        );
+        assert_eq!(
+            size_of::<Option<Rc<[u8], Global, u32>>>(),
+            size_of::<Rc<[u8], Global, u32>>()
+        );
+        assert_eq!(size_of::<Option<Weak<str>>>(), size_of::<Weak<str>>());
+
+        // and what that size is
+        assert_eq!(size_of::<Rc<u64>>(), size_of::<usize>());
+        assert_eq!(size_of::<Weak<u64>>(), size_of::<usize>());
+        assert_eq!(size_of::<Rc<[u64]>>(), 2 * size_of::<usize>());
+
+        // an allocator with state makes the Rc bigger, but the niche stays
+        assert_eq!(
+            size_of::<Option<Rc<u8, &CountingAlloc>>>(),
+            size_of::<Rc<u8, &CountingAlloc>>()
+        );
+    }
+
+    // a value whose comparisons are all counted
+    #[derive(Default)]
+    struct CountingEq {
+        comparisons: std::cell::Cell<usize>,
+    }
+
+    impl PartialEq for CountingEq {
+        fn eq(&self, other: &Self) -> bool {
+            self.comparisons.set(self.comparisons.get() + 1);
+            other.comparisons.set(other.comparisons.get() + 1);
+            true
+        }
+    }
+
+    impl Eq for CountingEq {}
+
+    #[test]
+    fn eq_compares_values() {
+        let a = Rc::new(String::from("hello"));
+        let b = Rc::new(String::from("hello"));
+        assert!(a == b);
+        assert!(a != Rc::new(String::from("world")));
+        let (x, y): (Rc<str>, Rc<str>) = (Rc::from("abc"), Rc::from("abc"));
+        assert!(x == y);
+
+        let distinct = Rc::new(CountingEq::default());
+        let other = Rc::new(CountingEq::default());
+        assert!(distinct == other);
+        assert_eq!(distinct.comparisons.get(), 1);
+    }
+
+    // CountingEq's eq always returns true
+    unsafe impl ReflexiveEq for CountingEq {}
+
+    // a value that can't be compared at all
+    #[derive(Eq)]
+    struct PanicEq;
+
+    impl PartialEq for PanicEq {
+        fn eq(&self, _: &Self) -> bool {
+            panic!("compared a PanicEq");
+        }
+    }
+
+    unsafe impl ReflexiveEq for PanicEq {}
+
+    #[test]
+    fn ptr_eq_or_eq_skips_values_for_the_same_allocation() {
+        let a = Rc::new(CountingEq::default());
+        let b = a.clone();
+        assert!(Rc::ptr_eq_or_eq(&a, &b));
+        assert_eq!(a.comparisons.get(), 0);
+        assert!(Rc::ptr_eq_or_eq(&a, &Rc::new(CountingEq::default())));
+        assert_eq!(a.comparisons.get(), 1);
+
+        let p = Rc::new(PanicEq);
+        assert!(Rc::ptr_eq_or_eq(&p, &p.clone()));
+
+        let v = Rc::new(vec![String::from("a"); 3]);
+        assert!(Rc::ptr_eq_or_eq(&v, &v.clone()));
+        assert!(!Rc::ptr_eq_or_eq(&v, &Rc::new(vec![])));
+    }
+
+    #[test]
+    #[cfg(feature = "nightly")]
+    fn eq_skips_values_for_the_same_allocation() {
+        let a = Rc::new(CountingEq::default());
+        let b = a.clone();
+        assert!(a == b);
+        assert!(!(a != b));
+        assert_eq!(a.comparisons.get(), 0);
+        let p = Rc::new(PanicEq);
+        assert!(p == p.clone());
+    }
+
+    #[test]
+    fn fmt_forwards_to_the_value() {
+        let rc = Rc::new(String::from("hi"));
+        assert_eq!(format!("{}", rc), "hi");
+        assert_eq!(format!("{:?}", rc), "\"hi\"");
+        assert_eq!(format!("{:>4}", Rc::new(7)), "   7");
+        assert_eq!(format!("{:?}", Rc::<[u8]>::from(vec![1, 2])), "[1, 2]");
+    }
+
+    // config failed to load, because the disk read failed, because the
+    // disk was unplugged; the disk error is shared through an Rc
+    #[derive(Debug)]
+    struct Unplugged;
+
+    impl fmt::Display for Unplugged {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("device unplugged")
+        }
+    }
+
+    impl Error for Unplugged {}
+
+    #[derive(Debug)]
+    struct DiskError(Unplugged);
+
+    impl fmt::Display for DiskError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("read failed")
+        }
+    }
+
+    impl Error for DiskError {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    #[derive(Debug)]
+    struct ConfigError(Rc<std::io::Error>);
+
+    impl fmt::Display for ConfigError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("couldn't load config")
+        }
+    }
+
+    impl Error for ConfigError {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    #[test]
+    fn error_chain_through_rc() {
+        let io = std::io::Error::other(DiskError(Unplugged));
+        let shared = Rc::new(io);
+        let err = ConfigError(shared.clone());
+
+        let mut chain = Vec::new();
+        let mut next: Option<&dyn Error> = Some(&err);
+        while let Some(e) = next {
+            chain.push(e.to_string());
+            next = e.source();
+        }
+        // io::Error's source skips the error it wraps and goes straight to
+        // that error's source
+        assert_eq!(
+            chain,
+            ["couldn't load config", "read failed", "device unplugged"]
+        );
+        assert!(err.source().unwrap().is::<Rc<std::io::Error>>());
+
+        let rc = Rc::new(Unplugged);
+        let dyn_err: &dyn Error = &rc;
+        assert_eq!(dyn_err.to_string(), "device unplugged");
+        assert!(dyn_err.source().is_none());
+        assert_eq!(Rc::strong_count(&shared), 2);
+    }
+
+    #[test]
+    fn into_dyn_error_and_back() {
+        let err: Rc<dyn Error> = Rc::from(DiskError(Unplugged));
+        assert_eq!(err.to_string(), "read failed");
+        assert_eq!(err.source().unwrap().to_string(), "device unplugged");
+        let shared = err.clone();
+
+        let err = err.downcast::<Unplugged>().unwrap_err();
+        let disk = err.downcast::<DiskError>().unwrap();
+        assert_eq!(disk.0.to_string(), "device unplugged");
+        // still the same allocation
+        assert_eq!(Rc::strong_count(&disk), 2);
+        drop(shared);
+        assert_eq!(Rc::strong_count(&disk), 1);
+
+        let errors: Vec<Rc<dyn Error>> = vec![
+            Rc::from(Unplugged),
+            Rc::from(std::io::Error::other("io")),
+            Rc::from("x".parse::<i32>().unwrap_err()),
+        ];
+        assert_eq!(errors[1].to_string(), "io");
+        assert!(errors[2].is::<std::num::ParseIntError>());
+
+        fn parse(s: &str) -> Result<i32, Rc<dyn Error>> {
+            Ok(s.parse::<i32>()?)
+        }
+        assert_eq!(parse("3").unwrap(), 3);
+        assert!(parse("three")
+            .unwrap_err()
+            .downcast::<std::num::ParseIntError>()
+            .is_ok());
+    }
+
+    #[test]
+    fn downcast_any() {
+        let (inner, alloc) = Rc::into_inner_with_allocator(Rc::new(5u32));
+        let inner: NonNull<RcInner<dyn Any>> = inner;
+        let any = unsafe { Rc::from_inner_in(inner, alloc) };
+        let any = any.downcast::<String>().unwrap_err();
+        assert_eq!(*any.downcast::<u32>().unwrap(), 5);
+    }
+
+    #[test]
+    fn eq_bare_value() {
+        let rc = Rc::new(5);
+        assert!(rc == 5);
+        assert!(rc != 6);
+        let s: Rc<str> = Rc::from("abc");
+        assert!(s == *"abc");
+        assert!(Rc::new(vec![1, 2]) == vec![1, 2]);
+    }
+
+    #[test]
+    fn nan_is_not_equal_to_itself() {
+        let nan = Rc::new(f64::NAN);
+        let same = nan.clone();
+        assert!(nan != same);
+        assert!(!(nan == same));
+        assert!(Rc::new([f64::NAN]) != Rc::new([f64::NAN]));
+    }
+
+    #[test]
+    fn u32_counts() {
+        let rc = Rc::<String, Global, u32>::new_counted(String::from("hello"));
+        let rc2 = rc.clone();
+        let weak: Weak<String, Global, u32> = Rc::downgrade(&rc);
+        assert_eq!((Rc::strong_count(&rc), Rc::weak_count(&rc)), (2, 1));
+        drop(rc);
+        assert_eq!(*weak.upgrade().unwrap(), "hello");
+        drop(rc2);
+        assert!(weak.upgrade().is_none());
+
+        let mut rc = Rc::<u8, Global, u32>::uninit_slice_with(2, Global);
+        Rc::get_mut(&mut rc).unwrap().fill(MaybeUninit::new(1));
+        assert_eq!(*unsafe { rc.assume_init() }, [1, 1]);
+    }
+
+    #[test]
+    fn smaller_counts_shrink_the_allocation() {
+        use std::mem::size_of;
+
+        assert_eq!(size_of::<RcInner<u32>>(), 3 * size_of::<usize>());
+        assert_eq!(size_of::<RcInner<u32, u32>>(), 12);
+        assert_eq!(size_of::<RcInner<u8, u8>>(), 3);
+        // the handle itself stays one pointer either way
+        assert_eq!(size_of::<Rc<u32, Global, u32>>(), size_of::<Rc<u32>>());
+    }
+
+    // sequences that are only interesting under cargo miri test, which
+    // checks every pointer access against stacked borrows and reports any
+    // allocation that isn't freed
+    mod aliasing {
+        use super::*;
+
+        #[test]
+        fn clone_drop_orders() {
+            let a = Rc::new(vec![1, 2, 3]);
+            let clones: Vec<_> = (0..4).map(|_| a.clone()).collect();
+            let value = &**a;
+            // dropping other Rcs while a borrow of the value is out
+            for (i, rc) in clones.into_iter().enumerate() {
+                assert_eq!(rc[i % 3], value[i % 3]);
+                drop(rc);
+                assert_eq!(value.len(), 3);
+            }
+            assert_eq!(Rc::strong_count(&a), 1);
+
+            let b = a.clone();
+            drop(a);
+            // the last drop, through the clone rather than the original
+            drop(b);
+        }
+
+        #[test]
+        fn last_drop_with_weaks_around() {
+            let rc: Rc<str> = Rc::from("hello");
+            let weak = Rc::downgrade(&rc);
+            let weak2 = weak.clone();
+            drop(rc);
+            // only the counts are read from here on, the str is gone
+            assert_eq!(weak.strong_count(), 0);
+            assert_eq!(weak2.weak_count(), 0);
+            assert!(weak.upgrade().is_none());
+            drop(weak);
+            drop(weak2);
+
+            let rc = Rc::new(String::from("hello"));
+            let weak = Rc::downgrade(&rc);
+            assert_eq!(Rc::try_unwrap(rc).unwrap(), "hello");
+            assert!(weak.upgrade().is_none());
+        }
+
+        // reads its own counts from its destructor, while the allocation is
+        // in the middle of being torn down
+        struct SelfRef {
+            me: std::cell::RefCell<Weak<SelfRef>>,
+            seen: std::rc::Rc<std::cell::Cell<(usize, bool)>>,
+        }
+
+        impl Drop for SelfRef {
+            fn drop(&mut self) {
+                let me = self.me.borrow();
+                self.seen.set((me.strong_count(), me.upgrade().is_some()));
+            }
+        }
+
+        #[test]
+        fn drop_looks_at_own_counts() {
+            let seen = std::rc::Rc::new(std::cell::Cell::new((1, true)));
+            let rc = Rc::new(SelfRef {
+                me: std::cell::RefCell::new(Weak::new()),
+                seen: seen.clone(),
+            });
+            *rc.me.borrow_mut() = Rc::downgrade(&rc);
+            drop(rc);
+            assert_eq!(seen.get(), (0, false));
+        }
+
+        #[test]
+        fn get_mut_unchecked_next_to_count_reads() {
+            let mut a = Rc::new([0u8; 4]);
+            let b = a.clone();
+            let weak = Rc::downgrade(&b);
+            let value = unsafe { Rc::get_mut_unchecked(&mut a) };
+            // the counts aren't part of the value, so reading them doesn't
+            // invalidate the &mut
+            assert_eq!(Rc::strong_count(&b), 2);
+            assert_eq!(weak.weak_count(), 1);
+            value[0] = 1;
+            drop(weak.upgrade());
+            value[1] = 2;
+            assert_eq!(*b, [1, 2, 0, 0]);
+        }
+    }
+
+    // these only compile because of may_dangle
+    // the Rcs are declared before what they borrow so they're dropped after
+    // it, which is the whole point, hence the late inits
+    #[cfg(feature = "nightly")]
+    #[allow(clippy::needless_late_init)]
+    mod dropck {
+        use super::*;
+
+        struct Foo<'a>(&'a str);
+
+        #[test]
+        fn rc_outlives_its_borrow() {
+            let rc;
+            let weak;
+            let s = String::from("hello");
+            rc = Rc::new(Foo(&s));
+            weak = Rc::downgrade(&rc);
+            assert_eq!(rc.0, "hello");
+            assert_eq!(weak.strong_count(), 1);
+            // s is dropped first, then weak and rc, which still point at it
+        }
+
+        #[test]
+        fn cycle_of_borrows() {
+            // each node borrows the other's cell, and they're dropped
+            // together
+            struct Node<'a> {
+                other: std::cell::Cell<Option<&'a Rc<Node<'a>>>>,
+            }
+            let (a, b);
+            a = Rc::new(Node {
+                other: std::cell::Cell::new(None),
+            });
+            b = Rc::new(Node {
+                other: std::cell::Cell::new(Some(&a)),
+            });
+            a.other.set(Some(&b));
+            assert!(Rc::ptr_eq(a.other.get().unwrap(), &b));
+            assert!(Rc::ptr_eq(b.other.get().unwrap(), &a));
+        }
+    }
+
+    #[cfg(feature = "nightly")]
+    mod coerce {
+        use super::*;
+        use std::fmt::Display;
+
+        trait Shape {
+            fn area(&self) -> u32;
+
+            fn by_rc(self: Rc<Self>) -> u32;
+        }
+
+        struct Square(u32);
+
+        impl Shape for Square {
+            fn area(&self) -> u32 {
+                self.0 * self.0
+            }
+
+            fn by_rc(self: Rc<Self>) -> u32 {
+                self.area() + 1
+            }
+        }
+
+        #[test]
+        fn coerce_to_dyn() {
+            let r: Rc<dyn Display> = Rc::new(5u32);
+            assert_eq!(r.to_string(), "5");
+
+            let slice: Rc<[u8]> = Rc::new([1, 2, 3]);
+            assert_eq!(*slice, [1, 2, 3]);
+        }
+
+        #[test]
+        fn dyn_error_source() {
+            let err: Rc<dyn Error> = Rc::new(DiskError(Unplugged));
+            assert_eq!(err.to_string(), "read failed");
+            assert_eq!(err.source().unwrap().to_string(), "device unplugged");
+            let outer: &dyn Error = &*err;
+            assert!(outer.source().unwrap().is::<Unplugged>());
+        }
+
+        #[test]
+        fn trait_method_through_rc() {
+            let shape: Rc<dyn Shape> = Rc::new(Square(3));
+            let shape2 = shape.clone();
+            assert_eq!(shape.area(), 9);
+            drop(shape);
+            assert_eq!(shape2.area(), 9);
+        }
+
+        #[test]
+        fn rc_self_receiver() {
+            let shape: Rc<dyn Shape> = Rc::new(Square(4));
+            assert_eq!(shape.by_rc(), 17);
+        }
+    }
+
+    #[cfg(feature = "allocator-api")]
+    mod allocator {
+        use super::*;
+
+        #[test]
+        fn every_allocation_is_returned() {
+            let alloc = CountingAlloc::default();
+            {
+                let rc = Rc::new_in(String::from("hello"), &alloc);
+                let rc2 = rc.clone();
+                let other = Rc::new_in([1u64; 4], &alloc);
+                assert_eq!(alloc.live.borrow().len(), 2);
+                drop(rc);
+                assert_eq!(*rc2, "hello");
+                assert_eq!(other[3], 1);
+                assert!(std::ptr::eq(*Rc::allocator(&rc2), &alloc));
+            }
+            assert_eq!(alloc.allocations.get(), 2);
+            assert!(alloc.live.borrow().is_empty());
+        }
+
+        #[test]
+        fn uninit_slice_in() {
+            let alloc = CountingAlloc::default();
+            let mut rc = Rc::new_uninit_slice_in(3, &alloc);
+            for (i, slot) in Rc::get_mut(&mut rc).unwrap().iter_mut().enumerate() {
+                slot.write(i);
+            }
+            let rc = unsafe { rc.assume_init() };
+            assert_eq!(*rc, [0, 1, 2]);
+            drop(rc);
+            assert_eq!(alloc.allocations.get(), 1);
+            assert!(alloc.live.borrow().is_empty());
+        }
+
+        #[test]
+        fn clone_shares_the_allocator() {
+            let alloc = CountingAlloc::default();
+            let mut rc = Rc::new_in(5, &alloc);
+            *Rc::get_mut(&mut rc).unwrap() += 1;
+            let clones: Vec<_> = (0..10).map(|_| rc.clone()).collect();
+            assert_eq!(alloc.allocations.get(), 1);
+            drop(rc);
+            assert_eq!(alloc.live.borrow().len(), 1);
+            assert!(clones.iter().all(|rc| **rc == 6));
+            drop(clones);
+            assert!(alloc.live.borrow().is_empty());
+        }
+
+        // the same counting, written against std's allocator trait
+        #[cfg(feature = "nightly-allocator-api")]
+        #[derive(Default)]
+        struct StdCounting {
+            allocs: std::cell::Cell<usize>,
+            frees: std::cell::Cell<usize>,
+        }
+
+        #[cfg(feature = "nightly-allocator-api")]
+        unsafe impl std::alloc::Allocator for StdCounting {
+            fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, std::alloc::AllocError> {
+                self.allocs.set(self.allocs.get() + 1);
+                std::alloc::Global.allocate(layout)
+            }
+
+            unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+                self.frees.set(self.frees.get() + 1);
+                std::alloc::Global.deallocate(ptr, layout)
+            }
+        }
+
+        #[test]
+        #[cfg(feature = "nightly-allocator-api")]
+        fn std_allocator() {
+            use crate::alloc::StdAlloc;
+
+            let alloc = StdCounting::default();
+            {
+                let rc = Rc::new_in(String::from("hello"), StdAlloc(&alloc));
+                let weak = Rc::downgrade(&rc);
+                let array = Rc::new_in([7u8; 4], StdAlloc(&alloc));
+                assert_eq!(alloc.allocs.get(), 2);
+                drop(rc);
+                // the weak still holds the allocation
+                assert_eq!(alloc.frees.get(), 0);
+                assert!(weak.upgrade().is_none());
+                drop(weak);
+                assert_eq!(alloc.frees.get(), 1);
+                assert_eq!(*array, [7; 4]);
+                drop(array);
             }
-            _ => inner.refcount.set(count - 1),
+            assert_eq!(alloc.allocs.get(), 2);
+            assert_eq!(alloc.frees.get(), 2);
         }
     }
 }
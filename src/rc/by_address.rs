@@ -0,0 +1,99 @@
+// RcByAddress compares and hashes an Rc by which allocation it points to,
+// not by the value in it
+//
+// e.g. a HashSet<RcByAddress<Node>> of visited nodes in a graph: two nodes
+// that happen to hold equal values are still different nodes, and T doesn't
+// need to implement Hash or Eq at all
+use super::{Count, Rc};
+use crate::alloc::{Allocator, Global};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+
+pub struct RcByAddress<T: ?Sized, A: Allocator = Global, C: Count = usize>(pub Rc<T, A, C>);
+
+impl<T: ?Sized, A: Allocator, C: Count> RcByAddress<T, A, C> {
+    // the address without any slice length or vtable, which can differ
+    // between pointers to the same allocation
+    fn addr(&self) -> *const () {
+        Rc::as_ptr(&self.0) as *const ()
+    }
+}
+
+impl<T: ?Sized, A: Allocator, C: Count> PartialEq for RcByAddress<T, A, C> {
+    fn eq(&self, other: &Self) -> bool {
+        self.addr() == other.addr()
+    }
+}
+
+impl<T: ?Sized, A: Allocator, C: Count> Eq for RcByAddress<T, A, C> {}
+
+impl<T: ?Sized, A: Allocator, C: Count> Hash for RcByAddress<T, A, C> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.addr().hash(state)
+    }
+}
+
+impl<T: ?Sized, A: Allocator + Clone, C: Count> Clone for RcByAddress<T, A, C> {
+    fn clone(&self) -> Self {
+        RcByAddress(self.0.clone())
+    }
+}
+
+impl<T: ?Sized, A: Allocator, C: Count> Deref for RcByAddress<T, A, C> {
+    type Target = Rc<T, A, C>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T: ?Sized, A: Allocator, C: Count> From<Rc<T, A, C>> for RcByAddress<T, A, C> {
+    fn from(rc: Rc<T, A, C>) -> Self {
+        RcByAddress(rc)
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, A: Allocator, C: Count> fmt::Debug for RcByAddress<T, A, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("RcByAddress").field(&self.0).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn same_allocation_collapses() {
+        let a = Rc::new(String::from("node"));
+        let mut set = HashSet::new();
+        assert!(set.insert(RcByAddress(a.clone())));
+        assert!(!set.insert(RcByAddress(a.clone())));
+        assert_eq!(set.len(), 1);
+        assert!(set.contains(&RcByAddress(a)));
+    }
+
+    #[test]
+    fn equal_values_stay_apart() {
+        let a = Rc::new(String::from("node"));
+        let b = Rc::new(String::from("node"));
+        assert!(a == b);
+        let set: HashSet<_> = vec![a.clone(), b, a]
+            .into_iter()
+            .map(RcByAddress::from)
+            .collect();
+        assert_eq!(set.len(), 2);
+        assert_ne!(RcByAddress(Rc::new(1)), RcByAddress(Rc::new(1)));
+    }
+
+    #[test]
+    fn no_hash_needed_on_t() {
+        // f64 is neither Hash nor Eq
+        let x = RcByAddress(Rc::new(f64::NAN));
+        assert_eq!(x, x.clone());
+        assert!(x.is_nan());
+        assert_eq!(format!("{:?}", RcByAddress(Rc::new(2))), "RcByAddress(2)");
+    }
+}
@@ -0,0 +1,60 @@
+// the integer types Rc can use for its strong and weak counts
+//
+// an Rc with u32 counts is 8 bytes smaller per allocation on 64 bit targets,
+// which adds up with millions of small nodes, at the cost of only allowing
+// about four billion handles to one allocation
+//
+// going past the maximum would wrap the count around and free the value
+// while handles to it still exist, so instead the process is aborted, the
+// same thing std does when its (usize) counts overflow
+use std::fmt;
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// implemented for the unsigned integer types
+///
+/// sealed, since Rc's soundness depends on these never wrapping around
+pub trait Count: Copy + Eq + fmt::Debug + private::Sealed {
+    const ZERO: Self;
+    const ONE: Self;
+
+    /// add one, aborting on overflow
+    fn increment(self) -> Self;
+
+    /// subtract one
+    /// never called on zero, since a count only goes down when a handle that
+    /// it counted goes away
+    fn decrement(self) -> Self;
+
+    fn as_usize(self) -> usize;
+}
+
+macro_rules! impl_count {
+    ($($t:ty),*) => {$(
+        impl private::Sealed for $t {}
+
+        impl Count for $t {
+            const ZERO: Self = 0;
+            const ONE: Self = 1;
+
+            fn increment(self) -> Self {
+                match self.checked_add(1) {
+                    Some(count) => count,
+                    None => std::process::abort(),
+                }
+            }
+
+            fn decrement(self) -> Self {
+                self - 1
+            }
+
+            fn as_usize(self) -> usize {
+                self as usize
+            }
+        }
+    )*};
+}
+
+impl_count!(u8, u16, u32, u64, usize);
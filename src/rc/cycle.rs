@@ -0,0 +1,168 @@
+// cycle detection, behind the cycle-debug feature
+//
+// a strong cycle keeps itself alive forever: every node in it has a strong
+// count of at least one even when nothing outside points in
+// Rc can't see what a value holds, so this walks the strong edges the
+// values list through Trace, the same as trace::find_cycles
+// find_cycles reports every cycle reachable from some roots, this only
+// answers whether one particular Rc is in one
+use super::{Count, Rc};
+use crate::alloc::Allocator;
+use crate::trace::{CycleMember, ErasedRc, Trace};
+use std::collections::HashSet;
+
+fn children_of(node: &dyn ErasedRc) -> Vec<Box<dyn ErasedRc>> {
+    let mut children = Vec::new();
+    node.trace_value(&mut |child| children.push(child.clone_erased()));
+    // popped off the end, so reverse to visit in Trace's order
+    children.reverse();
+    children
+}
+
+fn member(node: &dyn ErasedRc) -> CycleMember {
+    CycleMember {
+        type_name: node.type_name(),
+        address: node.address(),
+    }
+}
+
+impl<T, A, C> Rc<T, A, C>
+where
+    T: Trace + 'static,
+    A: Allocator + Clone + 'static,
+    C: Count + 'static,
+{
+    /// if this is part of a strong cycle, the allocations of one such cycle,
+    /// starting with this one
+    ///
+    /// the cycle has to come back to this node, cycles only reachable from
+    /// it aren't reported
+    pub fn debug_reachable_cycle(this: &Self) -> Option<Vec<CycleMember>> {
+        // depth first, keeping the current path and each node's children
+        // that haven't been looked at yet
+        // a node that's already been visited can't lead back here, since
+        // that would have been found the first time round
+        let start = ErasedRc::address(this);
+        let mut visited = HashSet::new();
+        visited.insert(start);
+        let mut path: Vec<(Box<dyn ErasedRc>, _)> = vec![(this.clone_erased(), children_of(this))];
+        while let Some((_, pending)) = path.last_mut() {
+            let child = match pending.pop() {
+                Some(child) => child,
+                None => {
+                    path.pop();
+                    continue;
+                }
+            };
+            if child.address() == start {
+                return Some(path.iter().map(|(node, _)| member(&**node)).collect());
+            }
+            if visited.insert(child.address()) {
+                let grandchildren = children_of(&*child);
+                path.push((child, grandchildren));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::refcell::RefCell;
+
+    struct Node {
+        name: &'static str,
+        edges: RefCell<Vec<Rc<Node>>>,
+    }
+
+    impl Trace for Node {
+        fn trace(&self, visitor: &mut dyn FnMut(&dyn ErasedRc)) {
+            self.edges.trace(visitor)
+        }
+    }
+
+    fn node(name: &'static str) -> Rc<Node> {
+        Rc::new(Node {
+            name,
+            edges: RefCell::new(Vec::new()),
+        })
+    }
+
+    fn link(from: &Rc<Node>, to: &Rc<Node>) {
+        from.edges.borrow_mut().unwrap().push(to.clone());
+    }
+
+    // the members' names, looked up by address among nodes
+    fn names(cycle: &[CycleMember], nodes: &[&Rc<Node>]) -> Vec<&'static str> {
+        cycle
+            .iter()
+            .map(|member| {
+                assert_eq!(member.type_name, std::any::type_name::<Node>());
+                let node = nodes
+                    .iter()
+                    .find(|node| Rc::as_ptr(node) as usize == member.address)
+                    .expect("a member is one of the nodes");
+                node.name
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_cycle() {
+        let a = node("a");
+        let b = node("b");
+        let c = node("c");
+        let d = node("d");
+        link(&a, &d);
+        link(&a, &b);
+        link(&b, &c);
+        link(&c, &a);
+
+        let nodes = [&a, &b, &c, &d];
+        let cycle = Rc::debug_reachable_cycle(&a).unwrap();
+        assert_eq!(names(&cycle, &nodes), ["a", "b", "c"]);
+        let cycle = Rc::debug_reachable_cycle(&c).unwrap();
+        assert_eq!(names(&cycle, &nodes), ["c", "a", "b"]);
+        // d hangs off the cycle but isn't part of it
+        assert!(Rc::debug_reachable_cycle(&d).is_none());
+        // the same cycle find_cycles reports, in its own order
+        let reports = crate::trace::find_cycles(&[&a]);
+        assert_eq!(reports.len(), 1);
+        let mut found = reports[0].members.clone();
+        let mut cycle = cycle;
+        found.sort_by_key(|member| member.address);
+        cycle.sort_by_key(|member| member.address);
+        assert_eq!(found, cycle);
+
+        // break it so the test doesn't leak
+        c.edges.borrow_mut().unwrap().clear();
+        assert!(Rc::debug_reachable_cycle(&a).is_none());
+    }
+
+    #[test]
+    fn self_loop() {
+        let a = node("a");
+        link(&a, &a);
+        let cycle = Rc::debug_reachable_cycle(&a).unwrap();
+        assert_eq!(names(&cycle, &[&a]), ["a"]);
+        a.edges.borrow_mut().unwrap().clear();
+    }
+
+    #[test]
+    fn acyclic_diamond() {
+        let top = node("top");
+        let left = node("left");
+        let right = node("right");
+        let bottom = node("bottom");
+        link(&top, &left);
+        link(&top, &right);
+        link(&left, &bottom);
+        link(&right, &bottom);
+        for n in [&top, &left, &right, &bottom].iter() {
+            assert!(Rc::debug_reachable_cycle(n).is_none());
+        }
+        // the walk doesn't leave any extra references behind
+        assert_eq!(Rc::strong_count(&bottom), 3);
+    }
+}
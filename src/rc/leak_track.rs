@@ -0,0 +1,149 @@
+// leak tracking, behind the leak-track feature
+//
+// every Rc allocation on this thread is recorded when it's made and removed
+// again when it's freed, so whatever is still in the list at shutdown (or at
+// the end of a test) is either still in use or leaked, usually by a cycle
+//
+// Rcs are !Send, so an allocation is always freed on the thread that made it
+// and a thread local list is enough
+use crate::refcell::RefCell;
+use std::collections::BTreeMap;
+use std::panic::Location;
+
+/// an Rc allocation that hasn't been freed yet
+#[derive(Clone, Copy, Debug)]
+pub struct LiveAlloc {
+    /// the type the Rc was created with, from std::any::type_name
+    pub type_name: &'static str,
+    /// where the allocation starts
+    pub address: usize,
+    /// the line that created the Rc
+    pub location: &'static Location<'static>,
+}
+
+thread_local! {
+    // keyed by address, so the report comes out in a stable order
+    static LIVE: RefCell<BTreeMap<usize, LiveAlloc>> = RefCell::new(BTreeMap::new());
+}
+
+#[track_caller]
+pub(super) fn register(address: usize, type_name: &'static str) {
+    let alloc = LiveAlloc {
+        type_name,
+        address,
+        location: Location::caller(),
+    };
+    LIVE.with(|live| {
+        live.borrow_mut()
+            .expect("leak registry is never borrowed across calls")
+            .insert(address, alloc);
+    });
+}
+
+pub(super) fn deregister(address: usize) {
+    // an Rc can be freed while the thread locals are being torn down, at
+    // which point there's nothing left to report to anyway
+    let _ = LIVE.try_with(|live| {
+        live.borrow_mut()
+            .expect("leak registry is never borrowed across calls")
+            .remove(&address);
+    });
+}
+
+/// every Rc allocation made on this thread that hasn't been freed yet
+pub fn live_allocations() -> Vec<LiveAlloc> {
+    LIVE.with(|live| {
+        live.borrow()
+            .expect("leak registry is never borrowed across calls")
+            .values()
+            .copied()
+            .collect()
+    })
+}
+
+/// how many Rc allocations made on this thread haven't been freed yet
+pub fn live_count() -> usize {
+    LIVE.with(|live| {
+        live.borrow()
+            .expect("leak registry is never borrowed across calls")
+            .len()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rc::{Rc, Weak};
+
+    #[test]
+    fn count_follows_allocations() {
+        let before = live_count();
+        let a = Rc::new(1);
+        let b = Rc::<str>::from("hello");
+        let a2 = a.clone();
+        assert_eq!(live_count(), before + 2);
+        drop(a);
+        assert_eq!(live_count(), before + 2);
+        drop(a2);
+        drop(b);
+        assert_eq!(live_count(), before);
+    }
+
+    #[test]
+    fn weak_keeps_allocation_live() {
+        let before = live_count();
+        let rc = Rc::new(1);
+        let weak = Rc::downgrade(&rc);
+        drop(rc);
+        // the value is gone but the allocation isn't
+        assert_eq!(live_count(), before + 1);
+        drop(weak);
+        assert_eq!(live_count(), before);
+    }
+
+    struct Node {
+        next: RefCell<Option<Rc<Node>>>,
+    }
+
+    fn node(next: Option<Rc<Node>>) -> Node {
+        Node {
+            next: RefCell::new(next),
+        }
+    }
+
+    #[test]
+    fn leaked_cycle_shows_up() {
+        let before = live_count();
+        let a = Rc::new(node(None));
+        let a_line = line!() - 1;
+        let b = Rc::new(node(Some(a.clone())));
+        let b_line = line!() - 1;
+        *a.next.borrow_mut().unwrap() = Some(b.clone());
+
+        let weak: Weak<Node> = Rc::downgrade(&a);
+        drop(a);
+        drop(b);
+
+        // nothing outside the cycle points to it anymore, yet it's still live
+        assert_eq!(live_count(), before + 2);
+        let leaked = live_allocations();
+        let mut lines: Vec<_> = leaked
+            .iter()
+            .filter(|alloc| alloc.type_name.ends_with("Node"))
+            .map(|alloc| {
+                assert_eq!(alloc.location.file(), file!());
+                alloc.location.line()
+            })
+            .collect();
+        lines.sort();
+        assert_eq!(lines, [a_line, b_line]);
+
+        // break the cycle so the test doesn't actually leak
+        let a = weak.upgrade().unwrap();
+        a.next.borrow_mut().unwrap().take();
+        drop(a);
+        assert_eq!(live_count(), before + 1);
+        drop(weak);
+        assert_eq!(live_count(), before);
+    }
+}
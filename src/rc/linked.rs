@@ -0,0 +1,97 @@
+// dropping the head of a long chain of Rcs recurses once per node: the
+// head's drop drops its next Rc, whose drop drops the next one, ... and a
+// long enough list overflows the stack
+//
+// unlink_iteratively walks the chain with a loop instead: it takes each
+// node's next link out before the node is dropped, so every drop only has
+// a None left to deal with
+// it stops at the first node someone else still holds, since that node
+// (and everything after it) stays alive anyway
+//
+// a node type can make its own drop iterative by calling it on its next
+// link, see the tests
+use super::Rc;
+
+/// a value that links to the next one in a chain through an Rc
+pub trait LinkedNode: Sized {
+    /// take the link to the next node out, leaving none behind
+    fn take_next(&mut self) -> Option<Rc<Self>>;
+}
+
+impl<T: LinkedNode> Rc<T> {
+    /// drop this Rc and every node after it that only the chain holds, one
+    /// at a time instead of recursively
+    pub fn unlink_iteratively(this: Self) {
+        let mut next = Some(this);
+        while let Some(rc) = next {
+            next = match Rc::try_unwrap(rc) {
+                // the node is dropped at the end of this arm, with its link
+                // already taken out
+                Ok(mut node) => node.take_next(),
+                // shared, so dropping it here only decrements the count
+                Err(_) => None,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    struct Node<'a> {
+        next: Option<Rc<Node<'a>>>,
+        drops: &'a Cell<usize>,
+    }
+
+    impl LinkedNode for Node<'_> {
+        fn take_next(&mut self) -> Option<Rc<Self>> {
+            self.next.take()
+        }
+    }
+
+    impl Drop for Node<'_> {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+            // every node drop goes through here, so the whole chain is
+            // dropped iteratively, whoever drops the head
+            if let Some(next) = self.next.take() {
+                Rc::unlink_iteratively(next);
+            }
+        }
+    }
+
+    fn chain<'a>(len: usize, drops: &'a Cell<usize>) -> Rc<Node<'a>> {
+        let mut head = None;
+        for _ in 0..len {
+            head = Some(Rc::new(Node { next: head, drops }));
+        }
+        head.expect("len is at least 1")
+    }
+
+    #[test]
+    fn long_chain() {
+        let len = if cfg!(miri) { 100 } else { 1_000_000 };
+        let drops = Cell::new(0);
+        let head = chain(len, &drops);
+        drop(head);
+        assert_eq!(drops.get(), len);
+    }
+
+    #[test]
+    fn stops_at_shared_node() {
+        let drops = Cell::new(0);
+        let head = chain(10, &drops);
+        let mut middle = head.clone();
+        for _ in 0..5 {
+            middle = middle.next.clone().unwrap();
+        }
+        Rc::unlink_iteratively(head);
+        // the five before the shared node are gone, the rest are alive
+        assert_eq!(drops.get(), 5);
+        assert_eq!(Rc::strong_count(&middle), 1);
+        drop(middle);
+        assert_eq!(drops.get(), 10);
+    }
+}
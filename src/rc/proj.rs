@@ -0,0 +1,105 @@
+// RcProj is a handle to one part of a shared value, e.g. just the name of
+// an Rc<Config>
+//
+// it holds on to the whole Rc, so the allocation stays alive, plus a
+// pointer to the part, which is only ever produced by a closure borrowing
+// from the Rc's value; that makes it exactly as long-lived as the Rc
+use super::{Count, Rc};
+use crate::alloc::{Allocator, Global};
+use std::fmt;
+use std::ops::Deref;
+use std::ptr::NonNull;
+
+pub struct RcProj<T: ?Sized, U: ?Sized, A: Allocator = Global, C: Count = usize> {
+    rc: Rc<T, A, C>,
+    part: NonNull<U>,
+}
+
+impl<T: ?Sized, A: Allocator, C: Count> Rc<T, A, C> {
+    /// a handle to the part of the value picked out by f, which keeps the
+    /// whole value alive
+    pub fn project<U, F>(this: Self, f: F) -> RcProj<T, U, A, C>
+    where
+        U: ?Sized,
+        F: FnOnce(&T) -> &U,
+    {
+        let part = NonNull::from(f(&this));
+        RcProj { rc: this, part }
+    }
+}
+
+impl<T: ?Sized, U: ?Sized, A: Allocator, C: Count> RcProj<T, U, A, C> {
+    /// the Rc to the whole value
+    pub fn rc(this: &Self) -> &Rc<T, A, C> {
+        &this.rc
+    }
+}
+
+impl<T: ?Sized, U: ?Sized, A: Allocator, C: Count> Deref for RcProj<T, U, A, C> {
+    type Target = U;
+
+    fn deref(&self) -> &U {
+        // part was borrowed from the value rc still keeps alive
+        unsafe { self.part.as_ref() }
+    }
+}
+
+impl<T: ?Sized, U: ?Sized, A: Allocator + Clone, C: Count> Clone for RcProj<T, U, A, C> {
+    fn clone(&self) -> Self {
+        RcProj {
+            rc: self.rc.clone(),
+            part: self.part,
+        }
+    }
+}
+
+impl<T: ?Sized, U: ?Sized + fmt::Debug, A: Allocator, C: Count> fmt::Debug for RcProj<T, U, A, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Config {
+        name: String,
+        ports: Vec<u16>,
+    }
+
+    fn config() -> Rc<Config> {
+        Rc::new(Config {
+            name: String::from("prod"),
+            ports: vec![80, 443],
+        })
+    }
+
+    #[test]
+    fn projection_keeps_value_alive() {
+        let config = config();
+        let weak = Rc::downgrade(&config);
+        let name = Rc::project(config, |c| &c.name);
+        assert_eq!(*name, "prod");
+        assert_eq!(weak.strong_count(), 1);
+
+        let name2 = name.clone();
+        assert_eq!(weak.strong_count(), 2);
+        drop(name);
+        assert_eq!(name2.len(), 4);
+        assert_eq!(RcProj::rc(&name2).ports, [80, 443]);
+        drop(name2);
+        assert_eq!(weak.strong_count(), 0);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn unsized_parts() {
+        let config = config();
+        let https = Rc::project(config.clone(), |c| &c.ports[1..]);
+        let name = Rc::project(config, |c| c.name.as_str());
+        assert_eq!(*https, [443]);
+        assert_eq!(format!("{:?} {:?}", name, https), "\"prod\" [443]");
+        assert_eq!(Rc::strong_count(RcProj::rc(&name)), 2);
+    }
+}
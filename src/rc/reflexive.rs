@@ -0,0 +1,96 @@
+// ReflexiveEq marks the types for which a == a always holds, so two Rcs
+// pointing at the same allocation can be called equal without looking at
+// the value
+//
+// Eq already says this, but a generic impl can't ask whether T: Eq without
+// specialization, so on stable the shortcut needs its own bound: use
+// Rc::ptr_eq_or_eq where T: ReflexiveEq, while == only skips the value on
+// nightly
+use super::{Count, Rc};
+use crate::alloc::Allocator;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::num::{NonZeroU128, NonZeroU16, NonZeroU32, NonZeroU64, NonZeroU8, NonZeroUsize};
+
+/// types whose values always compare equal to themselves
+///
+/// implement it for your own Eq types to let Rc::ptr_eq_or_eq skip comparing
+/// the values of two Rcs to the same allocation
+///
+/// # Safety
+///
+/// `a == a` must be true for every value `a`, including through interior
+/// mutability: an impl that breaks this makes ptr_eq_or_eq disagree with ==
+pub unsafe trait ReflexiveEq: Eq {}
+
+macro_rules! reflexive {
+    ($($t:ty),* $(,)?) => {
+        $(unsafe impl ReflexiveEq for $t {})*
+    };
+}
+
+reflexive!(
+    (),
+    bool,
+    char,
+    str,
+    String,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    NonZeroU8,
+    NonZeroU16,
+    NonZeroU32,
+    NonZeroU64,
+    NonZeroU128,
+    NonZeroUsize,
+);
+
+unsafe impl<T: ?Sized + ReflexiveEq> ReflexiveEq for &T {}
+unsafe impl<T: ?Sized + ReflexiveEq> ReflexiveEq for Box<T> {}
+unsafe impl<T: ReflexiveEq> ReflexiveEq for [T] {}
+unsafe impl<T: ReflexiveEq, const N: usize> ReflexiveEq for [T; N] {}
+unsafe impl<T: ReflexiveEq> ReflexiveEq for Vec<T> {}
+unsafe impl<T: ReflexiveEq> ReflexiveEq for VecDeque<T> {}
+unsafe impl<T: ReflexiveEq> ReflexiveEq for Option<T> {}
+unsafe impl<T: ReflexiveEq, E: ReflexiveEq> ReflexiveEq for Result<T, E> {}
+unsafe impl<T: ReflexiveEq> ReflexiveEq for BTreeSet<T> {}
+unsafe impl<K: ReflexiveEq, V: ReflexiveEq> ReflexiveEq for BTreeMap<K, V> {}
+unsafe impl<T: ?Sized + ReflexiveEq + ToOwned> ReflexiveEq for Cow<'_, T> {}
+unsafe impl<T: ReflexiveEq + Copy> ReflexiveEq for Cell<T> {}
+unsafe impl<T: ?Sized + ReflexiveEq, A: Allocator, C: Count> ReflexiveEq for Rc<T, A, C> {}
+
+macro_rules! reflexive_tuples {
+    ($(($($t:ident),+)),* $(,)?) => {
+        $(unsafe impl<$($t: ReflexiveEq),+> ReflexiveEq for ($($t,)+) {})*
+    };
+}
+
+reflexive_tuples!(
+    (A),
+    (A, B),
+    (A, B, C),
+    (A, B, C, D),
+    (A, B, C, D, E),
+    (A, B, C, D, E, F),
+);
+
+impl<T: ?Sized + ReflexiveEq, A: Allocator, C: Count> Rc<T, A, C> {
+    /// this == other, but true straight away when both point to the same
+    /// allocation, without comparing the values
+    ///
+    /// what == does on nightly for every T: Eq
+    pub fn ptr_eq_or_eq(this: &Self, other: &Self) -> bool {
+        Rc::ptr_eq(this, other) || **this == **other
+    }
+}
@@ -0,0 +1,187 @@
+// UniqueRc is an Rc that hasn't been shared yet
+//
+// it owns the allocation with a strong count of 0, so Weaks can already be
+// handed out (e.g. to the nodes it's about to be linked with) but none of
+// them can upgrade; with no Rc able to exist, the value can be mutated
+// freely through DerefMut
+// into_rc flips the strong count to 1, from then on it's a normal Rc and
+// the earlier Weaks start upgrading
+use super::{release_weak, Count, Rc, RcInner, Weak};
+use crate::alloc::{Allocator, Global};
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::ptr::{self, NonNull};
+
+pub struct UniqueRc<T, A: Allocator = Global, C: Count = usize> {
+    inner: NonNull<RcInner<T, C>>,
+    _marker: PhantomData<RcInner<T, C>>,
+    // invariant in T: the Weaks out there still have the original T, so
+    // shortening a lifetime inside it through DerefMut and then upgrading
+    // one of them would let the short-lived value escape
+    _invariant: PhantomData<*mut T>,
+    alloc: A,
+}
+
+impl<T> UniqueRc<T> {
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    pub fn new(value: T) -> Self {
+        UniqueRc::new_with(value, Global)
+    }
+}
+
+impl<T, A: Allocator> UniqueRc<T, A> {
+    /// like new, but the RcInner is allocated from alloc
+    #[cfg(feature = "allocator-api")]
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    pub fn new_in(value: T, alloc: A) -> Self {
+        UniqueRc::new_with(value, alloc)
+    }
+}
+
+impl<T, A: Allocator, C: Count> UniqueRc<T, A, C> {
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    fn new_with(value: T, alloc: A) -> Self {
+        let (inner, alloc) = Rc::into_inner_with_allocator(Rc::<T, A, C>::new_with(value, alloc));
+        // the weak shared by the Rcs stays, it's what keeps the allocation
+        // alive until into_rc or drop
+        unsafe { RcInner::strong(inner.as_ptr()).set(C::ZERO) };
+        UniqueRc {
+            inner,
+            _marker: PhantomData,
+            _invariant: PhantomData,
+            alloc,
+        }
+    }
+
+    /// a Weak that won't upgrade until this is turned into an Rc
+    pub fn downgrade(this: &Self) -> Weak<T, A, C>
+    where
+        A: Clone,
+    {
+        let weak = unsafe { RcInner::weak(this.inner.as_ptr()) };
+        weak.set(weak.get().increment());
+        Weak {
+            inner: this.inner,
+            alloc: this.alloc.clone(),
+        }
+    }
+
+    /// share the value, after which the Weaks from downgrade upgrade too
+    pub fn into_rc(this: Self) -> Rc<T, A, C> {
+        let this = ManuallyDrop::new(this);
+        unsafe {
+            RcInner::strong(this.inner.as_ptr()).set(C::ONE);
+            // this is never used or dropped again, so moving alloc out is fine
+            Rc::from_inner_in(this.inner, ptr::read(&this.alloc))
+        }
+    }
+}
+
+impl<T, A: Allocator, C: Count> Deref for UniqueRc<T, A, C> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &(*self.inner.as_ptr()).value }
+    }
+}
+
+// no Rc exists yet and Weaks can't upgrade, so nothing else can be looking
+// at the value
+impl<T, A: Allocator, C: Count> DerefMut for UniqueRc<T, A, C> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut (*self.inner.as_ptr()).value }
+    }
+}
+
+impl<T, A: Allocator, C: Count> Drop for UniqueRc<T, A, C> {
+    fn drop(&mut self) {
+        // the same as the last Rc going away: strong is already 0, so the
+        // value's drop can't upgrade a Weak to itself either
+        unsafe {
+            ptr::drop_in_place(ptr::addr_of_mut!((*self.inner.as_ptr()).value));
+            release_weak(self.inner, &self.alloc);
+        }
+    }
+}
+
+impl<T: fmt::Debug, A: Allocator, C: Count> fmt::Debug for UniqueRc<T, A, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    struct Node<'a> {
+        name: &'static str,
+        prev: Weak<Node<'a>>,
+        next: Weak<Node<'a>>,
+        drops: &'a Cell<usize>,
+    }
+
+    impl Drop for Node<'_> {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+        }
+    }
+
+    fn node<'a>(name: &'static str, drops: &'a Cell<usize>) -> UniqueRc<Node<'a>> {
+        UniqueRc::new(Node {
+            name,
+            prev: Weak::new(),
+            next: Weak::new(),
+            drops,
+        })
+    }
+
+    #[test]
+    fn doubly_linked_pair() {
+        let drops = Cell::new(0);
+        let mut a = node("a", &drops);
+        let mut b = node("b", &drops);
+        a.next = UniqueRc::downgrade(&b);
+        b.prev = UniqueRc::downgrade(&a);
+        b.name = "b2";
+
+        // neither has been shared yet
+        assert!(a.next.upgrade().is_none());
+        assert!(b.prev.upgrade().is_none());
+        assert_eq!(a.next.strong_count(), 0);
+
+        let a = UniqueRc::into_rc(a);
+        let b = UniqueRc::into_rc(b);
+        assert_eq!(a.next.upgrade().unwrap().name, "b2");
+        assert!(Rc::ptr_eq(&b.prev.upgrade().unwrap(), &a));
+        assert_eq!(Rc::strong_count(&a), 1);
+        assert_eq!(Rc::weak_count(&a), 1);
+
+        drop(a);
+        assert_eq!(drops.get(), 1);
+        assert!(b.prev.upgrade().is_none());
+        drop(b);
+        assert_eq!(drops.get(), 2);
+    }
+
+    #[test]
+    fn dropped_without_sharing() {
+        let drops = Cell::new(0);
+        let a = node("a", &drops);
+        let weak = UniqueRc::downgrade(&a);
+        drop(a);
+        assert_eq!(drops.get(), 1);
+        assert!(weak.upgrade().is_none());
+        drop(weak);
+
+        let a = node("a", &drops);
+        assert_eq!(format!("{:?}", UniqueRc::new(5)), "5");
+        let rc = UniqueRc::into_rc(a);
+        assert_eq!(Rc::weak_count(&rc), 0);
+        assert!(Rc::try_unwrap(rc).is_ok());
+        assert_eq!(drops.get(), 2);
+    }
+}
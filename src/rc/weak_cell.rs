@@ -0,0 +1,126 @@
+// WeakCell remembers an Rc without keeping it alive, for caches of the
+// "reuse the shared instance while anyone still has it, otherwise build a
+// new one" kind
+//
+// no borrow of the cell is held while the closure given to
+// get_or_create_with runs, so the closure may use the cell too; whatever it
+// stores there is replaced by the Rc the closure returns
+use super::{Rc, Weak};
+use crate::refcell::RefCell;
+use std::fmt;
+
+pub struct WeakCell<T> {
+    weak: RefCell<Weak<T>>,
+}
+
+impl<T> WeakCell<T> {
+    /// an empty WeakCell
+    pub fn new() -> Self {
+        WeakCell {
+            weak: RefCell::new(Weak::new()),
+        }
+    }
+
+    /// the remembered Rc, if it's still alive
+    pub fn get(&self) -> Option<Rc<T>> {
+        self.weak
+            .borrow()
+            .expect("WeakCell is never borrowed across calls")
+            .upgrade()
+    }
+
+    /// remember rc, forgetting whatever was there before
+    pub fn set(&self, rc: &Rc<T>) {
+        *self
+            .weak
+            .borrow_mut()
+            .expect("WeakCell is never borrowed across calls") = Rc::downgrade(rc);
+    }
+
+    /// the remembered Rc if it's still alive, otherwise remember and return
+    /// a new one from f
+    pub fn get_or_create_with(&self, f: impl FnOnce() -> Rc<T>) -> Rc<T> {
+        if let Some(rc) = self.get() {
+            return rc;
+        }
+        let rc = f();
+        self.set(&rc);
+        rc
+    }
+}
+
+impl<T> Default for WeakCell<T> {
+    fn default() -> Self {
+        WeakCell::new()
+    }
+}
+
+impl<T> fmt::Debug for WeakCell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("(WeakCell)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn created_once_while_alive() {
+        let cell = WeakCell::new();
+        let created = Cell::new(0);
+        let make = || {
+            created.set(created.get() + 1);
+            Rc::new(String::from("config"))
+        };
+
+        let a = cell.get_or_create_with(make);
+        let b = cell.get_or_create_with(make);
+        assert!(Rc::ptr_eq(&a, &b));
+        assert_eq!(created.get(), 1);
+        // the cell itself holds no strong reference
+        assert_eq!(Rc::strong_count(&a), 2);
+
+        drop(a);
+        drop(b);
+        assert!(cell.get().is_none());
+        let c = cell.get_or_create_with(make);
+        assert_eq!(created.get(), 2);
+        assert!(Rc::ptr_eq(&c, &cell.get().unwrap()));
+    }
+
+    #[test]
+    fn set_replaces() {
+        let cell = WeakCell::default();
+        assert!(cell.get().is_none());
+        let a = Rc::new(1);
+        cell.set(&a);
+        let b = Rc::new(2);
+        cell.set(&b);
+        assert_eq!(*cell.get().unwrap(), 2);
+        drop(b);
+        assert!(cell.get().is_none());
+        assert_eq!(*cell.get_or_create_with(|| a.clone()), 1);
+    }
+
+    #[test]
+    fn reentrant() {
+        let cell = WeakCell::new();
+        let inner = Rc::new(0);
+        let outer = cell.get_or_create_with(|| {
+            // still empty, and free to be used from in here
+            assert!(cell.get().is_none());
+            cell.set(&inner);
+            assert!(cell.get().is_some());
+            Rc::new(1)
+        });
+        // the closure's result wins
+        assert!(Rc::ptr_eq(&cell.get().unwrap(), &outer));
+
+        let nested = WeakCell::new();
+        let value = nested.get_or_create_with(|| nested.get_or_create_with(|| Rc::new(5)));
+        assert_eq!(*value, 5);
+        assert!(Rc::ptr_eq(&nested.get().unwrap(), &value));
+    }
+}
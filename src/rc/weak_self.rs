@@ -0,0 +1,192 @@
+// WeakSelf is a field for values that need a Weak to their own Rc, e.g. to
+// register themselves as a listener from inside a method
+//
+// the Rc doesn't exist yet while the value is being built, so the field
+// starts out empty and is filled in once afterwards, usually through
+// Rc::init_weak_self for types that say where their WeakSelf is via
+// HasWeakSelf
+use super::{Rc, Weak};
+use crate::refcell::RefCell;
+use std::fmt;
+
+pub struct WeakSelf<T> {
+    weak: RefCell<Option<Weak<T>>>,
+}
+
+impl<T> WeakSelf<T> {
+    /// an empty WeakSelf, to be filled in with init
+    pub fn new() -> Self {
+        WeakSelf {
+            weak: RefCell::new(None),
+        }
+    }
+
+    /// sets the Weak, only the first time; after that weak is handed back
+    pub fn init(&self, weak: Weak<T>) -> Result<(), Weak<T>> {
+        let mut slot = self
+            .weak
+            .borrow_mut()
+            .expect("WeakSelf is never borrowed across calls");
+        if slot.is_some() {
+            return Err(weak);
+        }
+        *slot = Some(weak);
+        Ok(())
+    }
+
+    /// the Weak to the value's own Rc
+    /// before init this is a Weak that never upgrades
+    pub fn get(&self) -> Weak<T> {
+        self.weak
+            .borrow()
+            .expect("WeakSelf is never borrowed across calls")
+            .clone()
+            .unwrap_or_default()
+    }
+
+    /// the value's own Rc, unless it's being dropped (or init wasn't called)
+    pub fn upgrade(&self) -> Option<Rc<T>> {
+        self.get().upgrade()
+    }
+}
+
+impl<T> Default for WeakSelf<T> {
+    fn default() -> Self {
+        WeakSelf::new()
+    }
+}
+
+impl<T> fmt::Debug for WeakSelf<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("(WeakSelf)")
+    }
+}
+
+/// a type with a WeakSelf field, so Rc can fill it in
+pub trait HasWeakSelf: Sized {
+    fn weak_self(&self) -> &WeakSelf<Self>;
+}
+
+impl<T: HasWeakSelf> Rc<T> {
+    /// Rc::new, then init_weak_self
+    #[cfg_attr(feature = "leak-track", track_caller)]
+    pub fn new_with_weak_self(value: T) -> Self {
+        let rc = Rc::new(value);
+        Rc::init_weak_self(&rc);
+        rc
+    }
+
+    /// point the value's WeakSelf at this Rc
+    ///
+    /// panics if it was already initialized
+    pub fn init_weak_self(this: &Self) {
+        if this.weak_self().init(Rc::downgrade(this)).is_err() {
+            panic!("WeakSelf already initialized");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    // something that calls back whoever registered with it
+    struct Button<'a> {
+        listeners: RefCell<Vec<Weak<Label<'a>>>>,
+    }
+
+    impl Button<'_> {
+        fn new() -> Self {
+            Button {
+                listeners: RefCell::new(Vec::new()),
+            }
+        }
+
+        // tells every listener that's still around, returns how many
+        fn click(&self) -> usize {
+            let listeners = self.listeners.borrow().unwrap();
+            let live: Vec<_> = listeners.iter().filter_map(Weak::upgrade).collect();
+            for label in &live {
+                label.clicks.set(label.clicks.get() + 1);
+            }
+            live.len()
+        }
+    }
+
+    struct Label<'a> {
+        me: WeakSelf<Label<'a>>,
+        clicks: Cell<usize>,
+        drops: &'a Cell<usize>,
+    }
+
+    impl<'a> Label<'a> {
+        fn listen(&self, button: &Button<'a>) {
+            button.listeners.borrow_mut().unwrap().push(self.me.get());
+        }
+    }
+
+    impl<'a> HasWeakSelf for Label<'a> {
+        fn weak_self(&self) -> &WeakSelf<Self> {
+            &self.me
+        }
+    }
+
+    impl Drop for Label<'_> {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+        }
+    }
+
+    fn label(drops: &Cell<usize>) -> Label<'_> {
+        Label {
+            me: WeakSelf::new(),
+            clicks: Cell::new(0),
+            drops,
+        }
+    }
+
+    #[test]
+    fn registers_itself_without_staying_alive() {
+        let drops = Cell::new(0);
+        let button = Button::new();
+        let a = Rc::new_with_weak_self(label(&drops));
+        let b = Rc::new(label(&drops));
+        Rc::init_weak_self(&b);
+        a.listen(&button);
+        b.listen(&button);
+
+        assert_eq!(button.click(), 2);
+        assert_eq!(a.clicks.get(), 1);
+        assert!(Rc::ptr_eq(&a.me.upgrade().unwrap(), &a));
+
+        // the button's registration doesn't keep b alive
+        drop(b);
+        assert_eq!(drops.get(), 1);
+        assert_eq!(button.click(), 1);
+        assert_eq!(a.clicks.get(), 2);
+        drop(a);
+        assert_eq!(drops.get(), 2);
+        assert_eq!(button.click(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "WeakSelf already initialized")]
+    fn double_init_panics() {
+        let drops = Cell::new(0);
+        let a = Rc::new_with_weak_self(label(&drops));
+        Rc::init_weak_self(&a);
+    }
+
+    #[test]
+    fn init_only_once() {
+        let drops = Cell::new(0);
+        let a = Rc::new(label(&drops));
+        assert!(a.me.upgrade().is_none());
+        assert!(a.me.init(Rc::downgrade(&a)).is_ok());
+        let other = Rc::new(label(&drops));
+        let rejected = a.me.init(Rc::downgrade(&other)).unwrap_err();
+        assert!(rejected.ptr_eq(&Rc::downgrade(&other)));
+        assert!(Rc::ptr_eq(&a.me.upgrade().unwrap(), &a));
+    }
+}
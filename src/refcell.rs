@@ -82,6 +82,11 @@ impl<T> RefCell<T> {
         }
     }
 
+    /// no borrows can be alive since we're consuming the RefCell
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
     /// return Some(&value) if no exclusive reference (mutable) was given out
     pub fn borrow(&self) -> Option<Ref<'_, T>> {
         // no exclusive references given out since state would be exclusive
@@ -0,0 +1,157 @@
+// serde support, behind the serde feature
+//
+// an Rc serializes as whatever it points to, so the output looks exactly
+// like it would for a plain T
+//
+// deserializing always makes a fresh allocation for each Rc
+// shared structure is NOT preserved: two clones of one Rc come back as two
+// separate Rcs with equal values (same caveat as serde's own rc feature)
+//
+// a Weak serializes as an Option: the value if it can still be upgraded,
+// none if it's already gone
+// deserializing one always gives a Weak::new that never upgrades, since
+// nothing would be left holding on to a freshly made value anyway
+// the data is still read (and checked) so the rest of the input lines up
+use crate::alloc::Allocator;
+use crate::rc::{Count, Rc, Weak};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+impl<T: ?Sized + Serialize, A: Allocator, C: Count> Serialize for Rc<T, A, C> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (**self).serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Rc<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Rc::new)
+    }
+}
+
+impl<'de> Deserialize<'de> for Rc<str> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Rc::from)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Rc<[T]> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::<T>::deserialize(deserializer).map(Rc::from)
+    }
+}
+
+impl<T, A, C> Serialize for Weak<T, A, C>
+where
+    T: ?Sized + Serialize,
+    A: Allocator + Clone,
+    C: Count,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.upgrade().serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Weak<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Option::<T>::deserialize(deserializer).map(|_| Weak::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct Config {
+        name: Rc<str>,
+        tags: Rc<[Rc<str>]>,
+        server: Rc<Server>,
+        backup: Rc<Server>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Server {
+        host: Rc<str>,
+        port: u16,
+    }
+
+    fn config() -> Config {
+        let server = Rc::new(Server {
+            host: Rc::from("localhost"),
+            port: 8080,
+        });
+        Config {
+            name: Rc::from("prod"),
+            tags: Rc::from(vec![Rc::from("a"), Rc::from("b")]),
+            backup: server.clone(),
+            server,
+        }
+    }
+
+    const JSON: &str = r#"{"name":"prod","tags":["a","b"],"server":{"host":"localhost","port":8080},"backup":{"host":"localhost","port":8080}}"#;
+
+    #[test]
+    fn serialize_like_the_pointee() {
+        assert_eq!(serde_json::to_string(&config()).unwrap(), JSON);
+        assert_eq!(serde_json::to_string(&Rc::new(5)).unwrap(), "5");
+    }
+
+    #[test]
+    fn round_trip() {
+        let config: Config = serde_json::from_str(JSON).unwrap();
+        assert_eq!(&*config.name, "prod");
+        assert_eq!(config.tags.len(), 2);
+        assert_eq!(&*config.tags[1], "b");
+        assert_eq!(config.server.port, 8080);
+        assert_eq!(&*config.backup.host, "localhost");
+        assert_eq!(serde_json::to_string(&config).unwrap(), JSON);
+    }
+
+    #[test]
+    fn sharing_is_not_preserved() {
+        let before = config();
+        assert!(Rc::ptr_eq(&before.server, &before.backup));
+
+        let json = serde_json::to_string(&before).unwrap();
+        let after: Config = serde_json::from_str(&json).unwrap();
+        // equal values, but two separate allocations
+        assert_eq!(*after.server.host, *after.backup.host);
+        assert!(!Rc::ptr_eq(&after.server, &after.backup));
+        assert_eq!(Rc::strong_count(&after.server), 1);
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Child {
+        name: Rc<str>,
+        parent: Weak<Server>,
+    }
+
+    #[test]
+    fn weak() {
+        let parent = Rc::new(Server {
+            host: Rc::from("localhost"),
+            port: 80,
+        });
+        let child = Child {
+            name: Rc::from("c"),
+            parent: Rc::downgrade(&parent),
+        };
+        let live = serde_json::to_string(&child).unwrap();
+        assert_eq!(
+            live,
+            r#"{"name":"c","parent":{"host":"localhost","port":80}}"#
+        );
+        drop(parent);
+        let dead = serde_json::to_string(&child).unwrap();
+        assert_eq!(dead, r#"{"name":"c","parent":null}"#);
+        assert_eq!(serde_json::to_string(&Weak::<u8>::new()).unwrap(), "null");
+
+        for json in [live, dead].iter() {
+            let child: Child = serde_json::from_str(json).unwrap();
+            assert_eq!(&*child.name, "c");
+            assert!(child.parent.upgrade().is_none());
+        }
+        // the value is still checked even though it's thrown away
+        assert!(serde_json::from_str::<Weak<u8>>("\"x\"").is_err());
+    }
+}
@@ -0,0 +1,199 @@
+// Shared<T> is the Rc<RefCell<T>> that every graph or tree ends up using,
+// wrapped up so it's less of a mouthful
+//
+// clones are cheap and all point to the same value
+// borrow and borrow_mut panic instead of returning None, since a conflicting
+// borrow through a Shared is almost always a bug
+// the closure accessors with and with_mut make it hard to hold on to a
+// borrow for longer than intended
+use crate::rc::{Rc, Weak};
+use crate::refcell::{Ref, RefCell, RefMut};
+use std::fmt;
+
+pub struct Shared<T>(Rc<RefCell<T>>);
+
+impl<T> Shared<T> {
+    pub fn new(value: T) -> Self {
+        Shared(Rc::new(RefCell::new(value)))
+    }
+
+    /// panics if the value is currently mutably borrowed
+    pub fn borrow(&self) -> Ref<'_, T> {
+        self.0.borrow().expect("already mutably borrowed")
+    }
+
+    /// panics if the value is currently borrowed
+    pub fn borrow_mut(&self) -> RefMut<'_, T> {
+        self.0.borrow_mut().expect("already borrowed")
+    }
+
+    /// run f with a shared borrow of the value
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.borrow())
+    }
+
+    /// run f with an exclusive borrow of the value
+    pub fn with_mut<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.borrow_mut())
+    }
+
+    /// take the value out if this is the last Shared pointing to it
+    pub fn try_unwrap(this: Self) -> Result<T, Self> {
+        Rc::try_unwrap(this.0)
+            .map(RefCell::into_inner)
+            .map_err(Shared)
+    }
+
+    pub fn downgrade(this: &Self) -> WeakShared<T> {
+        WeakShared(Rc::downgrade(&this.0))
+    }
+
+    /// true if both point to the same value
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        Rc::ptr_eq(&this.0, &other.0)
+    }
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Shared(self.0.clone())
+    }
+}
+
+impl<T: Default> Default for Shared<T> {
+    fn default() -> Self {
+        Shared::new(T::default())
+    }
+}
+
+// formatting a value that's mutably borrowed somewhere up the stack must not
+// panic, so print a placeholder instead
+impl<T: fmt::Debug> fmt::Debug for Shared<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.borrow() {
+            Some(value) => f.debug_tuple("Shared").field(&*value).finish(),
+            None => f.write_str("Shared(<borrowed>)"),
+        }
+    }
+}
+
+// the Weak to a Shared, for back edges that shouldn't keep things alive
+pub struct WeakShared<T>(Weak<RefCell<T>>);
+
+impl<T> WeakShared<T> {
+    /// a WeakShared that never upgrades
+    pub fn new() -> Self {
+        WeakShared(Weak::new())
+    }
+
+    pub fn upgrade(&self) -> Option<Shared<T>> {
+        self.0.upgrade().map(Shared)
+    }
+}
+
+impl<T> Clone for WeakShared<T> {
+    fn clone(&self) -> Self {
+        WeakShared(self.0.clone())
+    }
+}
+
+impl<T> Default for WeakShared<T> {
+    fn default() -> Self {
+        WeakShared::new()
+    }
+}
+
+impl<T> fmt::Debug for WeakShared<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("(WeakShared)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Node {
+        value: i32,
+        children: Vec<Shared<Node>>,
+        parent: WeakShared<Node>,
+    }
+
+    fn add_child(parent: &Shared<Node>, value: i32) -> Shared<Node> {
+        let child = Shared::new(Node {
+            value,
+            children: Vec::new(),
+            parent: Shared::downgrade(parent),
+        });
+        parent.borrow_mut().children.push(child.clone());
+        child
+    }
+
+    #[test]
+    fn mutate_through_one_observe_through_another() {
+        let root = Shared::new(Node::default());
+        let a = add_child(&root, 1);
+        let b = add_child(&root, 2);
+        // a diamond: c is a child of both a and b
+        let c = add_child(&a, 3);
+        b.borrow_mut().children.push(c.clone());
+
+        c.with_mut(|c| c.value += 10);
+        let through_b = b.with(|b| b.children[0].borrow().value);
+        assert_eq!(through_b, 13);
+
+        let sum: i32 = root.with(|root| root.children.iter().map(|c| c.borrow().value).sum());
+        assert_eq!(sum, 3);
+        assert!(Shared::ptr_eq(
+            &a.borrow().children[0],
+            &b.borrow().children[0]
+        ));
+        assert!(!Shared::ptr_eq(&a, &b));
+    }
+
+    #[test]
+    fn weak_parent_links() {
+        let root = Shared::new(Node::default());
+        let child = add_child(&root, 1);
+
+        let parent = child.borrow().parent.upgrade().unwrap();
+        assert!(Shared::ptr_eq(&parent, &root));
+        parent.borrow_mut().value = 7;
+        drop(parent);
+        assert_eq!(root.borrow().value, 7);
+
+        // the child's back edge doesn't keep the root alive
+        let weak_root = Shared::downgrade(&root);
+        drop(root);
+        assert!(weak_root.upgrade().is_none());
+        assert!(child.borrow().parent.upgrade().is_none());
+        assert!(WeakShared::<Node>::new().upgrade().is_none());
+    }
+
+    #[test]
+    fn try_unwrap() {
+        let shared = Shared::new(String::from("hello"));
+        let other = shared.clone();
+        let shared = Shared::try_unwrap(shared).unwrap_err();
+        drop(other);
+        assert_eq!(Shared::try_unwrap(shared).unwrap(), "hello");
+    }
+
+    #[test]
+    #[should_panic(expected = "already borrowed")]
+    fn conflicting_borrow_panics() {
+        let shared = Shared::new(1);
+        let _r = shared.borrow();
+        shared.borrow_mut();
+    }
+
+    #[test]
+    fn debug_is_borrow_aware() {
+        let shared = Shared::new(vec![1, 2]);
+        assert_eq!(format!("{:?}", shared), "Shared([1, 2])");
+        let _guard = shared.borrow_mut();
+        assert_eq!(format!("{:?}", shared), "Shared(<borrowed>)");
+        assert_eq!(format!("{:?}", Shared::<u8>::default()), "Shared(0)");
+    }
+}
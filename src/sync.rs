@@ -0,0 +1,21 @@
+// thread safe counterparts to the single threaded types in the rest of the
+// crate
+mod arc;
+mod condvar;
+mod mutex;
+mod once;
+mod once_lock;
+mod poison;
+mod rwlock;
+mod wait_queue;
+
+pub use arc::{Arc, Weak};
+pub use condvar::{Condvar, WaitTimeoutResult};
+pub use mutex::{MappedMutexGuard, Mutex, MutexGuard};
+pub use once::{Once, OnceState};
+pub use once_lock::OnceLock;
+pub use poison::{LockResult, PoisonError, TryLockError, TryLockResult};
+pub use rwlock::{
+    MappedRwLockReadGuard, MappedRwLockWriteGuard, RwLock, RwLockReadGuard,
+    RwLockUpgradableReadGuard, RwLockWriteGuard,
+};
@@ -0,0 +1,2042 @@
+// arc is rc with atomic counts, so clones can be sent to other threads
+// the counting works exactly like rc's, every clone adds one and every drop
+// takes one away, the last one frees the value
+//
+// the hard part is the memory orderings on the count:
+// - clone only needs Relaxed: the new Arc was made from an existing one, so
+//   the count is already at least 1 and can't hit 0 underneath us, and
+//   nothing else is published by the increment
+// - drop decrements with Release, so everything this thread did to the
+//   value happens before the decrement
+// - whoever takes the count to 0 does an Acquire fence before dropping the
+//   value, which syncs with all those Release decrements, so the drop sees
+//   every other thread's uses of the value as finished
+//
+// like rc, the Arcs together hold one extra weak, so the value is dropped
+// when strong hits 0 and the allocation is freed when weak does
+// the weak count follows the same Relaxed up, Release down, Acquire fence
+// before freeing pattern
+//
+// Send + Sync only when T is both: an Arc can be cloned and the clones
+// dropped on any thread, so T may be dropped on another thread (Send), and
+// every clone hands out &T across threads (Sync)
+#[cfg(feature = "nightly")]
+use crate::rc::MarkerEq;
+use crate::rc::ReflexiveEq;
+#[cfg(loom)]
+use loom::sync::atomic::{self, AtomicUsize, Ordering};
+use std::alloc::Layout;
+use std::any::Any;
+use std::borrow::Borrow;
+use std::cmp;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::iter::FromIterator;
+use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop};
+use std::ops::Deref;
+use std::pin::Pin;
+use std::ptr::{self, NonNull};
+#[cfg(not(loom))]
+use std::sync::atomic::{self, AtomicUsize, Ordering};
+
+// a program that leaks this many clones is broken, and going on would
+// overflow the count and free the value while it's in use
+const MAX_COUNT: usize = isize::MAX as usize;
+
+// what get_mut sets the weak count to while it checks for uniqueness
+const LOCKED: usize = usize::MAX;
+
+// repr(C) so the value is the last field, which lets T be unsized and lets
+// the layout of an ArcInner<[T]> be worked out by hand
+#[repr(C)]
+struct ArcInner<T: ?Sized> {
+    strong: AtomicUsize,
+    weak: AtomicUsize,
+    value: T,
+}
+
+impl<T: ?Sized> ArcInner<T> {
+    // the counts on their own, straight from the raw pointer, since a Weak
+    // can't make a &ArcInner once the value has been dropped
+    //
+    // safety: ptr must point to a live allocation that outlives 'a
+    unsafe fn strong<'a>(ptr: *const Self) -> &'a AtomicUsize {
+        &*ptr::addr_of!((*ptr).strong)
+    }
+
+    unsafe fn weak<'a>(ptr: *const Self) -> &'a AtomicUsize {
+        &*ptr::addr_of!((*ptr).weak)
+    }
+}
+
+/// a thread safe reference counted pointer
+pub struct Arc<T: ?Sized> {
+    inner: NonNull<ArcInner<T>>,
+    _marker: PhantomData<ArcInner<T>>,
+}
+
+unsafe impl<T: ?Sized + Send + Sync> Send for Arc<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for Arc<T> {}
+
+impl<T> Arc<T> {
+    pub fn new(value: T) -> Self {
+        let inner = Box::new(ArcInner {
+            strong: AtomicUsize::new(1),
+            weak: AtomicUsize::new(1),
+            value,
+        });
+        Arc {
+            inner: NonNull::from(Box::leak(inner)),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Arc::new, pinned
+    /// the value never moves once it's in the allocation: nothing can get
+    /// it out of a Pin<Arc<T>>, since try_unwrap and friends need the Arc
+    /// itself and Pin only hands out &T
+    pub fn pin(value: T) -> Pin<Self> {
+        unsafe { Pin::new_unchecked(Arc::new(value)) }
+    }
+
+    /// build a value that holds a Weak to its own Arc
+    /// the Weak passed to f can be cloned and stored, even sent to other
+    /// threads, but upgrading it gives None until new_cyclic returns
+    ///
+    /// if f panics the allocation is freed, once any Weaks it cloned are gone
+    pub fn new_cyclic<F: FnOnce(&Weak<T>) -> T>(f: F) -> Self {
+        // strong 0, so upgrades fail while the value isn't there yet, and
+        // the one weak the Arcs will share, held by this Weak until then
+        let uninit = Box::new(ArcInner {
+            strong: AtomicUsize::new(0),
+            weak: AtomicUsize::new(1),
+            value: mem::MaybeUninit::<T>::uninit(),
+        });
+        // MaybeUninit<T> has the same layout as T, so this is the allocation
+        // an ArcInner<T> would have, and dropping the Weak on a panic frees
+        // it without touching the value
+        let weak = Weak {
+            inner: NonNull::from(Box::leak(uninit)).cast::<ArcInner<T>>(),
+        };
+        let value = f(&weak);
+        let inner = weak.inner;
+        // the Weak's weak becomes the Arcs' shared one
+        mem::forget(weak);
+        unsafe {
+            ptr::write(ptr::addr_of_mut!((*inner.as_ptr()).value), value);
+            // Release: a Weak that's already on another thread sees the
+            // write above once its upgrade sees the 1
+            ArcInner::strong(inner.as_ptr()).store(1, Ordering::Release);
+        }
+        Arc {
+            inner,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Arc<T> {
+    // the allocation outlives every Arc pointing to it, and so does the
+    // value
+    fn inner(&self) -> &ArcInner<T> {
+        unsafe { self.inner.as_ref() }
+    }
+
+    /// how many Arcs point to this allocation
+    ///
+    /// other threads can clone and drop Arcs at any moment, so the answer
+    /// may already be out of date when it's returned: fine for tests and
+    /// metrics, but never decide anything about the value based on it
+    pub fn strong_count(this: &Self) -> usize {
+        // Acquire: a count lowered by another thread's drop (a Release
+        // decrement) comes with that thread's uses of the value, so e.g. a
+        // test that sees 1 after joining also sees everything they did
+        this.inner().strong.load(Ordering::Acquire)
+    }
+
+    /// how many Weaks point to this allocation
+    ///
+    /// just as racy as strong_count
+    pub fn weak_count(this: &Self) -> usize {
+        // Acquire: same as strong_count
+        match this.inner().weak.load(Ordering::Acquire) {
+            // get_mut on another Arc has the count locked, which it only
+            // manages when there are no Weaks
+            LOCKED => 0,
+            // while this Arc exists the Arcs' shared weak is still counted,
+            // so this never underflows
+            n => n - 1,
+        }
+    }
+
+    /// make a Weak pointing to the same allocation
+    /// the Weak doesn't keep the value alive, only the allocation
+    pub fn downgrade(this: &Self) -> Weak<T> {
+        let weak = &this.inner().weak;
+        let mut n = weak.load(Ordering::Relaxed);
+        loop {
+            // get_mut is checking for uniqueness, wait for it to finish
+            if n == LOCKED {
+                std::hint::spin_loop();
+                n = weak.load(Ordering::Relaxed);
+                continue;
+            }
+            if n > MAX_COUNT {
+                std::process::abort();
+            }
+            // Acquire: pairs with the Release that unlocks the count in
+            // is_unique, so the new Weak can't be upgraded into an Arc that
+            // sees the value before get_mut's caller is done with it
+            match weak.compare_exchange_weak(n, n + 1, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return Weak { inner: this.inner },
+                Err(actual) => n = actual,
+            }
+        }
+    }
+
+    /// a pointer to the value, which stays valid as long as some Arc does
+    pub fn as_ptr(this: &Self) -> *const T {
+        unsafe { ptr::addr_of!((*this.inner.as_ptr()).value) }
+    }
+
+    /// true if both Arcs point to the same allocation
+    /// only the address is compared, not any vtable
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        this.inner.cast::<()>() == other.inner.cast::<()>()
+    }
+
+    // true if this is the only Arc and there are no Weaks
+    //
+    // checking the two counts one after the other would race: between
+    // reading weak == 1 and strong == 1, another thread could upgrade a
+    // Weak and drop it again, or downgrade and then drop its Arc
+    // so the weak count is locked first, by swapping the Arcs' shared 1 for
+    // LOCKED, which only works if there are no Weaks and stops downgrade
+    // from making new ones
+    // with no Weaks to upgrade, and this Arc being borrowed mutably so it
+    // can't be cloned, the strong count can then only go down, and reading
+    // 1 means it stays 1
+    fn is_unique(this: &mut Self) -> bool {
+        let weak = &this.inner().weak;
+        // Acquire: pairs with the Release in Weak's drop, so anything the
+        // last Weak's upgraded Arcs did is visible to the caller
+        if weak
+            .compare_exchange(1, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return false;
+        }
+        // Acquire: pairs with the Release in Arc's drop, so the other Arcs'
+        // uses of the value happen before the caller's &mut
+        let unique = this.inner().strong.load(Ordering::Acquire) == 1;
+        // Release: pairs with the Acquire in downgrade
+        weak.store(1, Ordering::Release);
+        unique
+    }
+
+    /// a mutable reference to the value, if this is the only Arc and there
+    /// are no Weaks that could make another one
+    pub fn get_mut(this: &mut Self) -> Option<&mut T> {
+        if Arc::is_unique(this) {
+            // nothing else can reach the value while this Arc is borrowed
+            Some(unsafe { &mut (*this.inner.as_ptr()).value })
+        } else {
+            None
+        }
+    }
+}
+
+impl<T> Arc<T> {
+    /// give up this Arc without dropping it, e.g. to hand it to C code as
+    /// an opaque handle
+    /// the strong reference it held now belongs to the pointer, and has to
+    /// be given back with from_raw or the value leaks
+    pub fn into_raw(this: Self) -> *const T {
+        let ptr = Arc::as_ptr(&this);
+        mem::forget(this);
+        ptr
+    }
+
+    /// take back the strong reference given up by into_raw
+    ///
+    /// # Safety
+    ///
+    /// ptr must have come from Arc::into_raw, and each pointer may only be
+    /// turned back into an Arc once
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        // repr(C) puts the value right after the counts, padded out to T's
+        // alignment
+        let (_, offset) = Layout::new::<ArcInner<()>>()
+            .extend(Layout::new::<T>())
+            .expect("the layout of an existing ArcInner");
+        let inner = ptr.byte_sub(offset).cast::<ArcInner<T>>().cast_mut();
+        Arc {
+            inner: NonNull::new_unchecked(inner),
+            _marker: PhantomData,
+        }
+    }
+
+    /// add a strong reference through a pointer from into_raw, like
+    /// cloning the Arc it stands for and calling into_raw on the clone, for
+    /// C code that retains a handle
+    ///
+    /// # Safety
+    ///
+    /// ptr must have come from Arc::into_raw, and a strong reference it
+    /// stands for must still be around
+    pub unsafe fn increment_strong_count(ptr: *const T) {
+        let arc = ManuallyDrop::new(Arc::from_raw(ptr));
+        // Relaxed, like any clone
+        mem::forget(Arc::clone(&arc));
+    }
+
+    /// give up a strong reference through a pointer from into_raw, like
+    /// from_raw followed by a drop, so the last one drops the value and
+    /// frees the allocation if no Weaks are left
+    ///
+    /// # Safety
+    ///
+    /// ptr must have come from Arc::into_raw, and the strong reference
+    /// given up must be one that was given up to the pointer, by into_raw
+    /// or increment_strong_count
+    pub unsafe fn decrement_strong_count(ptr: *const T) {
+        drop(Arc::from_raw(ptr));
+    }
+
+    /// move the value out if this is the only Arc, otherwise hand the Arc
+    /// back
+    /// any Weaks stop upgrading once the value has been taken
+    ///
+    /// two threads calling this on the last two Arcs can both get their Arc
+    /// back, see into_inner for when that matters
+    pub fn try_unwrap(this: Self) -> Result<T, Self> {
+        // Relaxed, with the Acquire fence in take_value: only the thread
+        // that wins the swap to 0 needs to sync with the other Arcs' drops
+        if this
+            .inner()
+            .strong
+            .compare_exchange(1, 0, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(this);
+        }
+        let this = ManuallyDrop::new(this);
+        Ok(unsafe { Arc::take_value(this.inner) })
+    }
+
+    /// move the value out if this is the last Arc, otherwise just drop it
+    ///
+    /// unlike try_unwrap, when every thread calls this on its own Arc
+    /// exactly one of them gets the value: whichever decrement takes the
+    /// count to 0, which is the same one that would have dropped it
+    pub fn into_inner(this: Self) -> Option<T> {
+        let this = ManuallyDrop::new(this);
+        // Release, same as drop: this is a drop that keeps the value
+        if this.inner().strong.fetch_sub(1, Ordering::Release) != 1 {
+            return None;
+        }
+        Some(unsafe { Arc::take_value(this.inner) })
+    }
+
+    /// the value, moved out if this is the only Arc, otherwise cloned
+    ///
+    /// like try_unwrap, several threads doing this at once may all end up
+    /// cloning
+    pub fn unwrap_or_clone(this: Self) -> T
+    where
+        T: Clone,
+    {
+        Arc::try_unwrap(this).unwrap_or_else(|arc| (*arc).clone())
+    }
+
+    // the end of the last Arc's drop, moving the value out instead of
+    // dropping it
+    //
+    // safety: the caller just took strong to 0, and doesn't use inner again
+    unsafe fn take_value(inner: NonNull<ArcInner<T>>) -> T {
+        // Acquire: every other Arc's uses happen before the move, like drop
+        atomic::fence(Ordering::Acquire);
+        let value = ptr::read(ptr::addr_of!((*inner.as_ptr()).value));
+        drop(Weak { inner });
+        value
+    }
+
+    /// a mutable reference to the value, cloning it into a new allocation
+    /// first if other Arcs share it
+    ///
+    /// if only Weaks share it, the value is moved into a new allocation
+    /// instead of cloned, and the Weaks stop upgrading, like they would if
+    /// this Arc were dropped
+    pub fn make_mut(this: &mut Self) -> &mut T
+    where
+        T: Clone,
+    {
+        // swap the strong count from 1 to 0 for the duration: with no
+        // Arcs as far as upgrade can tell, nobody else can get at the value
+        // Acquire: pairs with the Release in Arc's drop, like is_unique
+        let strong = &this.inner().strong;
+        if strong
+            .compare_exchange(1, 0, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // other Arcs, so they keep the old value
+            *this = Arc::new((**this).clone());
+        } else if this.inner().weak.load(Ordering::Relaxed) != 1 {
+            // only Weaks, and strong stays 0 so they can't upgrade anymore
+            // the value moves out, which is all the old allocation's last
+            // Arc's drop would have done besides giving up its weak
+            let old = this.inner;
+            let value = unsafe { ptr::read(ptr::addr_of!((*old.as_ptr()).value)) };
+            mem::forget(mem::replace(this, Arc::new(value)));
+            drop(Weak { inner: old });
+        } else {
+            // this was unique after all, put the count back
+            // Release: pairs with the Acquire in upgrade's load
+            strong.store(1, Ordering::Release);
+        }
+        // this Arc is unique now, one way or another
+        unsafe { &mut (*this.inner.as_ptr()).value }
+    }
+}
+
+// lets Arc<T> coerce to Arc<dyn Trait> or Arc<[T]> from Arc<[T; N]>, like
+// Rc, and be a method receiver on trait objects
+#[cfg(feature = "nightly")]
+impl<T, U> std::ops::CoerceUnsized<Arc<U>> for Arc<T>
+where
+    T: ?Sized + std::marker::Unsize<U>,
+    U: ?Sized,
+{
+}
+
+#[cfg(feature = "nightly")]
+impl<T, U> std::ops::DispatchFromDyn<Arc<U>> for Arc<T>
+where
+    T: ?Sized + std::marker::Unsize<U>,
+    U: ?Sized,
+{
+}
+
+impl<T: ?Sized> Clone for Arc<T> {
+    fn clone(&self) -> Self {
+        // Relaxed: see the top of the file
+        let old = self.inner().strong.fetch_add(1, Ordering::Relaxed);
+        if old > MAX_COUNT {
+            std::process::abort();
+        }
+        Arc {
+            inner: self.inner,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for Arc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner().value
+    }
+}
+
+impl<T: Default> Default for Arc<T> {
+    fn default() -> Self {
+        Arc::new(T::default())
+    }
+}
+
+impl<T> From<T> for Arc<T> {
+    fn from(value: T) -> Self {
+        Arc::new(value)
+    }
+}
+
+// the value moves into a new allocation, since the Box's has no room for
+// the counts
+impl<T> From<Box<T>> for Arc<T> {
+    fn from(boxed: Box<T>) -> Self {
+        Arc::new(*boxed)
+    }
+}
+
+// Arc<T> hashes and compares like T, so a map keyed by Arc<T> can be
+// looked up with a &T
+impl<T: ?Sized> Borrow<T> for Arc<T> {
+    fn borrow(&self) -> &T {
+        self
+    }
+}
+
+impl<T: ?Sized> AsRef<T> for Arc<T> {
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+impl Arc<dyn Any + Send + Sync> {
+    /// the concrete value back, if that's what this is
+    pub fn downcast<T: Any + Send + Sync>(self) -> Result<Arc<T>, Self> {
+        if (*self).is::<T>() {
+            // same allocation, just without the vtable
+            let inner = self.inner.cast::<ArcInner<T>>();
+            mem::forget(self);
+            Ok(Arc {
+                inner,
+                _marker: PhantomData,
+            })
+        } else {
+            Err(self)
+        }
+    }
+}
+
+// allocates an ArcInner<[T]> with room for len elements and both counts at
+// 1, leaving the elements for the caller to write
+// the header and elements are one block, so there's no second pointer to
+// follow to get at them
+fn allocate_slice<T>(len: usize) -> NonNull<ArcInner<[T]>> {
+    let layout = slice_layout::<T>(len);
+    // never zero sized, because of the header
+    let mem = unsafe { std::alloc::alloc(layout) };
+    if mem.is_null() {
+        std::alloc::handle_alloc_error(layout);
+    }
+    // the slice length becomes the metadata of the fat pointer
+    let inner = ptr::slice_from_raw_parts_mut(mem as *mut T, len) as *mut ArcInner<[T]>;
+    unsafe {
+        ptr::addr_of_mut!((*inner).strong).write(AtomicUsize::new(1));
+        ptr::addr_of_mut!((*inner).weak).write(AtomicUsize::new(1));
+        NonNull::new_unchecked(inner)
+    }
+}
+
+// the header, then the elements padded out to T's alignment, which is what
+// Layout::for_value reports for the finished ArcInner<[T]>
+fn slice_layout<T>(len: usize) -> Layout {
+    crate::header_slice::layout::<ArcInner<()>, T>(len)
+}
+
+impl<T> Arc<[T]> {
+    // an Arc<[T]> of len elements, element i being f(i), built in place
+    // without a staging Vec
+    fn from_fn<F: FnMut(usize) -> T>(len: usize, f: F) -> Self {
+        // if f panics, fill drops the elements written so far and dropping
+        // the still uninit Arc frees the block
+        let uninit = Arc {
+            inner: allocate_slice::<mem::MaybeUninit<T>>(len),
+            _marker: PhantomData,
+        };
+        crate::header_slice::fill(unsafe { &mut (*uninit.inner.as_ptr()).value }, f);
+        // same length metadata, and MaybeUninit<T> has the same layout as T
+        let uninit = ManuallyDrop::new(uninit);
+        Arc {
+            inner: unsafe { NonNull::new_unchecked(uninit.inner.as_ptr() as *mut ArcInner<[T]>) },
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> From<Vec<T>> for Arc<[T]> {
+    fn from(mut v: Vec<T>) -> Self {
+        let inner = allocate_slice::<T>(v.len());
+        unsafe {
+            // move the elements over, then make the Vec forget about them
+            // so it only frees its buffer
+            let elements = ptr::addr_of_mut!((*inner.as_ptr()).value) as *mut T;
+            ptr::copy_nonoverlapping(v.as_ptr(), elements, v.len());
+            v.set_len(0);
+        }
+        Arc {
+            inner,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Clone> From<&[T]> for Arc<[T]> {
+    fn from(v: &[T]) -> Self {
+        Arc::from_fn(v.len(), |i| v[i].clone())
+    }
+}
+
+// most iterators can't say up front how many items they'll give, so they
+// go through a Vec first
+impl<T> FromIterator<T> for Arc<[T]> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Arc::from(iter.into_iter().collect::<Vec<T>>())
+    }
+}
+
+impl From<&str> for Arc<str> {
+    fn from(s: &str) -> Self {
+        let bytes = Arc::<[u8]>::from(s.as_bytes());
+        let bytes = ManuallyDrop::new(bytes);
+        // str is just [u8] known to be utf-8, same layout and length metadata
+        Arc {
+            inner: unsafe { NonNull::new_unchecked(bytes.inner.as_ptr() as *mut ArcInner<str>) },
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl From<String> for Arc<str> {
+    fn from(s: String) -> Self {
+        Arc::from(&s[..])
+    }
+}
+
+// formatting an Arc formats the value, except {:p}, which shows where the
+// value lives, the same for every clone
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Arc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for Arc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized> fmt::Pointer for Arc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Pointer::fmt(&(&**self as *const T), f)
+    }
+}
+
+// comparisons and hashing look through the pointer, like they do for &T
+// and Box<T>, so an Arc<str> works as a map key
+//
+// equality takes the same shortcut as Rc's: two Arcs to one allocation are
+// equal without comparing, but only if T: Eq, and only on nightly, see
+// RcEqIdent
+impl<T: ?Sized + PartialEq> PartialEq for Arc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        ArcEqIdent::eq(self, other)
+    }
+
+    #[allow(clippy::partialeq_ne_impl)]
+    fn ne(&self, other: &Self) -> bool {
+        ArcEqIdent::ne(self, other)
+    }
+}
+
+impl<T: ?Sized + Eq> Eq for Arc<T> {}
+
+unsafe impl<T: ?Sized + ReflexiveEq> ReflexiveEq for Arc<T> {}
+
+impl<T: ?Sized + ReflexiveEq> Arc<T> {
+    /// this == other, but true straight away when both point to the same
+    /// allocation, like Rc::ptr_eq_or_eq
+    pub fn ptr_eq_or_eq(this: &Self, other: &Self) -> bool {
+        Arc::ptr_eq(this, other) || **this == **other
+    }
+}
+
+// arc == value, without having to write *arc == value
+impl<T: ?Sized + PartialEq> PartialEq<T> for Arc<T> {
+    fn eq(&self, other: &T) -> bool {
+        **self == *other
+    }
+}
+
+trait ArcEqIdent<T: ?Sized + PartialEq> {
+    fn eq(&self, other: &Arc<T>) -> bool;
+    fn ne(&self, other: &Arc<T>) -> bool;
+}
+
+#[cfg(not(feature = "nightly"))]
+impl<T: ?Sized + PartialEq> ArcEqIdent<T> for Arc<T> {
+    fn eq(&self, other: &Arc<T>) -> bool {
+        **self == **other
+    }
+
+    fn ne(&self, other: &Arc<T>) -> bool {
+        **self != **other
+    }
+}
+
+#[cfg(feature = "nightly")]
+impl<T: ?Sized + PartialEq> ArcEqIdent<T> for Arc<T> {
+    default fn eq(&self, other: &Arc<T>) -> bool {
+        **self == **other
+    }
+
+    default fn ne(&self, other: &Arc<T>) -> bool {
+        **self != **other
+    }
+}
+
+#[cfg(feature = "nightly")]
+impl<T: ?Sized + MarkerEq> ArcEqIdent<T> for Arc<T> {
+    fn eq(&self, other: &Arc<T>) -> bool {
+        Arc::ptr_eq(self, other) || **self == **other
+    }
+
+    fn ne(&self, other: &Arc<T>) -> bool {
+        !Arc::ptr_eq(self, other) && **self != **other
+    }
+}
+
+// no identity shortcut for ordering: Ord would allow it, but it's rare to
+// compare an Arc with its own clone
+impl<T: ?Sized + PartialOrd> PartialOrd for Arc<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        (**self).partial_cmp(&**other)
+    }
+
+    fn lt(&self, other: &Self) -> bool {
+        **self < **other
+    }
+
+    fn le(&self, other: &Self) -> bool {
+        **self <= **other
+    }
+
+    fn gt(&self, other: &Self) -> bool {
+        **self > **other
+    }
+
+    fn ge(&self, other: &Self) -> bool {
+        **self >= **other
+    }
+}
+
+impl<T: ?Sized + Ord> Ord for Arc<T> {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+// hashes the same as the T itself, which Borrow<T> relies on
+impl<T: ?Sized + Hash> Hash for Arc<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl<T: ?Sized> Drop for Arc<T> {
+    fn drop(&mut self) {
+        // Release: our uses of the value happen before the decrement
+        if self.inner().strong.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+        // Acquire: every other Arc's uses happen before the drop below
+        atomic::fence(Ordering::Acquire);
+        // no Arcs are left and upgrades fail from now on, so nobody else
+        // can reach the value
+        unsafe { ptr::drop_in_place(ptr::addr_of_mut!((*self.inner.as_ptr()).value)) };
+        // give up the weak shared by the Arcs, freeing the allocation if no
+        // Weaks are left
+        drop(Weak { inner: self.inner });
+    }
+}
+
+// a pointer to an Arc's allocation that doesn't keep the value alive
+// upgrade gives an Arc back as long as some other Arc still exists
+//
+// orderings, next to the Arc ones at the top of the file:
+// - downgrade and clone increment the weak count Relaxed, except that
+//   downgrade has to wait out get_mut's lock, see is_unique
+// - upgrade increments the strong count with a compare-exchange that never
+//   starts from 0, so a value that's being dropped stays dropped; it
+//   succeeds with Acquire since the Weak may be one new_cyclic handed out
+//   before the value was written, see there
+// - drop decrements the weak count Release, and whichever drop takes it to
+//   0 (a Weak's, or the last Arc's giving up their shared weak) fences
+//   Acquire before freeing, so the value's drop is done by then
+/// a pointer to an Arc's allocation that doesn't keep the value alive
+pub struct Weak<T: ?Sized> {
+    // usize::MAX if this Weak came from Weak::new and has no allocation
+    // no real allocation can live there since ArcInner is at least
+    // 8-aligned
+    inner: NonNull<ArcInner<T>>,
+}
+
+// a Weak can become an Arc on any thread, so it needs the same bounds
+unsafe impl<T: ?Sized + Send + Sync> Send for Weak<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for Weak<T> {}
+
+impl<T> Weak<T> {
+    /// a Weak that doesn't point to anything, so upgrade always gives None
+    pub fn new() -> Self {
+        Weak {
+            inner: NonNull::new(ptr::without_provenance_mut(usize::MAX))
+                .expect("usize::MAX is not null"),
+        }
+    }
+}
+
+impl<T: ?Sized> Weak<T> {
+    fn is_dangling(&self) -> bool {
+        self.inner.as_ptr() as *mut () as usize == usize::MAX
+    }
+
+    /// true if both Weaks point to the same allocation, or both came from
+    /// Weak::new
+    /// works after the value has been dropped, since the allocation stays
+    /// around while the Weaks do
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        self.inner.cast::<()>() == other.inner.cast::<()>()
+    }
+
+    /// how many Arcs point to the allocation, 0 for a Weak from Weak::new
+    ///
+    /// as racy as Arc::strong_count
+    pub fn strong_count(&self) -> usize {
+        if self.is_dangling() {
+            return 0;
+        }
+        // Acquire: same as Arc::strong_count
+        unsafe { ArcInner::strong(self.inner.as_ptr()) }.load(Ordering::Acquire)
+    }
+
+    /// how many Weaks point to the allocation, 0 once the value is gone
+    ///
+    /// as racy as Arc::weak_count
+    pub fn weak_count(&self) -> usize {
+        if self.strong_count() == 0 {
+            return 0;
+        }
+        // this Weak exists, so get_mut can't have the count locked
+        let weak = unsafe { ArcInner::weak(self.inner.as_ptr()) }.load(Ordering::Acquire);
+        // the Arcs' shared weak, unless the last Arc dropped since the
+        // strong count was read
+        weak.saturating_sub(1).max(1)
+    }
+
+    /// get an Arc to the value back, if it hasn't been dropped yet
+    pub fn upgrade(&self) -> Option<Arc<T>> {
+        if self.is_dangling() {
+            return None;
+        }
+        // the allocation stays around as long as this Weak does
+        let strong = unsafe { ArcInner::strong(self.inner.as_ptr()) };
+        // a plain fetch_add could take the count from 0 back to 1 after the
+        // last Arc has started dropping the value, so only increment if it's
+        // still non-zero, retrying if another thread changed it in between
+        let mut n = strong.load(Ordering::Relaxed);
+        loop {
+            if n == 0 {
+                return None;
+            }
+            if n > MAX_COUNT {
+                std::process::abort();
+            }
+            // a count that's already non-zero is usually just a clone of one
+            // of the Arcs that's still around, but Acquire on success syncs
+            // with new_cyclic's Release store of the first 1, in case this
+            // Weak got here before the value was written
+            match strong.compare_exchange_weak(n, n + 1, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => {
+                    return Some(Arc {
+                        inner: self.inner,
+                        _marker: PhantomData,
+                    })
+                }
+                Err(actual) => n = actual,
+            }
+        }
+    }
+}
+
+// the value may be gone, or in use on another thread, so it isn't shown
+impl<T: ?Sized> fmt::Debug for Weak<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("(Weak)")
+    }
+}
+
+impl<T> Default for Weak<T> {
+    fn default() -> Self {
+        Weak::new()
+    }
+}
+
+impl<T: ?Sized> Clone for Weak<T> {
+    fn clone(&self) -> Self {
+        if !self.is_dangling() {
+            // Relaxed: same as Arc::downgrade
+            let weak = unsafe { ArcInner::weak(self.inner.as_ptr()) };
+            if weak.fetch_add(1, Ordering::Relaxed) > MAX_COUNT {
+                std::process::abort();
+            }
+        }
+        Weak { inner: self.inner }
+    }
+}
+
+impl<T: ?Sized> Drop for Weak<T> {
+    fn drop(&mut self) {
+        if self.is_dangling() {
+            return;
+        }
+        let weak = unsafe { ArcInner::weak(self.inner.as_ptr()) };
+        // Release and Acquire for the same reasons as Arc's drop: the last
+        // Arc's drop of the value has to finish before the memory goes
+        if weak.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+        atomic::fence(Ordering::Acquire);
+        // the value is already gone, so free the memory without dropping
+        // anything, with the layout it was allocated with (by Box::new, or
+        // allocate_slice)
+        // like Rc, stable can only get the layout of an unsized ArcInner
+        // through a reference, even though the value has been dropped
+        unsafe {
+            #[cfg(feature = "nightly")]
+            let layout = Layout::for_value_raw(self.inner.as_ptr());
+            #[cfg(not(feature = "nightly"))]
+            let layout = Layout::for_value(self.inner.as_ref());
+            std::alloc::dealloc(self.inner.as_ptr() as *mut u8, layout)
+        };
+    }
+}
+
+// the loom models are in tests/loom_arc.rs, these use std's threads
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    struct DropCount<'a>(&'a AtomicUsize);
+
+    impl Drop for DropCount<'_> {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn clone_and_drop() {
+        let drops = AtomicUsize::new(0);
+        let a = Arc::new(DropCount(&drops));
+        let b = a.clone();
+        assert_eq!(Arc::strong_count(&a), 2);
+        drop(a);
+        assert_eq!(Arc::strong_count(&b), 1);
+        assert_eq!(drops.load(Ordering::Relaxed), 0);
+        drop(b);
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let threads = if cfg!(miri) { 4 } else { 16 };
+        let arc = Arc::new((AtomicUsize::new(0), DropCount(&DROPS)));
+
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                let arc = arc.clone();
+                thread::spawn(move || {
+                    arc.0.fetch_add(1, Ordering::Relaxed);
+                    // more clones, dropped on this thread
+                    let extra = arc.clone();
+                    extra.0.fetch_add(1, Ordering::Relaxed);
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(arc.0.load(Ordering::Relaxed), threads * 2);
+        assert_eq!(Arc::strong_count(&arc), 1);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 0);
+        drop(arc);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn formatting() {
+        #[derive(Debug)]
+        struct Shared {
+            name: Arc<String>,
+            parent: Weak<String>,
+        }
+
+        let name = Arc::new(String::from("root"));
+        assert_eq!(format!("{}", name), "root");
+        assert_eq!(format!("{:?}", name), "\"root\"");
+        assert_eq!(format!("{:>6}", Arc::new(42)), "    42");
+        let shared = Shared {
+            name: name.clone(),
+            parent: Arc::downgrade(&name),
+        };
+        assert_eq!(
+            format!("{:?}", shared),
+            r#"Shared { name: "root", parent: (Weak) }"#
+        );
+        assert_eq!(*shared.parent.upgrade().unwrap(), *shared.name);
+
+        // every clone points at the same place, and {:p} shows where
+        let clone = name.clone();
+        assert_eq!(format!("{:p}", name), format!("{:p}", clone));
+        assert_eq!(
+            format!("{:p}", name),
+            format!("{:p}", &*name as *const String)
+        );
+        assert_ne!(
+            format!("{:p}", name),
+            format!("{:p}", Arc::new(String::new()))
+        );
+    }
+
+    #[test]
+    fn comparisons() {
+        let a = Arc::new(String::from("a"));
+        assert!(a == a.clone());
+        assert!(a == Arc::new(String::from("a")));
+        assert!(a != Arc::new(String::from("b")));
+        assert!(a == String::from("a"));
+
+        assert!(a < Arc::new(String::from("b")));
+        assert_eq!(a.cmp(&Arc::new(String::from("0"))), cmp::Ordering::Greater);
+        let mut sorted = vec![Arc::new(3), Arc::new(1), Arc::new(2)];
+        sorted.sort();
+        assert_eq!(sorted, [Arc::new(1), Arc::new(2), Arc::new(3)]);
+    }
+
+    // an Eq type whose comparisons are counted
+    #[derive(Default)]
+    struct CountingEq(AtomicUsize);
+
+    impl PartialEq for CountingEq {
+        fn eq(&self, _: &Self) -> bool {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            true
+        }
+    }
+
+    impl Eq for CountingEq {}
+
+    // its eq always returns true
+    unsafe impl ReflexiveEq for CountingEq {}
+
+    #[test]
+    fn ptr_eq_or_eq_skips_values_for_the_same_allocation() {
+        let a = Arc::new(CountingEq::default());
+        assert!(Arc::ptr_eq_or_eq(&a, &a.clone()));
+        assert_eq!(a.0.load(Ordering::Relaxed), 0);
+        assert!(Arc::ptr_eq_or_eq(&a, &Arc::new(CountingEq::default())));
+        assert_eq!(a.0.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn eq_skips_values_for_the_same_allocation() {
+        let a = Arc::new(CountingEq::default());
+        assert!(a == Arc::new(CountingEq::default()));
+        assert_eq!(a.0.load(Ordering::Relaxed), 1);
+        let b = a.clone();
+        assert!(a == b);
+        // only nightly can tell T: Eq apart, see ArcEqIdent
+        let expected = if cfg!(feature = "nightly") { 1 } else { 2 };
+        assert_eq!(a.0.load(Ordering::Relaxed), expected);
+    }
+
+    #[test]
+    fn nan_is_not_equal_to_itself() {
+        let nan = Arc::new(f64::NAN);
+        let clone = nan.clone();
+        // f64 isn't Eq, so even the same allocation is compared by value
+        assert!(nan != clone);
+        assert_eq!(nan.partial_cmp(&clone), None);
+        assert_eq!((nan < clone, nan >= clone), (false, false));
+        assert_eq!(
+            Arc::new(1.0).partial_cmp(&Arc::new(2.0)),
+            Some(cmp::Ordering::Less)
+        );
+    }
+
+    #[test]
+    fn hash_like_the_value() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::collections::HashMap;
+
+        fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            value.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        let key = Arc::new(String::from("key"));
+        assert_eq!(hash(&key), hash(&String::from("key")));
+        assert_eq!(hash(&key), hash(&key.clone()));
+
+        let mut map = HashMap::new();
+        map.insert(key.clone(), 1);
+        // a separate allocation with an equal value finds the same entry
+        assert_eq!(map.get(&Arc::new(String::from("key"))), Some(&1));
+        map.insert(Arc::new(String::from("key")), 2);
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn default_and_from() {
+        #[derive(Default)]
+        struct Config {
+            name: Arc<String>,
+            retries: Arc<u32>,
+        }
+
+        let config = Config::default();
+        assert_eq!((config.name.as_str(), *config.retries), ("", 0));
+
+        let from_value: Arc<u32> = 5.into();
+        let from_box: Arc<Vec<u8>> = Box::new(vec![1, 2]).into();
+        assert_eq!(*from_value, 5);
+        assert_eq!(*from_box, [1, 2]);
+    }
+
+    #[test]
+    fn borrow_and_as_ref() {
+        use std::collections::HashMap;
+
+        let mut map = HashMap::new();
+        map.insert(Arc::new(String::from("key")), 1);
+        // looked up with a plain &String, no Arc needed
+        assert_eq!(map.get(&String::from("key")), Some(&1));
+
+        fn len(s: impl AsRef<String>) -> usize {
+            s.as_ref().len()
+        }
+        assert_eq!(len(Arc::new(String::from("four"))), 4);
+    }
+
+    #[test]
+    fn slices() {
+        let from_vec: Arc<[String]> = Arc::from(vec![String::from("a"), String::from("b")]);
+        assert_eq!(from_vec.len(), 2);
+        assert_eq!(from_vec[1], "b");
+        let from_slice: Arc<[u64]> = Arc::from(&[1, 2, 3][..]);
+        assert_eq!(*from_slice, [1, 2, 3]);
+        let collected: Arc<[usize]> = (0..5).map(|i| i * i).collect();
+        assert_eq!(*collected, [0, 1, 4, 9, 16]);
+        assert_eq!(*Arc::<[u8]>::from_fn(3, |i| i as u8), [0, 1, 2]);
+
+        let empty: Arc<[String]> = Arc::from(Vec::new());
+        assert!(empty.is_empty());
+        assert!(Arc::<[u8]>::from(&[][..]).is_empty());
+        assert_eq!(std::iter::empty::<u8>().collect::<Arc<[u8]>>().len(), 0);
+
+        let clone = from_vec.clone();
+        drop(from_vec);
+        assert_eq!(Arc::strong_count(&clone), 1);
+        assert_eq!(clone[0], "a");
+    }
+
+    #[test]
+    fn slice_is_one_block() {
+        // e.g. an audio frame, shared read-only between threads
+        let frame: Arc<[f32]> = Arc::from(vec![0.5; 4096]);
+        let header = frame.inner.as_ptr() as *mut u8 as usize;
+        let first = &frame[0] as *const f32 as usize;
+        // the samples start right after the counts, in the same allocation
+        assert_eq!(first - header, 2 * std::mem::size_of::<usize>());
+        assert_eq!(frame.len(), 4096);
+        assert_eq!(
+            Layout::for_value(frame.inner()),
+            slice_layout::<f32>(frame.len())
+        );
+        assert!(frame.iter().all(|&s| s == 0.5));
+    }
+
+    #[test]
+    fn strs() {
+        let a: Arc<str> = Arc::from("hello");
+        let b: Arc<str> = Arc::from(String::from("hello"));
+        assert_eq!(&*a, "hello");
+        assert!(a == b);
+        assert_eq!(format!("{}", a), "hello");
+        assert_eq!(&*Arc::<str>::from(""), "");
+        let weak = Arc::downgrade(&a);
+        drop(a);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn slice_elements_dropped_once() {
+        let drops = AtomicUsize::new(0);
+        let arc: Arc<[DropCount]> = (0..4).map(|_| DropCount(&drops)).collect();
+        let weak = Arc::downgrade(&arc);
+        let clone = arc.clone();
+        drop(arc);
+        assert_eq!(drops.load(Ordering::Relaxed), 0);
+        drop(clone);
+        assert_eq!(drops.load(Ordering::Relaxed), 4);
+        drop(weak);
+        assert_eq!(drops.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn from_fn_panic_drops_what_was_written() {
+        let drops = AtomicUsize::new(0);
+        let result = std::panic::catch_unwind(|| {
+            Arc::<[DropCount]>::from_fn(4, |i| {
+                assert!(i < 2, "no more");
+                DropCount(&drops)
+            })
+        });
+        assert!(result.is_err());
+        assert_eq!(drops.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn slice_read_on_another_thread() {
+        let frame: Arc<[u8]> = (0..=255).collect();
+        let clone = frame.clone();
+        let sum = thread::spawn(move || clone.iter().map(|&b| b as u32).sum::<u32>())
+            .join()
+            .unwrap();
+        assert_eq!(sum, 255 * 256 / 2);
+        assert_eq!(Arc::strong_count(&frame), 1);
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        let arc = Arc::new(String::from("handle"));
+        let weak = Arc::downgrade(&arc);
+        let ptr = Arc::into_raw(arc.clone());
+        assert_eq!(ptr, Arc::as_ptr(&arc));
+        assert_eq!(unsafe { &*ptr }, "handle");
+        assert_eq!(Arc::strong_count(&arc), 2);
+        let back = unsafe { Arc::from_raw(ptr) };
+        assert!(Arc::ptr_eq(&arc, &back));
+        assert_eq!(Arc::weak_count(&back), 1);
+        drop((arc, back));
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn raw_retain_release_across_threads() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        // what a C library holding the handle would do, with the pointer
+        // as an integer so it can cross threads
+        fn retain(ptr: usize) {
+            unsafe { Arc::increment_strong_count(ptr as *const DropCount<'static>) }
+        }
+        fn release(ptr: usize) {
+            unsafe { Arc::decrement_strong_count(ptr as *const DropCount<'static>) }
+        }
+
+        let threads = if cfg!(miri) { 3 } else { 8 };
+        let ptr = Arc::into_raw(Arc::new(DropCount(&DROPS))) as usize;
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                retain(ptr);
+                thread::spawn(move || {
+                    retain(ptr);
+                    release(ptr);
+                    release(ptr);
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(DROPS.load(Ordering::Relaxed), 0);
+        release(ptr);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn raw_release_with_weaks_left() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let arc = Arc::new(DropCount(&DROPS));
+        let weak = Arc::downgrade(&arc);
+        let ptr = Arc::into_raw(arc);
+        unsafe { Arc::increment_strong_count(ptr) };
+        assert_eq!(weak.strong_count(), 2);
+
+        let ptr = ptr as usize;
+        let handle = thread::spawn(move || {
+            let ptr = ptr as *const DropCount<'static>;
+            unsafe { Arc::decrement_strong_count(ptr) };
+            // the last one drops the value, but the Weak keeps the header
+            unsafe { Arc::decrement_strong_count(ptr) };
+        });
+        handle.join().unwrap();
+        assert_eq!(DROPS.load(Ordering::Relaxed), 1);
+        assert!(weak.upgrade().is_none());
+        assert_eq!(weak.strong_count(), 0);
+        // and miri checks this frees it
+        drop(weak);
+    }
+
+    #[test]
+    fn ptr_eq() {
+        let a = Arc::new(5);
+        let b = a.clone();
+        assert!(Arc::ptr_eq(&a, &b));
+        assert!(!Arc::ptr_eq(&a, &Arc::new(5)));
+        let s: Arc<str> = Arc::from("abc");
+        assert!(Arc::ptr_eq(&s, &s.clone()));
+        assert_eq!(unsafe { &*Arc::as_ptr(&s) }, "abc");
+    }
+
+    #[test]
+    fn ptr_eq_as_cache_identity() {
+        // a cache that hands out the same Arc for equal keys, where callers
+        // check identity rather than comparing values
+        let cache = std::sync::Mutex::new(Vec::<Weak<String>>::new());
+        let lookup = |key: &str| -> Arc<String> {
+            let mut entries = cache.lock().unwrap();
+            if let Some(hit) = entries
+                .iter()
+                .filter_map(Weak::upgrade)
+                .find(|a| **a == key)
+            {
+                return hit;
+            }
+            let arc = Arc::new(key.to_string());
+            entries.push(Arc::downgrade(&arc));
+            arc
+        };
+
+        let threads = if cfg!(miri) { 2 } else { 8 };
+        let first = lookup("shared");
+        let hits: Vec<_> = thread::scope(|s| {
+            let handles: Vec<_> = (0..threads).map(|_| s.spawn(|| lookup("shared"))).collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert!(hits.iter().all(|hit| Arc::ptr_eq(hit, &first)));
+        assert!(!Arc::ptr_eq(&first, &lookup("other")));
+
+        let weak = Arc::downgrade(&first);
+        assert!(weak.ptr_eq(&cache.lock().unwrap()[0]));
+        drop((first, hits));
+        // still the same allocation after the value is gone
+        assert!(weak.ptr_eq(&cache.lock().unwrap()[0]));
+        assert!(!weak.ptr_eq(&Weak::new()));
+        assert!(Weak::<String>::new().ptr_eq(&Weak::new()));
+    }
+
+    struct SelfRef {
+        me: Weak<SelfRef>,
+        name: &'static str,
+    }
+
+    #[test]
+    fn new_cyclic_self_reference() {
+        let arc = Arc::new_cyclic(|me| {
+            assert!(me.upgrade().is_none());
+            assert_eq!(me.strong_count(), 0);
+            SelfRef {
+                me: me.clone(),
+                name: "node",
+            }
+        });
+        let again = arc.me.upgrade().unwrap();
+        assert!(Arc::ptr_eq(&arc, &again));
+        assert_eq!(again.name, "node");
+        assert_eq!((Arc::strong_count(&arc), Arc::weak_count(&arc)), (2, 1));
+        drop(again);
+
+        let weak = arc.me.clone();
+        drop(arc);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn new_cyclic_panic_frees() {
+        let escaped = std::sync::Mutex::new(None);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            Arc::<String>::new_cyclic(|me| {
+                *escaped.lock().unwrap() = Some(me.clone());
+                panic!("no value")
+            })
+        }));
+        assert!(result.is_err());
+        // the Weak that got out still points at the allocation, but there's
+        // never a value behind it; miri checks it's freed when that goes
+        let escaped = escaped.into_inner().unwrap().unwrap();
+        assert!(escaped.upgrade().is_none());
+        assert_eq!(escaped.weak_count(), 0);
+        drop(escaped);
+
+        // and without any Weak getting out
+        let result = std::panic::catch_unwind(|| Arc::<String>::new_cyclic(|_| panic!("no value")));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn new_cyclic_upgrade_on_another_thread() {
+        let arc = Arc::new_cyclic(|me| SelfRef {
+            me: me.clone(),
+            name: "sent",
+        });
+        let handle = thread::spawn(move || {
+            let again = arc.me.upgrade().unwrap();
+            assert!(Arc::ptr_eq(&arc, &again));
+            again.name
+        });
+        assert_eq!(handle.join().unwrap(), "sent");
+    }
+
+    #[test]
+    fn new_cyclic_races_upgrade() {
+        // another thread gets the Weak while the value is still being built
+        // and keeps upgrading: every upgrade before new_cyclic publishes the
+        // value has to fail, and the first one that succeeds has to see the
+        // whole value (miri's race detector checks the second half)
+        let rounds = if cfg!(miri) { 5 } else { 200 };
+        for _ in 0..rounds {
+            let misses = std::sync::Arc::new(AtomicUsize::new(0));
+            let (tx, rx) = std::sync::mpsc::channel::<Weak<Vec<u64>>>();
+            let spinner = thread::spawn({
+                let misses = misses.clone();
+                move || {
+                    let weak = rx.recv().unwrap();
+                    loop {
+                        match weak.upgrade() {
+                            Some(arc) => return arc.iter().sum::<u64>(),
+                            None => misses.fetch_add(1, Ordering::Relaxed),
+                        };
+                        thread::yield_now();
+                    }
+                }
+            });
+            let arc = Arc::new_cyclic(|me| {
+                tx.send(me.clone()).unwrap();
+                while misses.load(Ordering::Relaxed) == 0 {
+                    thread::yield_now();
+                }
+                (1..=10).collect()
+            });
+            assert_eq!(spinner.join().unwrap(), 55);
+            assert!(misses.load(Ordering::Relaxed) > 0);
+            assert_eq!((Arc::strong_count(&arc), Arc::weak_count(&arc)), (1, 0));
+        }
+    }
+
+    // what the nightly coercion does, on stable: the raw pointer unsizes to
+    // whatever Arc type is expected
+    macro_rules! unsize {
+        ($arc:expr) => {{
+            let arc = ManuallyDrop::new($arc);
+            Arc {
+                inner: arc.inner,
+                _marker: PhantomData,
+            }
+        }};
+    }
+
+    fn into_any<T: Any + Send + Sync>(arc: Arc<T>) -> Arc<dyn Any + Send + Sync> {
+        unsize!(arc)
+    }
+
+    #[test]
+    fn unsized_payloads() {
+        use std::fmt::Display;
+
+        let shown: Arc<dyn Display + Send + Sync> = unsize!(Arc::new(5u32));
+        let clone = shown.clone();
+        let handle = thread::spawn(move || clone.to_string());
+        assert_eq!(handle.join().unwrap(), "5");
+        assert_eq!(format!("{:>3}", shown), "  5");
+        assert_eq!(Arc::strong_count(&shown), 1);
+
+        let bytes: Arc<[u8]> = unsize!(Arc::new(*b"abc"));
+        assert_eq!(bytes.len(), 3);
+        assert_eq!(&*bytes, b"abc");
+
+        // drops counted through the unsized handle, from the array's own
+        // elements down to the slice's
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let counted: Arc<[DropCount<'static>]> =
+            unsize!(Arc::new([DropCount(&DROPS), DropCount(&DROPS)]));
+        let weak = Arc::downgrade(&counted);
+        let clones: Vec<_> = (0..3).map(|_| counted.clone()).collect();
+        assert_eq!(
+            (Arc::strong_count(&counted), Arc::weak_count(&counted)),
+            (4, 1)
+        );
+        drop(clones);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 0);
+        drop(counted);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 2);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn handle_sizes() {
+        use std::fmt::Display;
+        use std::mem::size_of;
+
+        assert_eq!(size_of::<Arc<u64>>(), size_of::<usize>());
+        assert_eq!(size_of::<Weak<u64>>(), size_of::<usize>());
+        // unsized targets carry a length or vtable next to the pointer
+        assert_eq!(size_of::<Arc<[u8]>>(), 2 * size_of::<usize>());
+        assert_eq!(size_of::<Arc<str>>(), 2 * size_of::<usize>());
+        assert_eq!(
+            size_of::<Arc<dyn Display + Send + Sync>>(),
+            2 * size_of::<usize>()
+        );
+        assert_eq!(size_of::<Weak<[u8]>>(), 2 * size_of::<usize>());
+        // and NonNull leaves a niche either way
+        assert_eq!(size_of::<Option<Arc<u64>>>(), size_of::<Arc<u64>>());
+        assert_eq!(size_of::<Option<Arc<str>>>(), size_of::<Arc<str>>());
+    }
+
+    #[cfg(feature = "nightly")]
+    mod coerce {
+        use super::*;
+        use std::fmt::Display;
+
+        trait Job: Send + Sync {
+            fn cost(&self) -> u32;
+
+            fn by_arc(self: Arc<Self>) -> u32;
+        }
+
+        struct Fixed(u32);
+
+        impl Job for Fixed {
+            fn cost(&self) -> u32 {
+                self.0
+            }
+
+            fn by_arc(self: Arc<Self>) -> u32 {
+                self.cost() * 2
+            }
+        }
+
+        #[test]
+        fn coerce_to_dyn() {
+            let shown: Arc<dyn Display + Send + Sync> = Arc::new(5u32);
+            assert_eq!(shown.to_string(), "5");
+            let slice: Arc<[u8]> = Arc::new([1, 2, 3]);
+            assert_eq!(*slice, [1, 2, 3]);
+            let any: Arc<dyn Any + Send + Sync> = Arc::new(String::from("x"));
+            assert_eq!(*any.downcast::<String>().unwrap(), "x");
+        }
+
+        #[test]
+        fn trait_objects_across_threads() {
+            let jobs: Vec<Arc<dyn Job>> = vec![Arc::new(Fixed(1)), Arc::new(Fixed(2))];
+            let shared = jobs.clone();
+            let handle = thread::spawn(move || shared.iter().map(|job| job.cost()).sum::<u32>());
+            assert_eq!(handle.join().unwrap(), 3);
+            let total: u32 = jobs.into_iter().map(Job::by_arc).sum();
+            assert_eq!(total, 6);
+        }
+    }
+
+    #[test]
+    fn downcast_across_threads() {
+        // Any needs 'static, so the DropCount counts into a static
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let mut registry = std::collections::HashMap::new();
+        registry.insert("name", into_any(Arc::new(String::from("acell"))));
+        registry.insert("answer", into_any(Arc::new(42u32)));
+        registry.insert("counted", into_any(Arc::new(DropCount(&DROPS))));
+
+        let registry = Arc::new(registry);
+        let handle = thread::spawn({
+            let registry = registry.clone();
+            move || {
+                let name = registry["name"].clone();
+                let name = name.downcast::<u32>().unwrap_err();
+                let name = name.downcast::<String>().unwrap();
+                assert_eq!(*name, "acell");
+                // the downcast Arc is a clone like any other
+                assert_eq!(Arc::strong_count(&name), 2);
+                let answer = registry["answer"].clone().downcast::<u32>().unwrap();
+                *answer
+            }
+        });
+        assert_eq!(handle.join().unwrap(), 42);
+        assert_eq!(Arc::strong_count(&registry["name"]), 1);
+
+        let counted = registry["counted"]
+            .clone()
+            .downcast::<DropCount<'static>>()
+            .ok()
+            .unwrap();
+        drop(registry);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 0);
+        drop(counted);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn pinned() {
+        struct SelfAddressed {
+            // where the value was when it was pinned, which must stay true
+            at: std::sync::atomic::AtomicPtr<SelfAddressed>,
+            _pin: std::marker::PhantomPinned,
+        }
+
+        let pinned = Arc::pin(SelfAddressed {
+            at: std::sync::atomic::AtomicPtr::new(ptr::null_mut()),
+            _pin: std::marker::PhantomPinned,
+        });
+        let here = &*pinned as *const SelfAddressed as *mut SelfAddressed;
+        pinned.at.store(here, Ordering::Relaxed);
+
+        let clone = pinned.clone();
+        let handle = thread::spawn(move || {
+            assert_eq!(
+                clone.at.load(Ordering::Relaxed),
+                &*clone as *const _ as *mut _
+            );
+        });
+        handle.join().unwrap();
+        assert_eq!(pinned.at.load(Ordering::Relaxed), here);
+    }
+
+    #[test]
+    fn send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Arc<AtomicUsize>>();
+        assert_send_sync::<Weak<Vec<u8>>>();
+    }
+
+    #[test]
+    fn clone_and_drop_hammered() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let clones = if cfg!(miri) { 20 } else { 10_000 };
+        let arc = Arc::new(DropCount(&DROPS));
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let arc = arc.clone();
+                thread::spawn(move || {
+                    let mut held = Vec::new();
+                    for i in 0..clones {
+                        held.push(arc.clone());
+                        if i % 3 == 0 {
+                            held.clear();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(Arc::strong_count(&arc), 1);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 0);
+        drop(arc);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn last_drop_on_another_thread() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let arc = Arc::new(DropCount(&DROPS));
+        let clone = arc.clone();
+        drop(arc);
+        thread::spawn(move || drop(clone)).join().unwrap();
+        assert_eq!(DROPS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn counts() {
+        let a = Arc::new(1);
+        assert_eq!((Arc::strong_count(&a), Arc::weak_count(&a)), (1, 0));
+        let b = a.clone();
+        let weak = Arc::downgrade(&a);
+        let weak2 = weak.clone();
+        assert_eq!((Arc::strong_count(&a), Arc::weak_count(&a)), (2, 2));
+        drop(weak);
+        drop(a);
+        assert_eq!((Arc::strong_count(&b), Arc::weak_count(&b)), (1, 1));
+        let c = weak2.upgrade().unwrap();
+        drop(weak2);
+        assert_eq!((Arc::strong_count(&c), Arc::weak_count(&c)), (2, 0));
+    }
+
+    #[test]
+    fn weak_counts() {
+        let arc = Arc::new(1);
+        let weak = Arc::downgrade(&arc);
+        assert_eq!((weak.strong_count(), weak.weak_count()), (1, 1));
+        let arc2 = arc.clone();
+        let weak2 = weak.clone();
+        assert_eq!((weak2.strong_count(), weak2.weak_count()), (2, 2));
+        drop((arc, arc2));
+        assert_eq!((weak.strong_count(), weak.weak_count()), (0, 0));
+        let dangling = Weak::<u8>::new();
+        assert_eq!((dangling.strong_count(), dangling.weak_count()), (0, 0));
+    }
+
+    #[test]
+    fn counts_across_threads() {
+        let threads = if cfg!(miri) { 3 } else { 8 };
+        let arc = Arc::new(());
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                let arc = arc.clone();
+                thread::spawn(move || {
+                    let weak = Arc::downgrade(&arc);
+                    let extra = arc.clone();
+                    // at most the original, one per thread and one extra
+                    // per thread have ever existed at once
+                    assert!(Arc::strong_count(&extra) >= 2);
+                    assert!(Arc::strong_count(&extra) <= 1 + 2 * threads);
+                    assert!(Arc::weak_count(&extra) >= 1);
+                    assert!(Arc::weak_count(&extra) <= threads);
+                    drop(weak);
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(Arc::strong_count(&arc), 1);
+        assert_eq!(Arc::weak_count(&arc), 0);
+    }
+
+    #[test]
+    fn get_mut_when_unique() {
+        let mut a = Arc::new(String::from("a"));
+        Arc::get_mut(&mut a).unwrap().push('b');
+        assert_eq!(*a, "ab");
+
+        let b = a.clone();
+        assert!(Arc::get_mut(&mut a).is_none());
+        drop(b);
+
+        let weak = Arc::downgrade(&a);
+        assert!(Arc::get_mut(&mut a).is_none());
+        drop(weak);
+        Arc::get_mut(&mut a).unwrap().push('c');
+        // the count was unlocked again
+        assert_eq!(Arc::weak_count(&a), 0);
+        assert_eq!(*Arc::downgrade(&a).upgrade().unwrap(), "abc");
+    }
+
+    #[test]
+    fn get_mut_sees_last_drop() {
+        let rounds = if cfg!(miri) { 5 } else { 200 };
+        for round in 0..rounds {
+            let mut arc = Arc::new(AtomicUsize::new(0));
+            let clone = arc.clone();
+            let other = thread::spawn(move || {
+                clone.store(round, Ordering::Relaxed);
+                drop(clone);
+            });
+            // spins until the other thread's drop; the non-atomic read
+            // through &mut is only race free (as far as miri is concerned)
+            // because get_mut synced with that drop
+            let value = loop {
+                if let Some(value) = Arc::get_mut(&mut arc) {
+                    break value;
+                }
+                std::hint::spin_loop();
+            };
+            assert_eq!(*value.get_mut(), round);
+            other.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn get_mut_races_upgrade() {
+        let rounds = if cfg!(miri) { 5 } else { 200 };
+        for _ in 0..rounds {
+            // a plain u64, so if get_mut ever handed out &mut while the other
+            // thread could read, miri would report the data race, and
+            // without miri the reader may see a half-done update
+            let mut arc = Arc::new(0u64);
+            let weak = Arc::downgrade(&arc);
+            let reader = thread::spawn(move || {
+                for _ in 0..10 {
+                    let arc = weak.upgrade().expect("the main thread's Arc is alive");
+                    assert_eq!(*arc % 2, 0);
+                }
+            });
+            let mut writes = 0;
+            while writes < 10 {
+                if let Some(value) = Arc::get_mut(&mut arc) {
+                    *value += 1;
+                    *value += 1;
+                    writes += 1;
+                }
+            }
+            reader.join().unwrap();
+            assert_eq!(*arc, 20);
+        }
+    }
+
+    // counts its clones, to tell when make_mut copied
+    struct CloneCount<'a>(u64, &'a AtomicUsize);
+
+    impl Clone for CloneCount<'_> {
+        fn clone(&self) -> Self {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            CloneCount(self.0, self.1)
+        }
+    }
+
+    #[test]
+    fn make_mut_unique_in_place() {
+        let clones = AtomicUsize::new(0);
+        let mut arc = Arc::new(CloneCount(1, &clones));
+        let before = &*arc as *const CloneCount;
+        Arc::make_mut(&mut arc).0 = 2;
+        assert_eq!(arc.0, 2);
+        assert_eq!(clones.load(Ordering::Relaxed), 0);
+        assert_eq!(&*arc as *const CloneCount, before);
+        assert_eq!(Arc::strong_count(&arc), 1);
+    }
+
+    #[test]
+    fn make_mut_shared_clones() {
+        let clones = AtomicUsize::new(0);
+        let mut a = Arc::new(CloneCount(1, &clones));
+        let b = a.clone();
+        let weak = Arc::downgrade(&b);
+        Arc::make_mut(&mut a).0 = 2;
+        assert_eq!((a.0, b.0), (2, 1));
+        assert_eq!(clones.load(Ordering::Relaxed), 1);
+        assert_eq!(Arc::strong_count(&a), 1);
+        assert_eq!(Arc::strong_count(&b), 1);
+        // the old allocation's Weak stays with it
+        assert_eq!(weak.upgrade().unwrap().0, 1);
+        assert_eq!(Arc::weak_count(&a), 0);
+    }
+
+    #[test]
+    fn make_mut_steals_from_weaks() {
+        let clones = AtomicUsize::new(0);
+        let mut arc = Arc::new(CloneCount(1, &clones));
+        let weak = Arc::downgrade(&arc);
+        Arc::make_mut(&mut arc).0 = 2;
+        assert_eq!(clones.load(Ordering::Relaxed), 0);
+        assert!(weak.upgrade().is_none());
+        assert_eq!(Arc::weak_count(&arc), 0);
+        assert_eq!(arc.0, 2);
+        drop(weak);
+    }
+
+    #[test]
+    fn make_mut_races_upgrade() {
+        let rounds = if cfg!(miri) { 5 } else { 200 };
+        for _ in 0..rounds {
+            let mut arc = Arc::new(0u64);
+            let weak = Arc::downgrade(&arc);
+            let other = thread::spawn(move || {
+                // either this gets in first and make_mut has to clone, or
+                // make_mut detaches the value and upgrades fail from then on
+                // an Arc from here must never see the write
+                if let Some(old) = weak.upgrade() {
+                    assert_eq!(*old, 0);
+                }
+            });
+            *Arc::make_mut(&mut arc) = 1;
+            other.join().unwrap();
+            assert_eq!(*arc, 1);
+            assert_eq!(Arc::strong_count(&arc), 1);
+        }
+    }
+
+    #[test]
+    fn make_mut_across_threads() {
+        let threads = if cfg!(miri) { 3 } else { 8 };
+        let adds = if cfg!(miri) { 10 } else { 1_000 };
+        let shared = Arc::new(vec![1u64; 4]);
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                let mut mine = shared.clone();
+                thread::spawn(move || {
+                    for _ in 0..adds {
+                        Arc::make_mut(&mut mine)[0] += 1;
+                    }
+                    mine
+                })
+            })
+            .collect();
+        let mut total = 0;
+        for handle in handles {
+            let mine = handle.join().unwrap();
+            assert_eq!(Arc::strong_count(&mine), 1);
+            total += mine[0];
+        }
+        // each thread diverged from shared on its first add
+        assert_eq!(total, threads as u64 * (adds + 1));
+        assert_eq!(*shared, [1; 4]);
+        assert_eq!(Arc::strong_count(&shared), 1);
+    }
+
+    #[test]
+    fn try_unwrap_only_when_unique() {
+        let drops = AtomicUsize::new(0);
+        let a = Arc::new(DropCount(&drops));
+        let b = a.clone();
+        let weak = Arc::downgrade(&a);
+        let a = Arc::try_unwrap(a).err().unwrap();
+        assert_eq!(Arc::strong_count(&a), 2);
+        drop(b);
+
+        let value = Arc::try_unwrap(a).ok().unwrap();
+        assert!(weak.upgrade().is_none());
+        assert_eq!(drops.load(Ordering::Relaxed), 0);
+        drop(value);
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn unwrap_or_clone() {
+        let clones = AtomicUsize::new(0);
+        let arc = Arc::new(CloneCount(1, &clones));
+        let other = arc.clone();
+        assert_eq!(Arc::unwrap_or_clone(arc).0, 1);
+        assert_eq!(clones.load(Ordering::Relaxed), 1);
+        assert_eq!(Arc::unwrap_or_clone(other).0, 1);
+        assert_eq!(clones.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn unwrap_or_clone_races() {
+        static CLONES: AtomicUsize = AtomicUsize::new(0);
+        let threads = if cfg!(miri) { 3 } else { 8 };
+        let rounds = if cfg!(miri) { 3 } else { 100 };
+        for _ in 0..rounds {
+            CLONES.store(0, Ordering::Relaxed);
+            let arc = Arc::new(CloneCount(7, &CLONES));
+            let handles: Vec<_> = (0..threads)
+                .map(|_| {
+                    let mine = arc.clone();
+                    thread::spawn(move || Arc::unwrap_or_clone(mine).0)
+                })
+                .collect();
+            drop(arc);
+            for handle in handles {
+                assert_eq!(handle.join().unwrap(), 7);
+            }
+            // at most one thread got the original, every other one cloned
+            let clones = CLONES.load(Ordering::Relaxed);
+            assert!(clones == threads || clones == threads - 1);
+        }
+    }
+
+    #[test]
+    fn try_unwrap_races_upgrade() {
+        let rounds = if cfg!(miri) { 5 } else { 200 };
+        for _ in 0..rounds {
+            let arc = Arc::new(String::from("value"));
+            let weak = Arc::downgrade(&arc);
+            let other = thread::spawn(move || weak.upgrade());
+            // either the upgrade got in first and the unwrap fails, or the
+            // unwrap won and the upgrade finds nothing, never both
+            let unwrapped = Arc::try_unwrap(arc);
+            let upgraded = other.join().unwrap();
+            match (unwrapped, upgraded) {
+                (Ok(value), None) => assert_eq!(value, "value"),
+                (Err(arc), Some(other)) => {
+                    assert_eq!(Arc::strong_count(&arc), 2);
+                    assert_eq!(*other, "value");
+                }
+                (Err(_), None) => panic!("nothing else held the value"),
+                (Ok(_), Some(_)) => panic!("unwrapped a shared value"),
+            }
+        }
+    }
+
+    #[test]
+    fn into_inner_last_one_wins() {
+        let a = Arc::new(String::from("last"));
+        let b = a.clone();
+        assert_eq!(Arc::into_inner(a), None);
+        assert_eq!(Arc::into_inner(b).unwrap(), "last");
+    }
+
+    #[test]
+    fn into_inner_races() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let rounds = if cfg!(miri) { 5 } else { 200 };
+        for round in 0..rounds {
+            let a = Arc::new(DropCount(&DROPS));
+            let b = a.clone();
+            let other = thread::spawn(move || Arc::into_inner(b).is_some());
+            let here = Arc::into_inner(a).is_some();
+            let there = other.join().unwrap();
+            // exactly one of them got it, and it was dropped once, by them
+            assert!(here != there);
+            assert_eq!(DROPS.load(Ordering::Relaxed), round + 1);
+        }
+    }
+
+    #[test]
+    fn upgrade_only_while_strong() {
+        let drops = AtomicUsize::new(0);
+        let arc = Arc::new(DropCount(&drops));
+        let weak = Arc::downgrade(&arc);
+        let upgraded = weak.upgrade().unwrap();
+        assert_eq!(Arc::strong_count(&upgraded), 2);
+        drop(upgraded);
+        drop(arc);
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+        assert!(weak.upgrade().is_none());
+        assert!(Weak::<u8>::new().upgrade().is_none());
+    }
+
+    #[test]
+    fn weak_keeps_header_alive() {
+        let drops = AtomicUsize::new(0);
+        let arc = Arc::new(DropCount(&drops));
+        let weak = Arc::downgrade(&arc);
+        let weak2 = weak.clone();
+        drop(arc);
+        // the value is gone, but the counts can still be read
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+        let inner = weak.inner.as_ptr();
+        assert_eq!(
+            unsafe { ArcInner::strong(inner) }.load(Ordering::Relaxed),
+            0
+        );
+        assert_eq!(unsafe { ArcInner::weak(inner) }.load(Ordering::Relaxed), 2);
+        drop(weak);
+        assert!(weak2.upgrade().is_none());
+        drop(weak2);
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn last_weak_races_last_arc() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let rounds = if cfg!(miri) { 5 } else { 200 };
+        for round in 0..rounds {
+            let arc = Arc::new(DropCount(&DROPS));
+            let weak = Arc::downgrade(&arc);
+            // either side may end up freeing the allocation
+            let other = thread::spawn(move || {
+                let _ = weak.upgrade();
+                drop(weak);
+            });
+            drop(arc);
+            other.join().unwrap();
+            assert_eq!(DROPS.load(Ordering::Relaxed), round + 1);
+        }
+    }
+
+    #[test]
+    fn upgrade_races_last_drop() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let rounds = if cfg!(miri) { 5 } else { 200 };
+        for round in 0..rounds {
+            let arc = Arc::new((5u64, DropCount(&DROPS)));
+            let weak = Arc::downgrade(&arc);
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    let weak = weak.clone();
+                    thread::spawn(move || {
+                        // every upgrade that succeeds sees the value intact,
+                        // and the last of them may be the one that drops it
+                        while let Some(arc) = weak.upgrade() {
+                            assert_eq!(arc.0, 5);
+                        }
+                    })
+                })
+                .collect();
+            drop(arc);
+            for handle in handles {
+                handle.join().unwrap();
+            }
+            assert!(weak.upgrade().is_none());
+            assert_eq!(DROPS.load(Ordering::Relaxed), round + 1);
+        }
+    }
+}
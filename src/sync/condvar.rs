@@ -0,0 +1,354 @@
+// a condition variable, for sleeping on a Mutex until another thread says
+// the value it guards has changed
+//
+// seq counts notifies: a waiter reads it while still holding the mutex, and
+// sleeps only if it's unchanged by the time it's queued, so a notify that
+// lands between unlocking the mutex and falling asleep isn't lost
+// anyone who changed the value under the mutex before notifying either
+// bumped seq before the waiter's check, or finds the waiter in the queue
+//
+// wakeups can still be spurious, e.g. a notify meant for an earlier waiter,
+// which is why waiting is normally done through wait_while, or in a loop
+// that checks the condition again
+//
+// a Condvar remembers the first mutex it's used with and panics if it's
+// later used with another, since waiters on two mutexes can't share one
+// notify
+use super::mutex::MutexGuard;
+use super::poison::{LockResult, PoisonError};
+use super::wait_queue::WaitQueue;
+use std::fmt;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+pub struct Condvar {
+    seq: AtomicU32,
+    waiters: WaitQueue,
+    // address of the mutex, 0 until the first wait
+    mutex: AtomicUsize,
+}
+
+/// whether wait_timeout returned because time ran out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            seq: AtomicU32::new(0),
+            waiters: WaitQueue::new(),
+            mutex: AtomicUsize::new(0),
+        }
+    }
+
+    /// unlock the guard's mutex and sleep until notified, then lock it again
+    /// the wakeup may be spurious, so check what was waited for again, or
+    /// use wait_while
+    /// Err if the mutex is poisoned once it's locked again, with the guard
+    /// inside
+    ///
+    /// panics if this Condvar was already used with a different mutex
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> LockResult<MutexGuard<'a, T>> {
+        let (guard, _) = self.wait_until(guard, None);
+        poison_result(guard)
+    }
+
+    /// wait until condition returns false, checking it before the first wait
+    /// and after every wakeup
+    pub fn wait_while<'a, T: ?Sized, F: FnMut(&mut T) -> bool>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> LockResult<MutexGuard<'a, T>> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard)?;
+        }
+        Ok(guard)
+    }
+
+    /// wait, giving up after timeout
+    /// the mutex is locked again either way, and a timeout too far out to
+    /// represent waits forever
+    pub fn wait_timeout<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> LockResult<(MutexGuard<'a, T>, WaitTimeoutResult)> {
+        let (guard, woken) = self.wait_until(guard, Instant::now().checked_add(timeout));
+        let result = WaitTimeoutResult(!woken);
+        match poison_result(guard) {
+            Ok(guard) => Ok((guard, result)),
+            Err(err) => Err(PoisonError::new((err.into_inner(), result))),
+        }
+    }
+
+    /// wake one waiting thread, if there are any
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        self.waiters.notify_one();
+    }
+
+    /// wake every waiting thread
+    pub fn notify_all(&self) {
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        self.waiters.notify_all();
+    }
+
+    // false if the deadline passed without a notify
+    fn wait_until<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        deadline: Option<Instant>,
+    ) -> (MutexGuard<'a, T>, bool) {
+        self.check_mutex(MutexGuard::mutex(&guard) as *const _ as *const () as usize);
+        // Relaxed: read with the mutex held, and the notifier changed the
+        // value under the same mutex, whose orderings are enough
+        let seq = self.seq.load(Ordering::Relaxed);
+        let woken = MutexGuard::unlocked(&mut guard, || {
+            self.waiters
+                .wait_until(|| self.seq.load(Ordering::Relaxed) == seq, deadline)
+        });
+        (guard, woken)
+    }
+
+    fn check_mutex(&self, addr: usize) {
+        match self
+            .mutex
+            .compare_exchange(0, addr, Ordering::Relaxed, Ordering::Relaxed)
+        {
+            Ok(_) => {}
+            Err(prev) if prev == addr => {}
+            Err(_) => panic!("a Condvar can't be used with more than one Mutex"),
+        }
+    }
+}
+
+fn poison_result<T: ?Sized>(guard: MutexGuard<'_, T>) -> LockResult<MutexGuard<'_, T>> {
+    if MutexGuard::mutex(&guard).is_poisoned() {
+        Err(PoisonError::new(guard))
+    } else {
+        Ok(guard)
+    }
+}
+
+impl fmt::Debug for Condvar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Condvar { .. }")
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Condvar::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::{Arc, Mutex};
+    use std::collections::VecDeque;
+    use std::thread;
+
+    struct Queue {
+        items: VecDeque<usize>,
+        closed: bool,
+    }
+
+    #[test]
+    fn bounded_queue() {
+        const CAP: usize = 4;
+        let items = if cfg!(miri) { 50 } else { 2000 };
+        let consumers = 4;
+        let state = Arc::new((
+            Mutex::new(Queue {
+                items: VecDeque::new(),
+                closed: false,
+            }),
+            Condvar::new(),
+            Condvar::new(),
+        ));
+
+        let handles: Vec<_> = (0..consumers)
+            .map(|_| {
+                let state = state.clone();
+                thread::spawn(move || {
+                    let (queue, not_empty, not_full) = &*state;
+                    let mut sum = 0;
+                    loop {
+                        let mut q = not_empty
+                            .wait_while(queue.lock().unwrap(), |q| q.items.is_empty() && !q.closed)
+                            .unwrap();
+                        match q.items.pop_front() {
+                            Some(item) => {
+                                drop(q);
+                                not_full.notify_one();
+                                sum += item;
+                            }
+                            None => return sum,
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        let (queue, not_empty, not_full) = &*state;
+        for item in 1..=items {
+            let mut q = not_full
+                .wait_while(queue.lock().unwrap(), |q| q.items.len() == CAP)
+                .unwrap();
+            q.items.push_back(item);
+            drop(q);
+            not_empty.notify_one();
+        }
+        queue.lock().unwrap().closed = true;
+        not_empty.notify_all();
+
+        let total: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(total, items * (items + 1) / 2);
+    }
+
+    #[test]
+    fn wait_while_flipped_elsewhere() {
+        let state = Arc::new((Mutex::new(false), Condvar::new()));
+        let flipper = thread::spawn({
+            let state = state.clone();
+            move || {
+                let (ready, cvar) = &*state;
+                *ready.lock().unwrap() = true;
+                cvar.notify_one();
+            }
+        });
+        let (ready, cvar) = &*state;
+        let guard = cvar
+            .wait_while(ready.lock().unwrap(), |ready| !*ready)
+            .unwrap();
+        assert!(*guard);
+        drop(guard);
+        flipper.join().unwrap();
+    }
+
+    #[test]
+    fn wait_timeout_expires() {
+        let mutex = Mutex::new(0);
+        let cvar = Condvar::new();
+        let timeout = Duration::from_millis(20);
+        let start = Instant::now();
+        let (mut guard, result) = cvar.wait_timeout(mutex.lock().unwrap(), timeout).unwrap();
+        assert!(result.timed_out());
+        assert!(start.elapsed() >= timeout);
+        // and the mutex is locked again
+        *guard += 1;
+        assert!(mutex.try_lock().is_err());
+    }
+
+    #[test]
+    fn wait_timeout_notified() {
+        let state = Arc::new((Mutex::new(false), Condvar::new()));
+        let notifier = thread::spawn({
+            let state = state.clone();
+            move || {
+                let (done, cvar) = &*state;
+                *done.lock().unwrap() = true;
+                cvar.notify_one();
+            }
+        });
+        let (done, cvar) = &*state;
+        let mut guard = done.lock().unwrap();
+        while !*guard {
+            let (g, result) = cvar.wait_timeout(guard, Duration::from_secs(30)).unwrap();
+            assert!(!result.timed_out());
+            guard = g;
+        }
+        drop(guard);
+        notifier.join().unwrap();
+    }
+
+    #[test]
+    fn notify_all_wakes_everyone() {
+        let waiters = 4;
+        // how many are waiting, and whether they've been let go
+        let state = Arc::new((Mutex::new((0, false)), Condvar::new(), Condvar::new()));
+        let handles: Vec<_> = (0..waiters)
+            .map(|_| {
+                let state = state.clone();
+                thread::spawn(move || {
+                    let (mutex, go, arrived) = &*state;
+                    let mut guard = mutex.lock().unwrap();
+                    guard.0 += 1;
+                    arrived.notify_one();
+                    // a plain wait, so a notify_one would leave the others
+                    // asleep
+                    while !guard.1 {
+                        guard = go.wait(guard).unwrap();
+                    }
+                })
+            })
+            .collect();
+        let (mutex, go, arrived) = &*state;
+        let mut guard = arrived
+            .wait_while(mutex.lock().unwrap(), |(n, _)| *n < waiters)
+            .unwrap();
+        guard.1 = true;
+        drop(guard);
+        go.notify_all();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn notify_without_waiters() {
+        let cvar = Condvar::new();
+        cvar.notify_one();
+        cvar.notify_all();
+        let mutex = Mutex::new(());
+        // an earlier notify doesn't count for a later wait
+        let (_, result) = cvar
+            .wait_timeout(mutex.lock().unwrap(), Duration::from_millis(1))
+            .unwrap();
+        assert!(result.timed_out());
+    }
+
+    #[test]
+    #[should_panic(expected = "more than one Mutex")]
+    fn two_mutexes_panic() {
+        let a = Mutex::new(());
+        let b = Mutex::new(());
+        let cvar = Condvar::new();
+        drop(cvar.wait_timeout(a.lock().unwrap(), Duration::ZERO));
+        drop(cvar.wait_timeout(b.lock().unwrap(), Duration::ZERO));
+    }
+
+    #[test]
+    fn poisoned_while_waiting() {
+        let state = Arc::new((Mutex::new(false), Condvar::new()));
+        let (mutex, cvar) = &*state;
+        let guard = mutex.lock().unwrap();
+        let poisoner = thread::spawn({
+            let state = state.clone();
+            move || {
+                let (mutex, cvar) = &*state;
+                let mut guard = mutex.lock().unwrap();
+                *guard = true;
+                cvar.notify_one();
+                panic!("poisoning the mutex");
+            }
+        });
+        let err = cvar.wait_while(guard, |done| !*done).unwrap_err();
+        assert!(*err.into_inner());
+        assert!(poisoner.join().is_err());
+    }
+
+    #[test]
+    fn send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Condvar>();
+    }
+}
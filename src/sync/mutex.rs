@@ -0,0 +1,947 @@
+// a lock that blocks until it's free, the thread safe counterpart to
+// RefCell::borrow_mut
+//
+// the state is one of three values:
+// - UNLOCKED
+// - LOCKED, with nobody waiting
+// - CONTENDED, locked and some thread may be asleep in waiters
+// lock tries a compare-exchange from UNLOCKED, then spins for a bit since
+// most critical sections are short, and only then marks the lock CONTENDED
+// and sleeps
+// unlock only has to go through the wait queue when it finds CONTENDED, so
+// an uncontended lock and unlock is one atomic operation each
+//
+// a thread that got the lock after sleeping keeps it CONTENDED, since it
+// can't know whether anyone else is still asleep; at worst that costs an
+// unlock a look at an empty queue
+//
+// orderings: taking the lock is Acquire and releasing it is Release, so
+// everything done under one guard happens before everything done under the
+// next
+//
+// a guard dropped by a panic poisons the lock, see poison.rs
+use super::poison::{self, LockResult, PoisonError, TryLockError, TryLockResult};
+use super::wait_queue::WaitQueue;
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::hint;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+const CONTENDED: u32 = 2;
+
+pub struct Mutex<T: ?Sized> {
+    raw: RawMutex,
+    value: UnsafeCell<T>,
+}
+
+// the lock without the value, which is all a guard needs to unlock, so
+// mapped guards don't need to know T
+struct RawMutex {
+    state: AtomicU32,
+    waiters: WaitQueue,
+    poison: poison::Flag,
+}
+
+// the lock hands out &mut T to one thread at a time, which is the same as
+// moving the T between them, so Send is all T needs for either
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+// like std's: a value left broken by a panic can only be reached again
+// through the poison error, which says so
+impl<T: ?Sized> UnwindSafe for Mutex<T> {}
+impl<T: ?Sized> RefUnwindSafe for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            raw: RawMutex {
+                state: AtomicU32::new(UNLOCKED),
+                waiters: WaitQueue::new(),
+                poison: poison::Flag::new(),
+            },
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// the value, without locking since no guard can outlive the Mutex
+    /// Err if it was poisoned, with the value inside it
+    pub fn into_inner(mut self) -> LockResult<T> {
+        let poisoned = self.raw.poison.get_mut();
+        let value = self.value.into_inner();
+        if poisoned {
+            Err(PoisonError::new(value))
+        } else {
+            Ok(value)
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// wait until no other guard exists, and return one
+    /// Err if a thread panicked while holding a guard, with the guard
+    /// inside it
+    ///
+    /// locking again on the same thread while holding a guard deadlocks
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        self.raw.lock();
+        self.raw.poison.result(MutexGuard {
+            mutex: self,
+            poison: self.raw.poison.guard(),
+            _marker: PhantomData,
+        })
+    }
+
+    /// a guard if the Mutex is free right now, without waiting
+    /// Err(WouldBlock) if it's held, Err(Poisoned) like lock if it was free
+    /// but poisoned
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
+        let locked = self.raw.try_lock().then(|| MutexGuard {
+            mutex: self,
+            poison: self.raw.poison.guard(),
+            _marker: PhantomData,
+        });
+        self.raw.poison.try_result(locked)
+    }
+
+    /// lock, but give up after timeout with Err(WouldBlock)
+    /// a timeout too far out to represent waits forever
+    pub fn try_lock_for(&self, timeout: Duration) -> TryLockResult<MutexGuard<'_, T>> {
+        self.lock_with_deadline(Instant::now().checked_add(timeout))
+    }
+
+    /// lock, but give up at deadline with Err(WouldBlock)
+    pub fn try_lock_until(&self, deadline: Instant) -> TryLockResult<MutexGuard<'_, T>> {
+        self.lock_with_deadline(Some(deadline))
+    }
+
+    fn lock_with_deadline(&self, deadline: Option<Instant>) -> TryLockResult<MutexGuard<'_, T>> {
+        let locked =
+            (self.raw.try_lock() || self.raw.lock_contended(deadline)).then(|| MutexGuard {
+                mutex: self,
+                poison: self.raw.poison.guard(),
+                _marker: PhantomData,
+            });
+        self.raw.poison.try_result(locked)
+    }
+
+    /// the value, without locking since &mut self means no guard exists
+    /// Err if it was poisoned, like into_inner
+    /// neither touches an atomic, &mut is all the synchronization needed
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        let value = self.value.get_mut();
+        if self.raw.poison.get_mut() {
+            Err(PoisonError::new(value))
+        } else {
+            Ok(value)
+        }
+    }
+
+    /// whether a thread panicked while holding a guard
+    /// another thread can poison it right after this returns false
+    pub fn is_poisoned(&self) -> bool {
+        self.raw.poison.get()
+    }
+
+    /// forget that the Mutex was poisoned, e.g. once the value has been put
+    /// right through the guard in a PoisonError
+    /// a guard that's dropped by a panic later poisons it again
+    pub fn clear_poison(&self) {
+        self.raw.poison.clear();
+    }
+}
+
+impl RawMutex {
+    fn try_lock(&self) -> bool {
+        // strong, since a spurious failure would be a WouldBlock for no
+        // reason
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    fn lock(&self) {
+        if self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            self.lock_contended(None);
+        }
+    }
+
+    // false if the deadline passed first
+    #[cold]
+    fn lock_contended(&self, deadline: Option<Instant>) -> bool {
+        // spin while the holder is likely to be done soon, i.e. while it
+        // hasn't had to make anyone sleep yet
+        for _ in 0..100 {
+            match self.state.load(Ordering::Relaxed) {
+                UNLOCKED => {
+                    if self
+                        .state
+                        .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+                        .is_ok()
+                    {
+                        return true;
+                    }
+                }
+                LOCKED => hint::spin_loop(),
+                _ => break,
+            }
+        }
+        // the swap both takes the lock if it's free and tells the holder to
+        // wake someone when it unlocks
+        // a thread that times out leaves the state CONTENDED, which only
+        // costs the next unlock a look at the queue
+        while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+            // a wakeup that came with the deadline is still used for one
+            // more try, so it isn't lost to the others
+            let woken = self
+                .waiters
+                .wait_until(|| self.state.load(Ordering::Relaxed) == CONTENDED, deadline);
+            if !woken {
+                return false;
+            }
+        }
+        true
+    }
+
+    // what every guard's drop does
+    fn unlock(&self, poison: &poison::Guard) {
+        self.poison.done(poison);
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            self.waiters.notify_one();
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("Mutex");
+        // can't wait for the lock here, whoever holds it might be the one
+        // formatting
+        match self.try_lock() {
+            Ok(guard) => d.field("data", &&*guard),
+            Err(TryLockError::Poisoned(err)) => d.field("data", &&**err.get_ref()),
+            Err(TryLockError::WouldBlock) => d.field("data", &format_args!("<locked>")),
+        };
+        d.field("poisoned", &self.is_poisoned()).finish()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T> From<T> for Mutex<T> {
+    fn from(value: T) -> Self {
+        Mutex::new(value)
+    }
+}
+
+/// exclusive access to a Mutex's value, unlocking it when dropped
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+    poison: poison::Guard,
+    // not Send: the guard has to be dropped on the thread that locked, since
+    // poisoning looks at whether that thread is panicking
+    // the raw pointer takes Sync away too, and it's given back below
+    _marker: PhantomData<(&'a mut T, *const ())>,
+}
+
+// a guard shared between threads hands out &T to all of them
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    /// narrow the guard down to part of the value, e.g. one field, keeping
+    /// the Mutex locked until the new guard is dropped
+    /// if f panics, the Mutex is unlocked (and poisoned) as usual
+    pub fn map<U: ?Sized, F: FnOnce(&mut T) -> &mut U>(
+        this: Self,
+        f: F,
+    ) -> MappedMutexGuard<'a, U> {
+        // this guard holds the lock, so the value is ours to lend out
+        let value = NonNull::from(f(unsafe { &mut *this.mutex.value.get() }));
+        // its unlocking is the new guard's job now
+        let this = ManuallyDrop::new(this);
+        MappedMutexGuard {
+            raw: &this.mutex.raw,
+            poison: this.poison,
+            value,
+            _marker: PhantomData,
+        }
+    }
+
+    /// unlock, run f, and lock again before returning, e.g. to call out to
+    /// code that might need the lock itself
+    /// other threads can lock and change the value while f runs
+    ///
+    /// if f panics the lock is taken back while unwinding, so the guard is
+    /// still a guard when it's dropped, and since that drop happens during a
+    /// panic it poisons the Mutex like any other panic with a guard held
+    pub fn unlocked<U, F: FnOnce() -> U>(this: &mut Self, f: F) -> U {
+        struct Relock<'a>(&'a RawMutex);
+
+        impl Drop for Relock<'_> {
+            fn drop(&mut self) {
+                self.0.lock();
+            }
+        }
+
+        this.mutex.raw.unlock(&this.poison);
+        let _relock = Relock(&this.mutex.raw);
+        f()
+    }
+
+    // for Condvar, which has to know which Mutex a guard came from
+    pub(super) fn mutex(this: &Self) -> &'a Mutex<T> {
+        this.mutex
+    }
+
+    /// map, if f finds the part it's looking for, otherwise the guard back
+    /// unchanged
+    pub fn try_map<U: ?Sized, F: FnOnce(&mut T) -> Option<&mut U>>(
+        this: Self,
+        f: F,
+    ) -> Result<MappedMutexGuard<'a, U>, Self> {
+        match f(unsafe { &mut *this.mutex.value.get() }).map(NonNull::from) {
+            Some(value) => {
+                let this = ManuallyDrop::new(this);
+                Ok(MappedMutexGuard {
+                    raw: &this.mutex.raw,
+                    poison: this.poison,
+                    value,
+                    _marker: PhantomData,
+                })
+            }
+            None => Err(this),
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // this guard holds the lock, so nothing else can reach the value
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.raw.unlock(&self.poison);
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+/// a MutexGuard narrowed down by map to part of the value
+pub struct MappedMutexGuard<'a, T: ?Sized> {
+    raw: &'a RawMutex,
+    poison: poison::Guard,
+    value: NonNull<T>,
+    // Send and Sync like MutexGuard
+    _marker: PhantomData<(&'a mut T, *const ())>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for MappedMutexGuard<'_, T> {}
+
+impl<'a, T: ?Sized> MappedMutexGuard<'a, T> {
+    /// narrow the guard down further
+    pub fn map<U: ?Sized, F: FnOnce(&mut T) -> &mut U>(
+        mut this: Self,
+        f: F,
+    ) -> MappedMutexGuard<'a, U> {
+        let value = NonNull::from(f(&mut *this));
+        let this = ManuallyDrop::new(this);
+        MappedMutexGuard {
+            raw: this.raw,
+            poison: this.poison,
+            value,
+            _marker: PhantomData,
+        }
+    }
+
+    /// map further, if f finds the part it's looking for
+    pub fn try_map<U: ?Sized, F: FnOnce(&mut T) -> Option<&mut U>>(
+        mut this: Self,
+        f: F,
+    ) -> Result<MappedMutexGuard<'a, U>, Self> {
+        match f(&mut *this).map(NonNull::from) {
+            Some(value) => {
+                let this = ManuallyDrop::new(this);
+                Ok(MappedMutexGuard {
+                    raw: this.raw,
+                    poison: this.poison,
+                    value,
+                    _marker: PhantomData,
+                })
+            }
+            None => Err(this),
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for MappedMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // value came from a &mut handed out under the lock this still holds
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MappedMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.value.as_mut() }
+    }
+}
+
+impl<T: ?Sized> Drop for MappedMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.raw.unlock(&self.poison);
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for MappedMutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for MappedMutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    #[test]
+    fn lock_and_unlock() {
+        let mutex = Mutex::new(vec![1]);
+        mutex.lock().unwrap().push(2);
+        let guard = mutex.lock().unwrap();
+        assert_eq!(*guard, [1, 2]);
+        assert_eq!(format!("{:?}", guard), "[1, 2]");
+    }
+
+    #[test]
+    fn counter_across_threads() {
+        let threads = if cfg!(miri) { 4 } else { 16 };
+        let rounds = if cfg!(miri) { 50 } else { 10_000 };
+        let counter = Arc::new(Mutex::new(0u64));
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                let counter = counter.clone();
+                thread::spawn(move || {
+                    for _ in 0..rounds {
+                        // a read and a write, which would lose updates if two
+                        // threads ever held the guard at once
+                        let mut guard = counter.lock().unwrap();
+                        let n = *guard;
+                        *guard = n + 1;
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(*counter.lock().unwrap(), threads * rounds);
+    }
+
+    #[test]
+    fn waiter_sleeps_until_unlock() {
+        let mutex = Arc::new(Mutex::new(0));
+        let guard = mutex.lock().unwrap();
+        let got_it = Arc::new(AtomicBool::new(false));
+        let handle = thread::spawn({
+            let mutex = mutex.clone();
+            let got_it = got_it.clone();
+            move || {
+                let mut guard = mutex.lock().unwrap();
+                got_it.store(true, Ordering::Relaxed);
+                *guard += 1;
+            }
+        });
+        // long enough for the other thread to give up spinning and park
+        thread::sleep(Duration::from_millis(50));
+        assert!(!got_it.load(Ordering::Relaxed));
+        drop(guard);
+        handle.join().unwrap();
+        assert!(got_it.load(Ordering::Relaxed));
+        assert_eq!(*mutex.lock().unwrap(), 1);
+        // and the lock isn't left looking contended for good
+        assert_eq!(mutex.raw.state.load(Ordering::Relaxed), UNLOCKED);
+    }
+
+    #[test]
+    fn panic_poisons() {
+        let mutex = Arc::new(Mutex::new(vec![1, 2]));
+        let handle = thread::spawn({
+            let mutex = mutex.clone();
+            move || {
+                let mut guard = mutex.lock().unwrap();
+                guard.push(3);
+                panic!("halfway through");
+            }
+        });
+        assert!(handle.join().is_err());
+        assert!(mutex.is_poisoned());
+
+        let err = mutex.lock().unwrap_err();
+        assert_eq!(format!("{:?}", err), "PoisonError { .. }");
+        assert_eq!(**err.get_ref(), [1, 2, 3]);
+        // still locked while the error holds the guard
+        let mut guard = err.into_inner();
+        guard.pop();
+        drop(guard);
+        // and it stays poisoned
+        assert!(mutex.lock().is_err());
+        assert_eq!(*mutex.lock().unwrap_or_else(|e| e.into_inner()), [1, 2]);
+    }
+
+    #[test]
+    fn recover_and_clear_poison() {
+        // a list that has to stay sorted
+        let mutex = Arc::new(Mutex::new(vec![1, 2, 4]));
+        let handle = thread::spawn({
+            let mutex = mutex.clone();
+            move || {
+                let mut sorted = mutex.lock().unwrap();
+                sorted.push(3);
+                // the panic comes before the sort that would fix it up
+                assert!(sorted.windows(2).all(|w| w[0] <= w[1]), "unsorted");
+            }
+        });
+        assert!(handle.join().is_err());
+        assert!(mutex.is_poisoned());
+
+        let mut sorted = mutex.lock().unwrap_or_else(|err| err.into_inner());
+        sorted.sort_unstable();
+        mutex.clear_poison();
+        drop(sorted);
+        assert!(!mutex.is_poisoned());
+        assert_eq!(*mutex.lock().unwrap(), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn only_panics_poison() {
+        fn fails(mutex: &Mutex<i32>) -> Result<(), String> {
+            let mut guard = mutex.lock().unwrap();
+            *guard += 1;
+            // an early return with the guard held is a normal unlock
+            Err(String::from("gave up"))?;
+            *guard += 1;
+            Ok(())
+        }
+
+        let mutex = Arc::new(Mutex::new(0));
+        assert!(fails(&mutex).is_err());
+        let handle = thread::spawn({
+            let mutex = mutex.clone();
+            move || {
+                let guard = mutex.lock().unwrap();
+                // a thread that ends with the guard still held
+                std::mem::forget(guard);
+            }
+        });
+        handle.join().unwrap();
+        // which leaves it locked, but not poisoned
+        assert!(matches!(mutex.try_lock(), Err(TryLockError::WouldBlock)));
+        assert!(!mutex.is_poisoned());
+    }
+
+    #[test]
+    fn locking_while_unwinding_does_not_poison() {
+        struct LocksOnDrop<'a>(&'a Mutex<i32>);
+
+        impl Drop for LocksOnDrop<'_> {
+            fn drop(&mut self) {
+                *self.0.lock().unwrap() += 1;
+            }
+        }
+
+        let mutex = Mutex::new(0);
+        let result = std::panic::catch_unwind(|| {
+            let _locks = LocksOnDrop(&mutex);
+            panic!("unrelated");
+        });
+        assert!(result.is_err());
+        assert!(!mutex.is_poisoned());
+        assert_eq!(*mutex.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn map_to_a_field() {
+        struct Account {
+            owner: String,
+            balance: i64,
+        }
+
+        let mutex = Arc::new(Mutex::new(Account {
+            owner: String::from("ada"),
+            balance: 10,
+        }));
+        let mut balance = MutexGuard::map(mutex.lock().unwrap(), |a| &mut a.balance);
+        *balance += 5;
+        assert_eq!(format!("{}", balance), "15");
+
+        // still locked while the mapped guard is around
+        let handle = thread::spawn({
+            let mutex = mutex.clone();
+            move || mutex.lock().unwrap().balance
+        });
+        thread::sleep(Duration::from_millis(20));
+        *balance -= 1;
+        drop(balance);
+        assert_eq!(handle.join().unwrap(), 14);
+
+        // mapped guards map further
+        let account = MutexGuard::map(mutex.lock().unwrap(), |a| a);
+        let mut owner = MappedMutexGuard::map(account, |a| a.owner.as_mut_str());
+        owner.make_ascii_uppercase();
+        drop(owner);
+        assert_eq!(mutex.lock().unwrap().owner, "ADA");
+        assert!(!mutex.is_poisoned());
+    }
+
+    // the kind of type map is for: callers get at one entry without ever
+    // seeing the lock or the rest of the table
+    struct Registry {
+        entries: Mutex<Vec<(String, u32)>>,
+    }
+
+    impl Registry {
+        fn entry(&self, name: &str) -> Option<MappedMutexGuard<'_, u32>> {
+            let entries = MutexGuard::map(self.entries.lock().unwrap(), |e| e.as_mut_slice());
+            MappedMutexGuard::try_map(entries, |e| {
+                e.iter_mut().find(|(n, _)| n == name).map(|(_, v)| v)
+            })
+            .ok()
+        }
+    }
+
+    #[test]
+    fn mapped_guard_from_an_accessor() {
+        let registry = Registry {
+            entries: Mutex::new(vec![(String::from("a"), 1), (String::from("b"), 2)]),
+        };
+        let mut b = registry.entry("b").unwrap();
+        *b += 10;
+        // the whole table stays locked while the entry is out
+        assert!(matches!(
+            registry.entries.try_lock(),
+            Err(TryLockError::WouldBlock)
+        ));
+        drop(b);
+        assert!(registry.entries.try_lock().is_ok());
+        assert_eq!(*registry.entry("b").unwrap(), 12);
+        // a miss unlocks too
+        assert!(registry.entry("c").is_none());
+        assert!(registry.entries.try_lock().is_ok());
+    }
+
+    #[test]
+    fn try_map_gives_the_guard_back() {
+        let mutex = Mutex::new(vec![1, 2]);
+        let guard = mutex.lock().unwrap();
+        let mut guard = MutexGuard::try_map(guard, |v| v.get_mut(5)).err().unwrap();
+        guard.push(3);
+        let last = MutexGuard::try_map(guard, |v| v.last_mut()).ok().unwrap();
+        assert_eq!(*last, 3);
+        assert!(matches!(mutex.try_lock(), Err(TryLockError::WouldBlock)));
+        drop(last);
+        assert_eq!(*mutex.lock().unwrap(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn map_panic_unlocks_and_poisons() {
+        let mutex = Mutex::new(vec![1]);
+        let result = std::panic::catch_unwind(|| {
+            MutexGuard::map(mutex.lock().unwrap(), |v| &mut v[5]);
+        });
+        assert!(result.is_err());
+        assert!(mutex.is_poisoned());
+        assert_eq!(*mutex.lock().unwrap_err().into_inner(), [1]);
+
+        // and a mapped guard dropped by a panic poisons too
+        let mutex = Mutex::new(vec![1]);
+        let result = std::panic::catch_unwind(|| {
+            let mut first = MutexGuard::map(mutex.lock().unwrap(), |v| &mut v[0]);
+            *first = 2;
+            panic!("after the write");
+        });
+        assert!(result.is_err());
+        assert_eq!(*mutex.lock().unwrap_err().into_inner(), [2]);
+    }
+
+    #[test]
+    fn try_lock_while_held_elsewhere() {
+        let mutex = Arc::new(Mutex::new(1));
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let holder = thread::spawn({
+            let mutex = mutex.clone();
+            move || {
+                let mut guard = mutex.lock().unwrap();
+                locked_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+                *guard += 1;
+            }
+        });
+        locked_rx.recv().unwrap();
+        assert!(matches!(mutex.try_lock(), Err(TryLockError::WouldBlock)));
+        assert_eq!(
+            format!("{:?}", mutex),
+            "Mutex { data: <locked>, poisoned: false }"
+        );
+        release_tx.send(()).unwrap();
+        holder.join().unwrap();
+
+        let guard = mutex.try_lock().unwrap();
+        assert_eq!(*guard, 2);
+        // not reentrant either
+        assert!(matches!(mutex.try_lock(), Err(TryLockError::WouldBlock)));
+        drop(guard);
+        assert_eq!(format!("{:?}", mutex), "Mutex { data: 2, poisoned: false }");
+    }
+
+    #[test]
+    fn try_lock_poisoned() {
+        let mutex = Mutex::new(1);
+        let _ = std::panic::catch_unwind(|| {
+            let _guard = mutex.lock().unwrap();
+            panic!("poison it");
+        });
+        match mutex.try_lock() {
+            Err(TryLockError::Poisoned(err)) => assert_eq!(*err.into_inner(), 1),
+            other => panic!("expected Poisoned, got {:?}", other.map(|g| *g)),
+        }
+        assert_eq!(format!("{:?}", mutex), "Mutex { data: 1, poisoned: true }");
+    }
+
+    #[test]
+    fn into_inner_after_contention() {
+        let threads = if cfg!(miri) { 3 } else { 8 };
+        let mutex = Arc::new(Mutex::new(Vec::new()));
+        let handles: Vec<_> = (0..threads)
+            .map(|i| {
+                let mutex = mutex.clone();
+                thread::spawn(move || mutex.lock().unwrap().push(i))
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let mutex = Arc::try_unwrap(mutex).ok().unwrap();
+        let mut pushed = mutex.into_inner().unwrap();
+        pushed.sort_unstable();
+        assert_eq!(pushed, (0..threads).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn get_mut_then_lock() {
+        let mut mutex = Mutex::new(String::from("a"));
+        mutex.get_mut().unwrap().push('b');
+        assert_eq!(*mutex.lock().unwrap(), "ab");
+    }
+
+    #[test]
+    fn get_mut_unsized() {
+        // the lock doesn't care whether it's in front of a slice
+        let mut mutex: Box<Mutex<[i32]>> = Box::new(Mutex::new([1, 2, 3]));
+        mutex.get_mut().unwrap()[0] = 5;
+        mutex.lock().unwrap().reverse();
+        assert_eq!(*mutex.lock().unwrap(), [3, 2, 5]);
+        assert_eq!(mutex.get_mut().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn into_inner_drops_once() {
+        struct DropCount<'a>(&'a std::cell::Cell<usize>);
+
+        impl Drop for DropCount<'_> {
+            fn drop(&mut self) {
+                self.0.set(self.0.get() + 1);
+            }
+        }
+
+        let drops = std::cell::Cell::new(0);
+        let value = Mutex::new(DropCount(&drops)).into_inner().unwrap();
+        assert_eq!(drops.get(), 0);
+        drop(value);
+        assert_eq!(drops.get(), 1);
+        drop(Mutex::new(DropCount(&drops)));
+        assert_eq!(drops.get(), 2);
+    }
+
+    #[test]
+    fn poisoned_into_inner_and_get_mut() {
+        let mut mutex = Mutex::new(1);
+        let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut guard = mutex.lock().unwrap();
+            *guard = 2;
+            panic!("poison it");
+        }));
+        **mutex.get_mut().unwrap_err().get_mut() += 1;
+        assert_eq!(mutex.into_inner().unwrap_err().into_inner(), 3);
+    }
+
+    #[test]
+    fn unlocked_lets_others_in() {
+        let mutex = Arc::new(Mutex::new(vec![1]));
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        let other = thread::spawn({
+            let mutex = mutex.clone();
+            move || {
+                ready_rx.recv().unwrap();
+                mutex.lock().unwrap().push(2);
+                done_tx.send(()).unwrap();
+            }
+        });
+
+        let mut guard = mutex.lock().unwrap();
+        guard.push(10);
+        let waited = MutexGuard::unlocked(&mut guard, || {
+            ready_tx.send(()).unwrap();
+            // the other thread can only finish while this one isn't holding
+            // the lock
+            done_rx.recv().unwrap();
+            "waited"
+        });
+        assert_eq!(waited, "waited");
+        // locked again, and the other thread's push is there
+        assert_eq!(*guard, [1, 10, 2]);
+        assert!(matches!(mutex.try_lock(), Err(TryLockError::WouldBlock)));
+        drop(guard);
+        other.join().unwrap();
+    }
+
+    #[test]
+    fn unlocked_panic_relocks_and_poisons() {
+        let mutex = Mutex::new(1);
+        let result = std::panic::catch_unwind(|| {
+            let mut guard = mutex.lock().unwrap();
+            MutexGuard::unlocked(&mut guard, || {
+                // really unlocked in here
+                *mutex.try_lock().unwrap() += 1;
+                panic!("in the callout");
+            });
+        });
+        assert!(result.is_err());
+        // not left locked
+        let guard = mutex.lock().unwrap_err().into_inner();
+        assert_eq!(*guard, 2);
+    }
+
+    #[test]
+    fn try_lock_for_free() {
+        let mutex = Mutex::new(1);
+        *mutex.try_lock_for(Duration::from_secs(10)).unwrap() += 1;
+        // a deadline in the past still gets a free lock
+        let past = Instant::now();
+        assert_eq!(*mutex.try_lock_until(past).unwrap(), 2);
+        // and an absurd timeout just means no deadline
+        assert_eq!(*mutex.try_lock_for(Duration::MAX).unwrap(), 2);
+    }
+
+    #[test]
+    fn try_lock_for_times_out() {
+        let mutex = Arc::new(Mutex::new(()));
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let holder = thread::spawn({
+            let mutex = mutex.clone();
+            move || {
+                let _guard = mutex.lock().unwrap();
+                locked_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+            }
+        });
+        locked_rx.recv().unwrap();
+
+        let timeout = Duration::from_millis(50);
+        let start = Instant::now();
+        assert!(matches!(
+            mutex.try_lock_for(timeout),
+            Err(TryLockError::WouldBlock)
+        ));
+        let waited = start.elapsed();
+        assert!(waited >= timeout);
+        // generous, only here to catch waiting for the holder instead
+        if !cfg!(miri) {
+            assert!(waited < Duration::from_secs(5));
+        }
+        release_tx.send(()).unwrap();
+        holder.join().unwrap();
+        assert!(mutex.try_lock().is_ok());
+    }
+
+    #[test]
+    fn try_lock_for_gets_it_when_released_in_time() {
+        let mutex = Arc::new(Mutex::new(0));
+        let guard = mutex.lock().unwrap();
+        let waiter = thread::spawn({
+            let mutex = mutex.clone();
+            move || match mutex.try_lock_for(Duration::from_secs(30)) {
+                Ok(mut guard) => {
+                    *guard += 1;
+                    true
+                }
+                Err(_) => false,
+            }
+        });
+        thread::sleep(Duration::from_millis(20));
+        drop(guard);
+        assert!(waiter.join().unwrap());
+        assert_eq!(*mutex.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        fn assert_sync<T: Sync>() {}
+        assert_send_sync::<Mutex<i32>>();
+        // a Cell can't be shared, but a lock only ever lets one thread at it
+        assert_send_sync::<Mutex<std::cell::Cell<i32>>>();
+        // the guards can't be sent, see tests/ui
+        assert_sync::<MutexGuard<'_, i32>>();
+        assert_sync::<MappedMutexGuard<'_, i32>>();
+    }
+}
@@ -0,0 +1,278 @@
+// run something exactly once, however many threads ask at the same time
+//
+// the state goes INCOMPLETE -> RUNNING -> COMPLETE, with the thread that
+// moves it to RUNNING running the closure
+// threads that find it RUNNING mark it QUEUED and sleep, and the runner
+// wakes them all when it's done, but only has to go near the wait queue if
+// it finds QUEUED
+//
+// a closure that panics leaves the Once POISONED instead of COMPLETE, and
+// wakes the waiters, which then panic too, since what they waited for never
+// happened; call_once_force runs anyway, and can tell it's retrying
+//
+// orderings: COMPLETE is stored with Release and every check for it is
+// Acquire, so whatever the closure did happens before call_once returns on
+// any thread
+use super::wait_queue::WaitQueue;
+use std::fmt;
+use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+const INCOMPLETE: u32 = 0;
+const POISONED: u32 = 1;
+const RUNNING: u32 = 2;
+const QUEUED: u32 = 3;
+const COMPLETE: u32 = 4;
+
+pub struct Once {
+    state: AtomicU32,
+    waiters: WaitQueue,
+}
+
+impl UnwindSafe for Once {}
+impl RefUnwindSafe for Once {}
+
+/// passed to call_once_force's closure
+pub struct OnceState {
+    poisoned: bool,
+}
+
+impl OnceState {
+    /// whether an earlier closure panicked, so this one is the retry
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+}
+
+impl Once {
+    pub const fn new() -> Self {
+        Once {
+            state: AtomicU32::new(INCOMPLETE),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// run f, unless this Once has already run something, and return once
+    /// that's finished, whichever thread ran it
+    ///
+    /// panics if an earlier closure panicked
+    /// calling call_once again from inside f deadlocks
+    pub fn call_once<F: FnOnce()>(&self, f: F) {
+        if self.is_completed() {
+            return;
+        }
+        let mut f = Some(f);
+        self.call(false, &mut |_| f.take().unwrap()());
+    }
+
+    /// like call_once, but runs f even if an earlier closure panicked,
+    /// which state.is_poisoned() says
+    pub fn call_once_force<F: FnOnce(&OnceState)>(&self, f: F) {
+        if self.is_completed() {
+            return;
+        }
+        let mut f = Some(f);
+        self.call(true, &mut |state| f.take().unwrap()(state));
+    }
+
+    /// whether a closure has run to completion
+    /// true means its effects are visible to this thread
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+
+    // not generic, so only the closure above gets copied per call site
+    #[cold]
+    fn call(&self, ignore_poison: bool, f: &mut dyn FnMut(&OnceState)) {
+        let mut state = self.state.load(Ordering::Acquire);
+        loop {
+            match state {
+                POISONED if !ignore_poison => {
+                    panic!("Once poisoned: an earlier call_once panicked")
+                }
+                INCOMPLETE | POISONED => {
+                    if let Err(s) = self.state.compare_exchange_weak(
+                        state,
+                        RUNNING,
+                        Ordering::Acquire,
+                        Ordering::Acquire,
+                    ) {
+                        state = s;
+                        continue;
+                    }
+                    // poisons, unless f returns and it's set to COMPLETE
+                    let mut finish = Finish {
+                        once: self,
+                        set_state_to: POISONED,
+                    };
+                    f(&OnceState {
+                        poisoned: state == POISONED,
+                    });
+                    finish.set_state_to = COMPLETE;
+                    return;
+                }
+                RUNNING | QUEUED => {
+                    if state == RUNNING {
+                        if let Err(s) = self.state.compare_exchange_weak(
+                            RUNNING,
+                            QUEUED,
+                            Ordering::Relaxed,
+                            Ordering::Acquire,
+                        ) {
+                            state = s;
+                            continue;
+                        }
+                    }
+                    self.waiters
+                        .wait_until(|| self.state.load(Ordering::Relaxed) == QUEUED, None);
+                    state = self.state.load(Ordering::Acquire);
+                }
+                _ => {
+                    debug_assert_eq!(state, COMPLETE);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// sets the state when the closure is done, or unwinding out of it
+struct Finish<'a> {
+    once: &'a Once,
+    set_state_to: u32,
+}
+
+impl Drop for Finish<'_> {
+    fn drop(&mut self) {
+        // Release: the closure's effects happen before anyone sees COMPLETE
+        let state = self.once.state.swap(self.set_state_to, Ordering::Release);
+        if state == QUEUED {
+            self.once.waiters.notify_all();
+        }
+    }
+}
+
+impl fmt::Debug for Once {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Once")
+            .field("completed", &self.is_completed())
+            .finish()
+    }
+}
+
+impl Default for Once {
+    fn default() -> Self {
+        Once::new()
+    }
+}
+
+impl fmt::Debug for OnceState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OnceState")
+            .field("poisoned", &self.poisoned)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::Arc;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn runs_once() {
+        let once = Once::new();
+        let mut runs = 0;
+        once.call_once(|| runs += 1);
+        once.call_once(|| runs += 1);
+        assert_eq!(runs, 1);
+        assert!(once.is_completed());
+    }
+
+    #[test]
+    fn runs_once_across_threads() {
+        let threads = if cfg!(miri) { 4 } else { 16 };
+        let once = Arc::new(Once::new());
+        let runs = Arc::new(AtomicUsize::new(0));
+        // written Relaxed by the closure, so only the Once can be what makes
+        // it visible to the other threads
+        let value = Arc::new(AtomicUsize::new(0));
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                let (once, runs, value) = (once.clone(), runs.clone(), value.clone());
+                thread::spawn(move || {
+                    once.call_once(|| {
+                        runs.fetch_add(1, Ordering::Relaxed);
+                        // keep it running long enough for others to queue
+                        thread::sleep(Duration::from_millis(10));
+                        value.store(42, Ordering::Relaxed);
+                    });
+                    value.load(Ordering::Relaxed)
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), 42);
+        }
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn in_a_static() {
+        static ONCE: Once = Once::new();
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        for _ in 0..3 {
+            ONCE.call_once(|| {
+                RUNS.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+        assert_eq!(RUNS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn panic_poisons() {
+        let once = Once::new();
+        let result = panic::catch_unwind(|| once.call_once(|| panic!("first try")));
+        assert!(result.is_err());
+        assert!(!once.is_completed());
+        let result = panic::catch_unwind(|| once.call_once(|| {}));
+        assert!(result.is_err());
+
+        let mut retried = false;
+        once.call_once_force(|state| retried = state.is_poisoned());
+        assert!(retried);
+        assert!(once.is_completed());
+        once.call_once(|| unreachable!());
+    }
+
+    #[test]
+    fn waiters_see_the_panic() {
+        let once = Arc::new(Once::new());
+        let (running_tx, running_rx) = std::sync::mpsc::channel();
+        let runner = thread::spawn({
+            let once = once.clone();
+            move || {
+                once.call_once(|| {
+                    running_tx.send(()).unwrap();
+                    thread::sleep(Duration::from_millis(20));
+                    panic!("giving up");
+                })
+            }
+        });
+        running_rx.recv().unwrap();
+        // queued behind the runner, then woken by its panic
+        let waited = panic::catch_unwind(AssertUnwindSafe(|| once.call_once(|| {})));
+        assert!(waited.is_err());
+        assert!(runner.join().is_err());
+    }
+
+    #[test]
+    fn send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Once>();
+    }
+}
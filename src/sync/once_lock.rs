@@ -0,0 +1,266 @@
+// the thread safe counterpart to OnceCell: written at most once, by
+// whichever thread gets there first, and only read after that
+//
+// a Once decides which thread writes, and its Acquire/Release orderings
+// are what make the value visible to everyone who finds it completed
+use super::once::Once;
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::mem::MaybeUninit;
+use std::panic::{RefUnwindSafe, UnwindSafe};
+
+pub struct OnceLock<T> {
+    once: Once,
+    // initialized once once has completed, and not before
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// like Arc: the value is shared between threads once it's set, which needs
+// Sync, and can be set from one and dropped on another, which needs Send
+unsafe impl<T: Send + Sync> Sync for OnceLock<T> {}
+unsafe impl<T: Send> Send for OnceLock<T> {}
+
+impl<T: UnwindSafe> UnwindSafe for OnceLock<T> {}
+impl<T: RefUnwindSafe + UnwindSafe> RefUnwindSafe for OnceLock<T> {}
+
+impl<T> OnceLock<T> {
+    pub const fn new() -> Self {
+        OnceLock {
+            once: Once::new(),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// the value, if it's been set
+    /// doesn't wait for an initializer that's still running
+    pub fn get(&self) -> Option<&T> {
+        if self.once.is_completed() {
+            // completed means written, and never written again while shared
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        if self.once.is_completed() {
+            Some(unsafe { self.value.get_mut().assume_init_mut() })
+        } else {
+            None
+        }
+    }
+
+    /// set the value, or give it back if it's already set
+    /// waits for an initializer that's running on another thread, and only
+    /// gives value back if that one succeeded
+    pub fn set(&self, value: T) -> Result<(), T> {
+        let mut value = Some(value);
+        self.get_or_init(|| value.take().unwrap());
+        match value {
+            None => Ok(()),
+            Some(value) => Err(value),
+        }
+    }
+
+    /// the value, running f to set it first if it hasn't been
+    /// if several threads get here at once, one runs its f and the others
+    /// wait for it and get its value
+    ///
+    /// if f panics the lock stays empty, and the next caller runs its own
+    /// calling get_or_init again from inside f deadlocks
+    pub fn get_or_init<F: FnOnce() -> T>(&self, f: F) -> &T {
+        if let Some(value) = self.get() {
+            return value;
+        }
+        // force, since a poisoned Once only means an earlier f panicked
+        // without writing anything
+        self.once.call_once_force(|_| {
+            let value = f();
+            // only the thread the Once picked gets here, once
+            unsafe { (*self.value.get()).write(value) };
+        });
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+
+    /// the value, leaving the lock empty
+    pub fn take(&mut self) -> Option<T> {
+        if self.once.is_completed() {
+            self.once = Once::new();
+            // the reset Once keeps it from being read or dropped again
+            Some(unsafe { self.value.get_mut().assume_init_read() })
+        } else {
+            None
+        }
+    }
+
+    pub fn into_inner(mut self) -> Option<T> {
+        self.take()
+    }
+}
+
+impl<T> Drop for OnceLock<T> {
+    fn drop(&mut self) {
+        if self.once.is_completed() {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+impl<T> Default for OnceLock<T> {
+    fn default() -> Self {
+        OnceLock::new()
+    }
+}
+
+impl<T> From<T> for OnceLock<T> {
+    fn from(value: T) -> Self {
+        let lock = OnceLock::new();
+        let _ = lock.set(value);
+        lock
+    }
+}
+
+impl<T: Clone> Clone for OnceLock<T> {
+    fn clone(&self) -> Self {
+        let lock = OnceLock::new();
+        if let Some(value) = self.get() {
+            let _ = lock.set(value.clone());
+        }
+        lock
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for OnceLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_tuple("OnceLock");
+        match self.get() {
+            Some(value) => d.field(value),
+            None => d.field(&format_args!("<uninit>")),
+        };
+        d.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::Arc;
+    use std::panic;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+    use std::thread;
+
+    #[test]
+    fn set_and_get() {
+        let lock = OnceLock::new();
+        assert_eq!(lock.get(), None);
+        assert_eq!(lock.set(String::from("first")), Ok(()));
+        let first = lock.get().unwrap();
+        assert_eq!(
+            lock.set(String::from("second")),
+            Err(String::from("second"))
+        );
+        assert_eq!(*lock.get_or_init(|| unreachable!()), *first);
+        assert_eq!(format!("{:?}", lock), "OnceLock(\"first\")");
+    }
+
+    #[test]
+    fn racing_get_or_init() {
+        let threads = if cfg!(miri) { 4 } else { 16 };
+        let lock = Arc::new(OnceLock::new());
+        let inits = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(threads));
+        let handles: Vec<_> = (0..threads)
+            .map(|i| {
+                let (lock, inits, barrier) = (lock.clone(), inits.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    let value = lock.get_or_init(|| {
+                        inits.fetch_add(1, Ordering::Relaxed);
+                        vec![i; 100]
+                    });
+                    // whoever won, every thread sees all of its value
+                    assert!(value.iter().all(|&v| v == value[0]));
+                    (value[0], value as *const Vec<usize> as usize)
+                })
+            })
+            .collect();
+        let seen: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(inits.load(Ordering::Relaxed), 1);
+        assert!(seen.iter().all(|&s| s == seen[0]));
+    }
+
+    #[test]
+    fn racing_set() {
+        let threads = if cfg!(miri) { 4 } else { 16 };
+        let lock = Arc::new(OnceLock::new());
+        let barrier = Arc::new(Barrier::new(threads));
+        let handles: Vec<_> = (0..threads)
+            .map(|i| {
+                let (lock, barrier) = (lock.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    lock.set(i).is_ok()
+                })
+            })
+            .collect();
+        let won: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(won.iter().filter(|&&w| w).count(), 1);
+        assert_eq!(won.iter().position(|&w| w), lock.get().copied());
+    }
+
+    #[test]
+    fn panicking_init_leaves_it_empty() {
+        let lock = OnceLock::new();
+        let result = panic::catch_unwind(|| lock.get_or_init(|| panic!("no value")));
+        assert!(result.is_err());
+        assert_eq!(lock.get(), None);
+        assert_eq!(*lock.get_or_init(|| 2), 2);
+    }
+
+    #[test]
+    fn drops_once() {
+        let count = Arc::new(());
+        let lock = OnceLock::new();
+        assert!(lock.set(count.clone()).is_ok());
+        // the rejected one is handed back and dropped here
+        assert!(lock.set(count.clone()).is_err());
+        assert_eq!(Arc::strong_count(&count), 2);
+        drop(lock);
+        assert_eq!(Arc::strong_count(&count), 1);
+
+        // and not at all when it was never set
+        drop(OnceLock::<Arc<()>>::new());
+    }
+
+    #[test]
+    fn mut_access() {
+        let mut lock = OnceLock::from(vec![1]);
+        lock.get_mut().unwrap().push(2);
+        assert_eq!(lock.clone().into_inner(), Some(vec![1, 2]));
+        assert_eq!(lock.take(), Some(vec![1, 2]));
+        assert_eq!(lock.get(), None);
+        assert_eq!(lock.set(vec![3]), Ok(()));
+        assert_eq!(lock.into_inner(), Some(vec![3]));
+    }
+
+    #[test]
+    fn in_a_static() {
+        static LOCK: OnceLock<String> = OnceLock::new();
+        let handles: Vec<_> = (0..3)
+            .map(|_| thread::spawn(|| LOCK.get_or_init(|| String::from("static")).len()))
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), 6);
+        }
+    }
+
+    #[test]
+    fn send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        fn assert_send<T: Send>() {}
+        assert_send_sync::<OnceLock<i32>>();
+        // shared once it's set, so like Arc it needs Sync for that
+        assert_send::<OnceLock<std::cell::Cell<i32>>>();
+    }
+}
@@ -0,0 +1,157 @@
+// poisoning: a lock whose guard was dropped by a panic remembers it, since
+// the panic may have left the value halfway through an update, and every
+// later lock reports it as an error that still carries the guard, so the
+// caller can look at the value and decide
+//
+// a guard notes whether its thread was already panicking when it was made,
+// so locking from a destructor that runs during unwinding doesn't poison
+// anything by itself
+//
+// the flag is Relaxed: it's only set before an unlock and read after a
+// lock, whose orderings it rides on
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+/// what lock returns: the guard either way, wrapped in an error if the
+/// lock is poisoned
+pub type LockResult<G> = Result<G, PoisonError<G>>;
+
+/// a lock was poisoned by a panic, but here's its guard anyway
+pub struct PoisonError<G> {
+    guard: G,
+}
+
+impl<G> PoisonError<G> {
+    pub fn new(guard: G) -> Self {
+        PoisonError { guard }
+    }
+
+    /// the guard, to carry on as if the lock weren't poisoned
+    pub fn into_inner(self) -> G {
+        self.guard
+    }
+
+    pub fn get_ref(&self) -> &G {
+        &self.guard
+    }
+
+    pub fn get_mut(&mut self) -> &mut G {
+        &mut self.guard
+    }
+}
+
+// no G: Debug bound, so lock().unwrap() works for any value
+impl<G> fmt::Debug for PoisonError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PoisonError { .. }")
+    }
+}
+
+impl<G> fmt::Display for PoisonError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("poisoned lock: another thread panicked while holding it")
+    }
+}
+
+impl<G> Error for PoisonError<G> {}
+
+/// what try_lock returns
+pub type TryLockResult<G> = Result<G, TryLockError<G>>;
+
+/// why try_lock didn't give back a guard
+pub enum TryLockError<G> {
+    /// the lock is poisoned, but it was free, so here's the guard
+    Poisoned(PoisonError<G>),
+    /// someone else holds the lock
+    WouldBlock,
+}
+
+impl<G> From<PoisonError<G>> for TryLockError<G> {
+    fn from(err: PoisonError<G>) -> Self {
+        TryLockError::Poisoned(err)
+    }
+}
+
+impl<G> fmt::Debug for TryLockError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryLockError::Poisoned(err) => f.debug_tuple("Poisoned").field(err).finish(),
+            TryLockError::WouldBlock => f.write_str("WouldBlock"),
+        }
+    }
+}
+
+impl<G> fmt::Display for TryLockError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryLockError::Poisoned(err) => fmt::Display::fmt(err, f),
+            TryLockError::WouldBlock => f.write_str("try_lock failed because the lock is held"),
+        }
+    }
+}
+
+impl<G> Error for TryLockError<G> {}
+
+pub(crate) struct Flag {
+    poisoned: AtomicBool,
+}
+
+// kept in a guard, from when the lock was taken
+#[derive(Clone, Copy)]
+pub(crate) struct Guard {
+    panicking: bool,
+}
+
+impl Flag {
+    pub(crate) const fn new() -> Self {
+        Flag {
+            poisoned: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn get(&self) -> bool {
+        self.poisoned.load(Ordering::Relaxed)
+    }
+
+    // for into_inner and get_mut, which don't need an atomic load at all
+    pub(crate) fn get_mut(&mut self) -> bool {
+        *self.poisoned.get_mut()
+    }
+
+    pub(crate) fn clear(&self) {
+        self.poisoned.store(false, Ordering::Relaxed);
+    }
+
+    /// call with the lock held
+    pub(crate) fn guard(&self) -> Guard {
+        Guard {
+            panicking: thread::panicking(),
+        }
+    }
+
+    /// wrap a guard that was just made, depending on whether the lock is
+    /// poisoned
+    pub(crate) fn result<G>(&self, guard: G) -> LockResult<G> {
+        if self.get() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    pub(crate) fn try_result<G>(&self, guard: Option<G>) -> TryLockResult<G> {
+        match guard {
+            Some(guard) => Ok(self.result(guard)?),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// call with the lock still held, before unlocking
+    pub(crate) fn done(&self, guard: &Guard) {
+        if !guard.panicking && thread::panicking() {
+            self.poisoned.store(true, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,2097 @@
+// a lock with any number of readers or one writer, the thread safe
+// counterpart to RefCell
+//
+// the state is one word:
+// - the low bits count the readers, and all of them set means write locked
+// - UPGRADABLE, one of the readers holds an upgradable read
+// - UPGRADERS_WAITING, some upgradable reader may be asleep in upgraders
+// - READERS_WAITING, some plain reader may be asleep in readers
+// - WRITERS_WAITING, some writer may be asleep in writers
+// an uncontended read or write is one compare-exchange, and unlocking only
+// goes near the wait queues when one of the waiting bits is set
+//
+// the policy prefers writers: once a writer is waiting, new readers wait
+// too instead of joining the readers already in, so a steady stream of
+// readers can't keep a writer out forever
+// when the last holder unlocks it wakes one writer if there's one waiting,
+// and only wakes the readers if there isn't
+// the other way round, readers that are waiting get in as soon as no writer
+// is, so writers can hold readers off only for as long as writers keep
+// queueing up
+//
+// an upgradable read is a read that can turn into a write without letting
+// go in between, so only one is handed out at a time; the next ones sleep
+// in a queue of their own, so plain readers never wait behind them, and it
+// wakes them when it's dropped
+// upgrading marks a writer waiting, which keeps new readers out, and sleeps
+// in its own queue until the last other reader leaves; downgrading it to a
+// plain read frees the upgradable slot the same way dropping it does
+// the other way, a write lock downgrades to a read in one step, waking the
+// readers asleep unless a writer is waiting for them to stay behind
+//
+// a thread that already holds a read gets to read again even with a writer
+// waiting, since that writer is waiting for the very read the thread holds;
+// each thread keeps a list of the locks it holds a read of for that
+//
+// like Mutex, taking the lock either way is Acquire and releasing it is
+// Release, and a writer that panics poisons the lock; a reader can't have
+// changed anything, so a panicking reader doesn't
+//
+// the bit twiddling follows the futex based RwLock in std
+use super::poison::{self, LockResult, PoisonError, TryLockError, TryLockResult};
+use super::wait_queue::WaitQueue;
+use crate::refcell::RefCell;
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::hint;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+const READ_LOCKED: u32 = 1;
+const MASK: u32 = (1 << 28) - 1;
+const WRITE_LOCKED: u32 = MASK;
+const MAX_READERS: u32 = MASK - 1;
+const UPGRADABLE: u32 = 1 << 28;
+const UPGRADERS_WAITING: u32 = 1 << 29;
+const READERS_WAITING: u32 = 1 << 30;
+// either kind of reader
+const ANY_READERS_WAITING: u32 = READERS_WAITING | UPGRADERS_WAITING;
+const WRITERS_WAITING: u32 = 1 << 31;
+
+fn is_unlocked(state: u32) -> bool {
+    state & MASK == 0
+}
+
+fn is_write_locked(state: u32) -> bool {
+    state & MASK == WRITE_LOCKED
+}
+
+fn has_upgradable(state: u32) -> bool {
+    state & UPGRADABLE != 0
+}
+
+fn has_readers_waiting(state: u32) -> bool {
+    state & READERS_WAITING != 0
+}
+
+fn has_writers_waiting(state: u32) -> bool {
+    state & WRITERS_WAITING != 0
+}
+
+fn has_waiters(state: u32) -> bool {
+    state & (ANY_READERS_WAITING | WRITERS_WAITING) != 0
+}
+
+// new readers also stay out while a writer or plain reader is waiting,
+// which is what puts waiting writers first
+// upgradable readers waiting don't count: they only wait for each other
+fn is_read_lockable(state: u32) -> bool {
+    state & MASK < MAX_READERS && !has_readers_waiting(state) && !has_writers_waiting(state)
+}
+
+// locked is what a reader adds to the state, with UPGRADABLE for an
+// upgradable read, which also has to wait for the one already handed out
+fn is_lockable_as(state: u32, locked: u32) -> bool {
+    is_read_lockable(state) && state & locked & UPGRADABLE == 0
+}
+
+thread_local! {
+    // the locks this thread holds a read of, once for each read
+    // a lock whose guard was forgotten stays in here, which only lets this
+    // thread in past writers waiting on a lock that's read locked for good
+    static READS_HELD: RefCell<Vec<*const RawRwLock>> = RefCell::new(Vec::new());
+}
+
+pub struct RwLock<T: ?Sized> {
+    raw: RawRwLock,
+    value: UnsafeCell<T>,
+}
+
+struct RawRwLock {
+    state: AtomicU32,
+    readers: WaitQueue,
+    writers: WaitQueue,
+    // upgradable readers waiting for the one handed out
+    upgraders: WaitQueue,
+    // the upgradable reader, while it waits to upgrade
+    upgrader: WaitQueue,
+    poison: poison::Flag,
+}
+
+// a writer gets &mut T, which needs Send like Mutex, and readers on several
+// threads share &T at once, which needs Sync
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T: ?Sized> UnwindSafe for RwLock<T> {}
+impl<T: ?Sized> RefUnwindSafe for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock {
+            raw: RawRwLock {
+                state: AtomicU32::new(0),
+                readers: WaitQueue::new(),
+                writers: WaitQueue::new(),
+                upgraders: WaitQueue::new(),
+                upgrader: WaitQueue::new(),
+                poison: poison::Flag::new(),
+            },
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// the value, without locking since no guard can outlive the RwLock
+    /// Err if it was poisoned, with the value inside it
+    pub fn into_inner(mut self) -> LockResult<T> {
+        let poisoned = self.raw.poison.get_mut();
+        let value = self.value.into_inner();
+        if poisoned {
+            Err(PoisonError::new(value))
+        } else {
+            Ok(value)
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// wait until no writer holds or is waiting for the lock, and return a
+    /// guard shared with any other readers
+    /// Err if a writer panicked while holding it, with the guard inside
+    ///
+    /// reading again on the same thread while holding a read guard gets in
+    /// straight away, even past a waiting writer, which would otherwise be
+    /// waiting on this thread while this thread waits on it
+    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, T>> {
+        self.raw.read();
+        self.raw.poison.result(RwLockReadGuard {
+            rwlock: self,
+            _marker: PhantomData,
+        })
+    }
+
+    /// wait until nobody else holds the lock, and return the only guard
+    /// Err if a writer panicked while holding it, with the guard inside
+    pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, T>> {
+        self.raw.write();
+        self.raw.poison.result(RwLockWriteGuard {
+            rwlock: self,
+            poison: self.raw.poison.guard(),
+            _marker: PhantomData,
+        })
+    }
+
+    /// a read guard that can later be upgraded to a write guard without
+    /// letting go of the lock in between, so whatever was read still holds
+    /// when writing
+    /// only one upgradable read is handed out at a time, and the next one
+    /// waits, but plain readers can still share the lock with it
+    /// Err if a writer panicked while holding it, with the guard inside
+    pub fn upgradable_read(&self) -> LockResult<RwLockUpgradableReadGuard<'_, T>> {
+        self.raw.upgradable_read();
+        self.raw.poison.result(RwLockUpgradableReadGuard {
+            rwlock: self,
+            _marker: PhantomData,
+        })
+    }
+
+    /// a read guard if one can be had right now, without waiting
+    /// Err(WouldBlock) while a writer holds the lock or is waiting for it
+    pub fn try_read(&self) -> TryLockResult<RwLockReadGuard<'_, T>> {
+        let locked = self.raw.try_read().then(|| RwLockReadGuard {
+            rwlock: self,
+            _marker: PhantomData,
+        });
+        self.raw.poison.try_result(locked)
+    }
+
+    /// a write guard if nobody holds the lock right now, without waiting
+    /// Err(WouldBlock) while anyone holds it
+    pub fn try_write(&self) -> TryLockResult<RwLockWriteGuard<'_, T>> {
+        let locked = self.raw.try_write().then(|| RwLockWriteGuard {
+            rwlock: self,
+            poison: self.raw.poison.guard(),
+            _marker: PhantomData,
+        });
+        self.raw.poison.try_result(locked)
+    }
+
+    /// read, but give up after timeout with Err(WouldBlock)
+    /// a timeout too far out to represent waits forever
+    pub fn try_read_for(&self, timeout: Duration) -> TryLockResult<RwLockReadGuard<'_, T>> {
+        self.read_with_deadline(Instant::now().checked_add(timeout))
+    }
+
+    /// read, but give up at deadline with Err(WouldBlock)
+    pub fn try_read_until(&self, deadline: Instant) -> TryLockResult<RwLockReadGuard<'_, T>> {
+        self.read_with_deadline(Some(deadline))
+    }
+
+    /// write, but give up after timeout with Err(WouldBlock)
+    /// a timeout too far out to represent waits forever
+    pub fn try_write_for(&self, timeout: Duration) -> TryLockResult<RwLockWriteGuard<'_, T>> {
+        self.write_with_deadline(Instant::now().checked_add(timeout))
+    }
+
+    /// write, but give up at deadline with Err(WouldBlock)
+    pub fn try_write_until(&self, deadline: Instant) -> TryLockResult<RwLockWriteGuard<'_, T>> {
+        self.write_with_deadline(Some(deadline))
+    }
+
+    fn read_with_deadline(
+        &self,
+        deadline: Option<Instant>,
+    ) -> TryLockResult<RwLockReadGuard<'_, T>> {
+        let locked =
+            (self.raw.try_read() || self.raw.read_contended(READ_LOCKED, deadline)).then(|| {
+                RwLockReadGuard {
+                    rwlock: self,
+                    _marker: PhantomData,
+                }
+            });
+        self.raw.poison.try_result(locked)
+    }
+
+    fn write_with_deadline(
+        &self,
+        deadline: Option<Instant>,
+    ) -> TryLockResult<RwLockWriteGuard<'_, T>> {
+        let locked = (self.raw.try_write() || self.raw.write_contended(deadline)).then(|| {
+            RwLockWriteGuard {
+                rwlock: self,
+                poison: self.raw.poison.guard(),
+                _marker: PhantomData,
+            }
+        });
+        self.raw.poison.try_result(locked)
+    }
+
+    /// the value, without locking since &mut self means no guard exists
+    /// Err if it was poisoned, like into_inner
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        let value = self.value.get_mut();
+        if self.raw.poison.get_mut() {
+            Err(PoisonError::new(value))
+        } else {
+            Ok(value)
+        }
+    }
+
+    /// whether a writer panicked while holding the lock
+    pub fn is_poisoned(&self) -> bool {
+        self.raw.poison.get()
+    }
+
+    /// forget that the lock was poisoned
+    pub fn clear_poison(&self) {
+        self.raw.poison.clear();
+    }
+}
+
+impl RawRwLock {
+    // these retry the compare-exchange while the lock could still be had,
+    // so another reader getting in at the same moment isn't a WouldBlock
+    // unlike read, a thread that already holds a read is still turned away
+    // by a waiting writer: not waiting can't deadlock
+    fn try_read(&self) -> bool {
+        let locked = self
+            .state
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |state| {
+                is_read_lockable(state).then(|| state + READ_LOCKED)
+            })
+            .is_ok();
+        if locked {
+            self.hold_read();
+        }
+        locked
+    }
+
+    // an unlocked lock with waiters can be taken, the waiters would just
+    // find it taken again when they wake
+    fn try_write(&self) -> bool {
+        self.state
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |state| {
+                is_unlocked(state).then(|| state + WRITE_LOCKED)
+            })
+            .is_ok()
+    }
+
+    fn read(&self) {
+        let state = self.state.load(Ordering::Relaxed);
+        if !is_read_lockable(state)
+            || self
+                .state
+                .compare_exchange_weak(
+                    state,
+                    state + READ_LOCKED,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                )
+                .is_err()
+        {
+            self.read_contended(READ_LOCKED, None);
+        } else {
+            self.hold_read();
+        }
+    }
+
+    // false if the deadline passed first
+    #[cold]
+    fn read_contended(&self, locked: u32, deadline: Option<Instant>) -> bool {
+        // a read this thread already holds keeps any writer out, so another
+        // one only needs room in the count
+        // the write lock is still checked in case that read was forgotten
+        // and the lock since reused
+        let again = locked == READ_LOCKED && self.holds_read();
+        let lockable = |state| {
+            is_lockable_as(state, locked)
+                || again && state & MASK < MAX_READERS && !is_write_locked(state)
+        };
+        // upgradable readers sleep apart, so a plain reader never waits
+        // behind one that's only waiting for its turn at UPGRADABLE
+        let (waiting, queue) = if locked & UPGRADABLE != 0 {
+            (UPGRADERS_WAITING, &self.upgraders)
+        } else {
+            (READERS_WAITING, &self.readers)
+        };
+        let mut state = self.spin_read();
+        loop {
+            if lockable(state) {
+                match self.state.compare_exchange_weak(
+                    state,
+                    state + locked,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        self.hold_read();
+                        return true;
+                    }
+                    Err(s) => {
+                        state = s;
+                        continue;
+                    }
+                }
+            }
+            if state & MASK == MAX_READERS {
+                panic!("too many readers of an RwLock");
+            }
+            // tell the unlockers someone's asleep before going to sleep
+            if state & waiting == 0 {
+                if let Err(s) = self.state.compare_exchange(
+                    state,
+                    state | waiting,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    state = s;
+                    continue;
+                }
+            }
+            let expected = state | waiting;
+            // sleep only if nothing changed since, anything that did may
+            // have been the wakeup
+            // giving up leaves the waiting bit set, which only costs the
+            // next unlock a look at the queue
+            let woken =
+                queue.wait_until(|| self.state.load(Ordering::Relaxed) == expected, deadline);
+            if !woken {
+                return false;
+            }
+            state = self.spin_read();
+        }
+    }
+
+    fn write(&self) {
+        if self
+            .state
+            .compare_exchange_weak(0, WRITE_LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            self.write_contended(None);
+        }
+    }
+
+    #[cold]
+    fn write_contended(&self, deadline: Option<Instant>) -> bool {
+        let mut state = self.spin_write();
+        // once this writer has slept, others may be asleep too, and it can't
+        // tell, so it keeps WRITERS_WAITING set when it gets the lock
+        let mut other_writers_waiting = 0;
+        loop {
+            if is_unlocked(state) {
+                match self.state.compare_exchange_weak(
+                    state,
+                    state | WRITE_LOCKED | other_writers_waiting,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return true,
+                    Err(s) => {
+                        state = s;
+                        continue;
+                    }
+                }
+            }
+            if !has_writers_waiting(state) {
+                if let Err(s) = self.state.compare_exchange(
+                    state,
+                    state | WRITERS_WAITING,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    state = s;
+                    continue;
+                }
+            }
+            // the unlocker clears WRITERS_WAITING before waking a writer, so
+            // a cleared bit means a wakeup may be on its way to nobody
+            // giving up leaves WRITERS_WAITING set, and the unlocker that
+            // finds no writer asleep wakes the readers instead
+            let woken = self.writers.wait_until(
+                || {
+                    let state = self.state.load(Ordering::Relaxed);
+                    !is_unlocked(state) && has_writers_waiting(state)
+                },
+                deadline,
+            );
+            if !woken {
+                return false;
+            }
+            other_writers_waiting = WRITERS_WAITING;
+            state = self.spin_write();
+        }
+    }
+
+    fn read_unlock(&self) {
+        self.release_read();
+        let state = self.state.fetch_sub(READ_LOCKED, Ordering::Release) - READ_LOCKED;
+        // plain readers only ever wait behind writers, which keep new
+        // readers out, and upgradable ones are woken by the upgradable read
+        // they wait for, so the last reader out only has writers to wake
+        if has_writers_waiting(state) {
+            if is_unlocked(state) {
+                self.wake_writer_or_readers(state);
+            } else if state & MASK == READ_LOCKED && has_upgradable(state) {
+                // all that's left is the upgradable read, which may be
+                // waiting to upgrade
+                self.upgrader.notify_one();
+            }
+        }
+    }
+
+    fn upgradable_read(&self) {
+        let state = self.state.load(Ordering::Relaxed);
+        let locked = READ_LOCKED | UPGRADABLE;
+        if !is_lockable_as(state, locked)
+            || self
+                .state
+                .compare_exchange_weak(state, state + locked, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+        {
+            self.read_contended(locked, None);
+        } else {
+            self.hold_read();
+        }
+    }
+
+    fn upgradable_unlock(&self) {
+        self.release_read();
+        let locked = READ_LOCKED | UPGRADABLE;
+        let state = self.state.fetch_sub(locked, Ordering::Release) - locked;
+        if is_unlocked(state) {
+            if has_waiters(state) {
+                self.wake_writer_or_readers(state);
+            }
+        } else {
+            // the upgradable readers asleep were waiting for this one
+            self.wake_readers(state);
+        }
+    }
+
+    // upgrade, if there are no other readers to wait for
+    fn try_upgrade(&self) -> bool {
+        let upgraded = self
+            .state
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |state| {
+                (state & MASK == READ_LOCKED)
+                    .then(|| state - READ_LOCKED - UPGRADABLE + WRITE_LOCKED)
+            })
+            .is_ok();
+        if upgraded {
+            self.release_read();
+        }
+        upgraded
+    }
+
+    // give up the upgradable part of an upgradable read, keeping the read
+    fn upgradable_downgrade(&self) {
+        // Relaxed: nothing was written under an upgradable read
+        let state = self.state.fetch_sub(UPGRADABLE, Ordering::Relaxed) - UPGRADABLE;
+        self.wake_readers(state);
+    }
+
+    // turn the write lock into a read lock, without unlocking in between
+    fn downgrade(&self, poison: &poison::Guard) {
+        self.poison.done(poison);
+        let state = self
+            .state
+            .fetch_sub(WRITE_LOCKED - READ_LOCKED, Ordering::Release)
+            - (WRITE_LOCKED - READ_LOCKED);
+        self.hold_read();
+        // the readers that were waiting behind the write can join in
+        self.wake_readers(state);
+    }
+
+    // with the lock read locked, let the readers asleep in, unless there's a
+    // writer for them to keep waiting behind
+    fn wake_readers(&self, state: u32) {
+        if state & ANY_READERS_WAITING != 0 && !has_writers_waiting(state) {
+            let waiting = self
+                .state
+                .fetch_and(!ANY_READERS_WAITING, Ordering::Relaxed);
+            self.notify_readers(waiting);
+        }
+    }
+
+    // wake every reader whose waiting bit was just cleared from state
+    // all the upgradable ones too, though only one of them gets in and the
+    // rest go back to sleep
+    fn notify_readers(&self, state: u32) {
+        if has_readers_waiting(state) {
+            self.readers.notify_all();
+        }
+        if state & UPGRADERS_WAITING != 0 {
+            self.upgraders.notify_all();
+        }
+    }
+
+    // turn the upgradable read into the write lock, once the other readers
+    // are gone
+    fn upgrade(&self) {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & MASK == READ_LOCKED {
+                // Acquire: the other readers' unlocks were Release, like a
+                // plain write
+                match self.state.compare_exchange_weak(
+                    state,
+                    state - READ_LOCKED - UPGRADABLE + WRITE_LOCKED,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    // WRITERS_WAITING stays set if this set it, the unlock
+                    // sorts that out like after any writer that slept
+                    Ok(_) => {
+                        self.release_read();
+                        return;
+                    }
+                    Err(s) => {
+                        state = s;
+                        continue;
+                    }
+                }
+            }
+            if !has_writers_waiting(state) {
+                if let Err(s) = self.state.compare_exchange(
+                    state,
+                    state | WRITERS_WAITING,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    state = s;
+                    continue;
+                }
+            }
+            // nothing clears WRITERS_WAITING while this read is held, so the
+            // last other reader out is sure to look at the queue
+            self.upgrader.wait_until(
+                || self.state.load(Ordering::Relaxed) & MASK != READ_LOCKED,
+                None,
+            );
+            state = self.state.load(Ordering::Relaxed);
+        }
+    }
+
+    fn write_unlock(&self, poison: &poison::Guard) {
+        self.poison.done(poison);
+        let state = self.state.fetch_sub(WRITE_LOCKED, Ordering::Release) - WRITE_LOCKED;
+        if has_waiters(state) {
+            self.wake_writer_or_readers(state);
+        }
+    }
+
+    // called by the last one out when someone's waiting
+    #[cold]
+    fn wake_writer_or_readers(&self, mut state: u32) {
+        debug_assert!(is_unlocked(state));
+        // only writers waiting: wake one
+        if state == WRITERS_WAITING {
+            match self
+                .state
+                .compare_exchange(state, 0, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => {
+                    // if the writer that set the bit isn't asleep yet, it
+                    // sees the lock is free before it would sleep
+                    self.writers.notify_one();
+                    return;
+                }
+                // new waiters showed up, or the lock was taken
+                Err(s) => state = s,
+            }
+        }
+        // both: the writer goes first, readers keep waiting behind it
+        let readers = state & ANY_READERS_WAITING;
+        if readers != 0 && state == readers | WRITERS_WAITING {
+            if self
+                .state
+                .compare_exchange(state, readers, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+            {
+                // the lock was taken in the meantime, the new holder will
+                // do this when it unlocks
+                return;
+            }
+            if self.writers.notify_one() {
+                return;
+            }
+            // no writer was actually asleep, so don't leave the readers
+            // hanging on one that may never come
+            state = readers;
+        }
+        // only readers waiting: wake all of them
+        if readers != 0
+            && state == readers
+            && self
+                .state
+                .compare_exchange(state, 0, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.notify_readers(state);
+        }
+    }
+
+    // spin a little while a writer holds the lock and nobody's asleep,
+    // returning the state last seen
+    fn spin_until(&self, mut stop: impl FnMut(u32) -> bool) -> u32 {
+        let mut spins = 100;
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if stop(state) || spins == 0 {
+                return state;
+            }
+            hint::spin_loop();
+            spins -= 1;
+        }
+    }
+
+    // note a read of this lock as held by this thread
+    // the list may already be gone during thread shutdown, and then reading
+    // again past a waiting writer just isn't an option any more
+    fn hold_read(&self) {
+        let _ = READS_HELD.try_with(|held| {
+            held.borrow_mut()
+                .expect("never borrowed across calls")
+                .push(self)
+        });
+    }
+
+    fn release_read(&self) {
+        let _ = READS_HELD.try_with(|held| {
+            let mut held = held.borrow_mut().expect("never borrowed across calls");
+            if let Some(i) = held.iter().rposition(|&lock| std::ptr::eq(lock, self)) {
+                held.swap_remove(i);
+            }
+        });
+    }
+
+    fn holds_read(&self) -> bool {
+        READS_HELD
+            .try_with(|held| {
+                held.borrow()
+                    .expect("never borrowed mutably across calls")
+                    .contains(&(self as *const _))
+            })
+            .unwrap_or(false)
+    }
+
+    fn spin_read(&self) -> u32 {
+        // readers only spin behind a writer, more readers won't leave soon
+        self.spin_until(|state| !is_write_locked(state) || has_waiters(state))
+    }
+
+    fn spin_write(&self) -> u32 {
+        self.spin_until(|state| is_unlocked(state) || has_writers_waiting(state))
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("RwLock");
+        // like Mutex, never waits
+        match self.try_read() {
+            Ok(guard) => d.field("data", &&*guard),
+            Err(TryLockError::Poisoned(err)) => d.field("data", &&**err.get_ref()),
+            Err(TryLockError::WouldBlock) => d.field("data", &format_args!("<locked>")),
+        };
+        d.field("poisoned", &self.is_poisoned()).finish()
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> Self {
+        RwLock::new(T::default())
+    }
+}
+
+impl<T> From<T> for RwLock<T> {
+    fn from(value: T) -> Self {
+        RwLock::new(value)
+    }
+}
+
+/// shared access to an RwLock's value, giving up its share when dropped
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    rwlock: &'a RwLock<T>,
+    // !Send like std's, though nothing here depends on the thread a read
+    // guard is dropped on; Sync like &T
+    _marker: PhantomData<(&'a T, *const ())>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockReadGuard<'_, T> {}
+
+impl<'a, T: ?Sized> RwLockReadGuard<'a, T> {
+    /// narrow the guard down to part of the value, keeping the read lock
+    /// until the new guard is dropped
+    pub fn map<U: ?Sized, F: FnOnce(&T) -> &U>(this: Self, f: F) -> MappedRwLockReadGuard<'a, U> {
+        let value = NonNull::from(f(&*this));
+        // the read lock is the new guard's to give up now
+        let this = ManuallyDrop::new(this);
+        MappedRwLockReadGuard {
+            raw: &this.rwlock.raw,
+            value,
+            _marker: PhantomData,
+        }
+    }
+
+    /// map, if f finds the part it's looking for, otherwise the guard back
+    /// unchanged
+    pub fn try_map<U: ?Sized, F: FnOnce(&T) -> Option<&U>>(
+        this: Self,
+        f: F,
+    ) -> Result<MappedRwLockReadGuard<'a, U>, Self> {
+        match f(&*this).map(NonNull::from) {
+            Some(value) => {
+                let this = ManuallyDrop::new(this);
+                Ok(MappedRwLockReadGuard {
+                    raw: &this.rwlock.raw,
+                    value,
+                    _marker: PhantomData,
+                })
+            }
+            None => Err(this),
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // no writer can get in while this guard is around
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.rwlock.raw.read_unlock();
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLockReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for RwLockReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+/// shared access to an RwLock's value, with the option to upgrade to
+/// exclusive access
+pub struct RwLockUpgradableReadGuard<'a, T: ?Sized> {
+    rwlock: &'a RwLock<T>,
+    // like RwLockReadGuard
+    _marker: PhantomData<(&'a T, *const ())>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockUpgradableReadGuard<'_, T> {}
+
+impl<'a, T: ?Sized> RwLockUpgradableReadGuard<'a, T> {
+    /// wait for the other readers to leave and take the write lock, without
+    /// letting any writer in first
+    /// new readers wait meanwhile, like behind any waiting writer
+    pub fn upgrade(this: Self) -> RwLockWriteGuard<'a, T> {
+        let this = ManuallyDrop::new(this);
+        this.rwlock.raw.upgrade();
+        // no writer can have held the lock since this guard's was checked
+        RwLockWriteGuard {
+            rwlock: this.rwlock,
+            poison: this.rwlock.raw.poison.guard(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// upgrade if no other readers are in right now, otherwise the guard
+    /// back unchanged
+    pub fn try_upgrade(this: Self) -> Result<RwLockWriteGuard<'a, T>, Self> {
+        if !this.rwlock.raw.try_upgrade() {
+            return Err(this);
+        }
+        let this = ManuallyDrop::new(this);
+        Ok(RwLockWriteGuard {
+            rwlock: this.rwlock,
+            poison: this.rwlock.raw.poison.guard(),
+            _marker: PhantomData,
+        })
+    }
+
+    /// give up the option to upgrade and keep reading, letting the next
+    /// upgradable read in
+    pub fn downgrade(this: Self) -> RwLockReadGuard<'a, T> {
+        let this = ManuallyDrop::new(this);
+        this.rwlock.raw.upgradable_downgrade();
+        RwLockReadGuard {
+            rwlock: this.rwlock,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockUpgradableReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // it's a read lock until it's upgraded
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockUpgradableReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.rwlock.raw.upgradable_unlock();
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLockUpgradableReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for RwLockUpgradableReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+/// exclusive access to an RwLock's value, unlocking it when dropped
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    rwlock: &'a RwLock<T>,
+    poison: poison::Guard,
+    // like MutexGuard
+    _marker: PhantomData<(&'a mut T, *const ())>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockWriteGuard<'_, T> {}
+
+impl<'a, T: ?Sized> RwLockWriteGuard<'a, T> {
+    /// narrow the guard down to part of the value, keeping the write lock
+    /// until the new guard is dropped
+    /// if f panics, the lock is unlocked (and poisoned) as usual
+    pub fn map<U: ?Sized, F: FnOnce(&mut T) -> &mut U>(
+        this: Self,
+        f: F,
+    ) -> MappedRwLockWriteGuard<'a, U> {
+        // this guard holds the lock alone, so the value is ours to lend out
+        let value = NonNull::from(f(unsafe { &mut *this.rwlock.value.get() }));
+        let this = ManuallyDrop::new(this);
+        MappedRwLockWriteGuard {
+            raw: &this.rwlock.raw,
+            poison: this.poison,
+            value,
+            _marker: PhantomData,
+        }
+    }
+
+    /// map, if f finds the part it's looking for, otherwise the guard back
+    /// unchanged
+    pub fn try_map<U: ?Sized, F: FnOnce(&mut T) -> Option<&mut U>>(
+        this: Self,
+        f: F,
+    ) -> Result<MappedRwLockWriteGuard<'a, U>, Self> {
+        match f(unsafe { &mut *this.rwlock.value.get() }).map(NonNull::from) {
+            Some(value) => {
+                let this = ManuallyDrop::new(this);
+                Ok(MappedRwLockWriteGuard {
+                    raw: &this.rwlock.raw,
+                    poison: this.poison,
+                    value,
+                    _marker: PhantomData,
+                })
+            }
+            None => Err(this),
+        }
+    }
+}
+
+impl<'a, T: ?Sized> RwLockWriteGuard<'a, T> {
+    /// turn the write guard into a read guard, without letting a writer in
+    /// between, so what was just written is what gets read
+    /// readers that were waiting get in alongside it, unless a writer is
+    /// waiting too
+    pub fn downgrade(this: Self) -> RwLockReadGuard<'a, T> {
+        let this = ManuallyDrop::new(this);
+        this.rwlock.raw.downgrade(&this.poison);
+        RwLockReadGuard {
+            rwlock: this.rwlock,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // this guard holds the lock alone
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.rwlock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.rwlock.raw.write_unlock(&self.poison);
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLockWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for RwLockWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+/// an RwLockReadGuard narrowed down by map to part of the value
+pub struct MappedRwLockReadGuard<'a, T: ?Sized> {
+    raw: &'a RawRwLock,
+    value: NonNull<T>,
+    // like RwLockReadGuard
+    _marker: PhantomData<(&'a T, *const ())>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for MappedRwLockReadGuard<'_, T> {}
+
+impl<'a, T: ?Sized> MappedRwLockReadGuard<'a, T> {
+    /// narrow the guard down further
+    pub fn map<U: ?Sized, F: FnOnce(&T) -> &U>(this: Self, f: F) -> MappedRwLockReadGuard<'a, U> {
+        let value = NonNull::from(f(&*this));
+        let this = ManuallyDrop::new(this);
+        MappedRwLockReadGuard {
+            raw: this.raw,
+            value,
+            _marker: PhantomData,
+        }
+    }
+
+    /// map further, if f finds the part it's looking for
+    pub fn try_map<U: ?Sized, F: FnOnce(&T) -> Option<&U>>(
+        this: Self,
+        f: F,
+    ) -> Result<MappedRwLockReadGuard<'a, U>, Self> {
+        match f(&*this).map(NonNull::from) {
+            Some(value) => {
+                let this = ManuallyDrop::new(this);
+                Ok(MappedRwLockReadGuard {
+                    raw: this.raw,
+                    value,
+                    _marker: PhantomData,
+                })
+            }
+            None => Err(this),
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for MappedRwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // value came from a & handed out under the read lock this still holds
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<T: ?Sized> Drop for MappedRwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.raw.read_unlock();
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for MappedRwLockReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for MappedRwLockReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+/// an RwLockWriteGuard narrowed down by map to part of the value
+pub struct MappedRwLockWriteGuard<'a, T: ?Sized> {
+    raw: &'a RawRwLock,
+    poison: poison::Guard,
+    value: NonNull<T>,
+    // like RwLockWriteGuard
+    _marker: PhantomData<(&'a mut T, *const ())>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for MappedRwLockWriteGuard<'_, T> {}
+
+impl<'a, T: ?Sized> MappedRwLockWriteGuard<'a, T> {
+    /// narrow the guard down further
+    pub fn map<U: ?Sized, F: FnOnce(&mut T) -> &mut U>(
+        mut this: Self,
+        f: F,
+    ) -> MappedRwLockWriteGuard<'a, U> {
+        let value = NonNull::from(f(&mut *this));
+        let this = ManuallyDrop::new(this);
+        MappedRwLockWriteGuard {
+            raw: this.raw,
+            poison: this.poison,
+            value,
+            _marker: PhantomData,
+        }
+    }
+
+    /// map further, if f finds the part it's looking for
+    pub fn try_map<U: ?Sized, F: FnOnce(&mut T) -> Option<&mut U>>(
+        mut this: Self,
+        f: F,
+    ) -> Result<MappedRwLockWriteGuard<'a, U>, Self> {
+        match f(&mut *this).map(NonNull::from) {
+            Some(value) => {
+                let this = ManuallyDrop::new(this);
+                Ok(MappedRwLockWriteGuard {
+                    raw: this.raw,
+                    poison: this.poison,
+                    value,
+                    _marker: PhantomData,
+                })
+            }
+            None => Err(this),
+        }
+    }
+
+    /// like RwLockWriteGuard::downgrade, keeping to the same part
+    pub fn downgrade(this: Self) -> MappedRwLockReadGuard<'a, T> {
+        let this = ManuallyDrop::new(this);
+        this.raw.downgrade(&this.poison);
+        MappedRwLockReadGuard {
+            raw: this.raw,
+            value: this.value,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for MappedRwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // value came from a &mut handed out under the write lock this still
+        // holds
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MappedRwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.value.as_mut() }
+    }
+}
+
+impl<T: ?Sized> Drop for MappedRwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.raw.write_unlock(&self.poison);
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for MappedRwLockWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for MappedRwLockWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn readers_share_writers_exclude() {
+        let lock = RwLock::new(vec![1]);
+        {
+            let a = lock.read().unwrap();
+            let b = lock.read().unwrap();
+            assert_eq!(*a, *b);
+            assert_eq!(lock.raw.state.load(Ordering::Relaxed), 2 * READ_LOCKED);
+        }
+        lock.write().unwrap().push(2);
+        assert_eq!(*lock.read().unwrap(), [1, 2]);
+        assert_eq!(lock.raw.state.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn concurrent_readers_and_a_writer() {
+        // the writer keeps the two halves equal, so a reader that ever sees
+        // them differ saw a write in progress
+        let threads = if cfg!(miri) { 3 } else { 8 };
+        let rounds = if cfg!(miri) { 20 } else { 2_000 };
+        let lock = Arc::new(RwLock::new((0u64, 0u64)));
+        let readers: Vec<_> = (0..threads)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for _ in 0..rounds {
+                        let guard = lock.read().unwrap();
+                        assert_eq!(guard.0, guard.1);
+                    }
+                })
+            })
+            .collect();
+        let writer = thread::spawn({
+            let lock = lock.clone();
+            move || {
+                for _ in 0..rounds {
+                    let mut guard = lock.write().unwrap();
+                    guard.0 += 1;
+                    thread::yield_now();
+                    guard.1 += 1;
+                }
+            }
+        });
+        for handle in readers {
+            handle.join().unwrap();
+        }
+        writer.join().unwrap();
+        assert_eq!(*lock.read().unwrap(), (rounds, rounds));
+    }
+
+    #[test]
+    fn writer_waits_for_a_snapshot() {
+        // the readers all hold one snapshot while the writer is stuck
+        // behind them, and see it unchanged
+        let threads = if cfg!(miri) { 3 } else { 8 };
+        let lock = Arc::new(RwLock::new(vec![1, 2, 3]));
+        let barrier = Arc::new(std::sync::Barrier::new(threads + 1));
+        let readers: Vec<_> = (0..threads)
+            .map(|_| {
+                let lock = lock.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    let guard = lock.read().unwrap();
+                    let before = guard.clone();
+                    barrier.wait();
+                    thread::sleep(Duration::from_millis(10));
+                    assert_eq!(*guard, before);
+                })
+            })
+            .collect();
+        barrier.wait();
+        assert!(matches!(lock.try_write(), Err(TryLockError::WouldBlock)));
+        lock.write().unwrap().push(4);
+        for handle in readers {
+            handle.join().unwrap();
+        }
+        // and every read after the write sees it, on any thread
+        let later = thread::spawn({
+            let lock = lock.clone();
+            move || lock.read().unwrap().clone()
+        });
+        assert_eq!(later.join().unwrap(), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn recursive_read() {
+        let lock = RwLock::new(1);
+        let a = lock.read().unwrap();
+        let b = lock.read().unwrap();
+        let c = lock.try_read().unwrap();
+        assert_eq!(*a + *b + *c, 3);
+        drop(b);
+        assert!(lock.try_write().is_err());
+        drop((a, c));
+        assert!(lock.try_write().is_ok());
+    }
+
+    #[test]
+    fn recursive_read_past_a_waiting_writer() {
+        let lock = Arc::new(RwLock::new(0));
+        let outer = lock.read().unwrap();
+        let writer = thread::spawn({
+            let lock = lock.clone();
+            move || *lock.write().unwrap() += 1
+        });
+        while lock.raw.writers.len() == 0 {
+            thread::yield_now();
+        }
+        // the writer waits for outer, so this can't wait for the writer
+        let inner = lock.read().unwrap();
+        let mapped = RwLockReadGuard::map(lock.read().unwrap(), |v| v);
+        assert_eq!(*outer + *inner + *mapped, 0);
+        // try_read doesn't have to, and still keeps to the writer's turn
+        assert!(matches!(lock.try_read(), Err(TryLockError::WouldBlock)));
+        // other threads still wait behind the writer
+        let other = thread::spawn({
+            let lock = lock.clone();
+            move || *lock.read().unwrap()
+        });
+        while lock.raw.readers.len() == 0 {
+            thread::yield_now();
+        }
+        drop((outer, inner, mapped));
+        writer.join().unwrap();
+        assert_eq!(other.join().unwrap(), 1);
+        // all three reads were given back, and none is left noted as held
+        assert_eq!(*lock.read().unwrap(), 1);
+        assert!(!lock.raw.holds_read());
+    }
+
+    #[test]
+    fn into_inner_drops_once() {
+        let count = Arc::new(());
+        let lock = Arc::new(RwLock::new(count.clone()));
+        let handles: Vec<_> = (0..3)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    let _guard = lock.read().unwrap();
+                    let _guard = lock.read().unwrap();
+                })
+            })
+            .collect();
+        // the old value is dropped here, the new one by into_inner's caller
+        *lock.write().unwrap() = count.clone();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(Arc::strong_count(&count), 2);
+        let lock = Arc::try_unwrap(lock).ok().unwrap();
+        assert!(!lock.is_poisoned());
+        let value = lock.into_inner().unwrap();
+        assert_eq!(Arc::strong_count(&count), 2);
+        drop(value);
+        assert_eq!(Arc::strong_count(&count), 1);
+    }
+
+    #[test]
+    fn get_mut_and_poisoned_into_inner() {
+        let mut lock = RwLock::new(String::from("a"));
+        lock.get_mut().unwrap().push('b');
+        assert_eq!(*lock.read().unwrap(), "ab");
+
+        let lock = Arc::new(lock);
+        let poisoner = thread::spawn({
+            let lock = lock.clone();
+            move || {
+                let mut guard = lock.write().unwrap();
+                guard.push('c');
+                panic!("poisoning the lock");
+            }
+        });
+        assert!(poisoner.join().is_err());
+        let mut lock = Arc::try_unwrap(lock).ok().unwrap();
+        lock.get_mut().unwrap_err().into_inner().push('d');
+        assert_eq!(lock.into_inner().unwrap_err().into_inner(), "abcd");
+    }
+
+    #[test]
+    fn readers_on_different_threads_overlap() {
+        // every reader waits for all the others while holding its guard,
+        // which only finishes if they can all hold one at once
+        let threads = if cfg!(miri) { 3 } else { 8 };
+        let lock = Arc::new(RwLock::new(5));
+        let barrier = Arc::new(std::sync::Barrier::new(threads));
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                let lock = lock.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    let guard = lock.read().unwrap();
+                    barrier.wait();
+                    *guard
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), 5);
+        }
+    }
+
+    #[test]
+    fn writers_count_correctly() {
+        let threads = if cfg!(miri) { 3 } else { 8 };
+        let rounds = if cfg!(miri) { 20 } else { 5_000 };
+        let lock = Arc::new(RwLock::new(0u64));
+        let handles: Vec<_> = (0..threads)
+            .map(|i| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for _ in 0..rounds {
+                        // mix in readers, so every transition gets exercised
+                        if i % 2 == 0 {
+                            assert!(*lock.read().unwrap() < threads * rounds);
+                        }
+                        let mut guard = lock.write().unwrap();
+                        let n = *guard;
+                        *guard = n + 1;
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(*lock.read().unwrap(), threads * rounds);
+        assert_eq!(lock.raw.state.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn panicking_writer_poisons() {
+        let lock = RwLock::new(1);
+        let _ = std::panic::catch_unwind(|| {
+            let _reader = lock.read().unwrap();
+            panic!("readers don't poison");
+        });
+        assert!(!lock.is_poisoned());
+        let _ = std::panic::catch_unwind(|| {
+            let mut writer = lock.write().unwrap();
+            *writer = 2;
+            panic!("writers do");
+        });
+        assert!(lock.is_poisoned());
+        assert_eq!(*lock.read().unwrap_err().into_inner(), 2);
+        lock.clear_poison();
+        assert_eq!(*lock.write().unwrap(), 2);
+    }
+
+    #[test]
+    fn try_uncontended() {
+        let lock = RwLock::new(1);
+        let a = lock.try_read().unwrap();
+        let b = lock.try_read().unwrap();
+        assert_eq!(*a + *b, 2);
+        // readers in, so no writer
+        assert!(matches!(lock.try_write(), Err(TryLockError::WouldBlock)));
+        assert_eq!(format!("{:?}", lock), "RwLock { data: 1, poisoned: false }");
+        drop((a, b));
+
+        let mut w = lock.try_write().unwrap();
+        *w = 2;
+        assert!(matches!(lock.try_read(), Err(TryLockError::WouldBlock)));
+        assert!(matches!(lock.try_write(), Err(TryLockError::WouldBlock)));
+        assert_eq!(
+            format!("{:?}", lock),
+            "RwLock { data: <locked>, poisoned: false }"
+        );
+        drop(w);
+        assert_eq!(*lock.try_read().unwrap(), 2);
+    }
+
+    #[test]
+    fn try_read_behind_a_waiting_writer() {
+        let lock = Arc::new(RwLock::new(0));
+        let reader = lock.read().unwrap();
+        let writer = thread::spawn({
+            let lock = lock.clone();
+            move || *lock.write().unwrap() += 1
+        });
+        // once the writer is waiting, new readers are turned away even
+        // though only readers hold the lock
+        while lock.raw.state.load(Ordering::Relaxed) & WRITERS_WAITING == 0 {
+            thread::yield_now();
+        }
+        assert!(matches!(lock.try_read(), Err(TryLockError::WouldBlock)));
+        drop(reader);
+        writer.join().unwrap();
+        assert_eq!(*lock.try_read().unwrap(), 1);
+    }
+
+    #[test]
+    fn writer_not_starved_by_readers() {
+        // a reader is in, a writer queues behind it, and more readers keep
+        // coming; none of the readers that came after the writer get in
+        // before it, even though the lock is only read locked
+        // the value counts the writes, so each reader sees which side of
+        // the writer it got in on
+        let readers = if cfg!(miri) { 2 } else { 4 };
+        let rounds = if cfg!(miri) { 2 } else { 10 };
+        let lock = Arc::new(RwLock::new(0));
+        for round in 0..rounds {
+            let held = lock.read().unwrap();
+            let writer = thread::spawn({
+                let lock = lock.clone();
+                move || *lock.write().unwrap() += 1
+            });
+            // asleep, not just about to be: a writer that hasn't gone to
+            // sleep yet can still lose the lock to a reader when it's freed
+            while lock.raw.writers.len() == 0 {
+                thread::yield_now();
+            }
+            assert!(lock.raw.state.load(Ordering::Relaxed) & WRITERS_WAITING != 0);
+            let late: Vec<_> = (0..readers)
+                .map(|_| {
+                    let lock = lock.clone();
+                    thread::spawn(move || *lock.read().unwrap())
+                })
+                .collect();
+            while lock.raw.readers.len() < readers {
+                thread::yield_now();
+            }
+            assert_eq!(*held, round);
+            drop(held);
+            writer.join().unwrap();
+            for handle in late {
+                assert_eq!(handle.join().unwrap(), round + 1);
+            }
+        }
+        assert_eq!(*lock.read().unwrap(), rounds);
+    }
+
+    #[test]
+    fn try_contended() {
+        let threads = if cfg!(miri) { 3 } else { 8 };
+        let rounds = if cfg!(miri) { 20 } else { 2_000 };
+        let lock = Arc::new(RwLock::new(0u64));
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    let mut wrote = 0;
+                    for _ in 0..rounds {
+                        if let Ok(mut guard) = lock.try_write() {
+                            *guard += 1;
+                            wrote += 1;
+                        }
+                        if let Ok(guard) = lock.try_read() {
+                            assert!(*guard <= threads * rounds);
+                        }
+                    }
+                    wrote
+                })
+            })
+            .collect();
+        let wrote: u64 = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(*lock.read().unwrap(), wrote);
+        assert_eq!(lock.raw.state.load(Ordering::Relaxed), 0);
+    }
+
+    #[derive(Debug)]
+    struct Config {
+        name: String,
+        limits: (u32, u32),
+    }
+
+    fn config() -> RwLock<Config> {
+        RwLock::new(Config {
+            name: String::from("default"),
+            limits: (1, 10),
+        })
+    }
+
+    #[test]
+    fn map_read_guards() {
+        let lock = config();
+        let name = RwLockReadGuard::map(lock.read().unwrap(), |c| c.name.as_str());
+        let upper = RwLockReadGuard::map(lock.read().unwrap(), |c| &c.limits);
+        let upper = MappedRwLockReadGuard::map(upper, |l| &l.1);
+        assert_eq!((&*name, *upper), ("default", 10));
+        assert_eq!(format!("{} {:?}", name, upper), "default 10");
+        // both still hold a share
+        assert_eq!(lock.raw.state.load(Ordering::Relaxed), 2 * READ_LOCKED);
+        assert!(matches!(lock.try_write(), Err(TryLockError::WouldBlock)));
+        drop(name);
+        assert!(matches!(lock.try_write(), Err(TryLockError::WouldBlock)));
+        drop(upper);
+        assert_eq!(lock.raw.state.load(Ordering::Relaxed), 0);
+        assert!(lock.try_write().is_ok());
+    }
+
+    #[test]
+    fn map_write_guards() {
+        let lock = config();
+        let limits = RwLockWriteGuard::map(lock.write().unwrap(), |c| &mut c.limits);
+        let mut lower = MappedRwLockWriteGuard::map(limits, |l| &mut l.0);
+        *lower = 5;
+        assert!(matches!(lock.try_read(), Err(TryLockError::WouldBlock)));
+        drop(lower);
+        assert_eq!(lock.read().unwrap().limits, (5, 10));
+
+        // and a panic through a mapped guard poisons
+        let _ = std::panic::catch_unwind(|| {
+            let mut name = RwLockWriteGuard::map(lock.write().unwrap(), |c| &mut c.name);
+            name.clear();
+            panic!("half renamed");
+        });
+        assert!(lock.is_poisoned());
+        assert_eq!(lock.read().unwrap_err().into_inner().name, "");
+        assert_eq!(lock.raw.state.load(Ordering::Relaxed), 0);
+    }
+
+    struct Settings {
+        entries: RwLock<std::collections::HashMap<String, Vec<u32>>>,
+    }
+
+    impl Settings {
+        // guards to single entries, still holding the lock after returning
+        fn get(&self, key: &str) -> Option<MappedRwLockReadGuard<'_, [u32]>> {
+            RwLockReadGuard::try_map(self.entries.read().unwrap(), |e| e.get(key).map(|v| &v[..]))
+                .ok()
+        }
+
+        fn get_mut(&self, key: &str) -> Option<MappedRwLockWriteGuard<'_, Vec<u32>>> {
+            RwLockWriteGuard::try_map(self.entries.write().unwrap(), |e| e.get_mut(key)).ok()
+        }
+    }
+
+    #[test]
+    fn guards_to_map_entries() {
+        let mut entries = std::collections::HashMap::new();
+        entries.insert(String::from("ports"), vec![80]);
+        let settings = Settings {
+            entries: RwLock::new(entries),
+        };
+        settings.get_mut("ports").unwrap().push(443);
+        assert!(settings.get_mut("hosts").is_none());
+        let ports = settings.get("ports").unwrap();
+        let again = settings.get("ports").unwrap();
+        assert_eq!((&*ports, &*again), (&[80, 443][..], &[80, 443][..]));
+        assert_eq!(
+            settings.entries.raw.state.load(Ordering::Relaxed),
+            2 * READ_LOCKED
+        );
+        assert!(settings.entries.try_write().is_err());
+        drop((ports, again));
+        assert_eq!(settings.entries.raw.state.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn try_map_gives_the_guard_back() {
+        let lock = config();
+        let read =
+            RwLockReadGuard::try_map(lock.read().unwrap(), |c| c.name.get(10..)).unwrap_err();
+        // still the whole value, and still a read lock
+        assert_eq!(read.limits, (1, 10));
+        assert_eq!(lock.raw.state.load(Ordering::Relaxed), READ_LOCKED);
+        let limits = RwLockReadGuard::map(read, |c| &c.limits);
+        let limits = MappedRwLockReadGuard::try_map(limits, |_| None::<&u32>).unwrap_err();
+        let upper = MappedRwLockReadGuard::try_map(limits, |l| Some(&l.1)).unwrap();
+        assert_eq!(*upper, 10);
+        drop(upper);
+
+        let write =
+            RwLockWriteGuard::try_map(lock.write().unwrap(), |c| c.name.get_mut(10..)).unwrap_err();
+        assert_eq!(lock.raw.state.load(Ordering::Relaxed), WRITE_LOCKED);
+        let name = RwLockWriteGuard::map(write, |c| &mut c.name);
+        let name = MappedRwLockWriteGuard::try_map(name, |n| n.get_mut(10..)).unwrap_err();
+        let mut first = MappedRwLockWriteGuard::try_map(name, |n| n.get_mut(..1)).unwrap();
+        first.make_ascii_uppercase();
+        assert!(lock.try_read().is_err());
+        drop(first);
+        assert_eq!(lock.read().unwrap().name, "Default");
+        assert_eq!(lock.raw.state.load(Ordering::Relaxed), 0);
+        assert!(!lock.is_poisoned());
+    }
+
+    #[test]
+    fn timed_when_free() {
+        let lock = RwLock::new(1);
+        *lock.try_write_for(Duration::from_secs(10)).unwrap() += 1;
+        let past = Instant::now();
+        assert_eq!(*lock.try_write_until(past).unwrap(), 2);
+        let a = lock.try_read_for(Duration::MAX).unwrap();
+        let b = lock.try_read_until(past).unwrap();
+        assert_eq!(*a + *b, 4);
+    }
+
+    #[test]
+    fn timed_out_by_a_holder() {
+        let lock = Arc::new(RwLock::new(()));
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let holder = thread::spawn({
+            let lock = lock.clone();
+            move || {
+                let _guard = lock.write().unwrap();
+                locked_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+            }
+        });
+        locked_rx.recv().unwrap();
+
+        let timeout = Duration::from_millis(30);
+        let start = Instant::now();
+        assert!(matches!(
+            lock.try_read_for(timeout),
+            Err(TryLockError::WouldBlock)
+        ));
+        assert!(matches!(
+            lock.try_write_for(timeout),
+            Err(TryLockError::WouldBlock)
+        ));
+        let waited = start.elapsed();
+        assert!(waited >= 2 * timeout);
+        // generous, only here to catch waiting for the holder instead
+        if !cfg!(miri) {
+            assert!(waited < Duration::from_secs(5));
+        }
+        release_tx.send(()).unwrap();
+        holder.join().unwrap();
+        // the waiting bits left behind don't keep anyone out
+        drop(lock.read().unwrap());
+        drop(lock.write().unwrap());
+    }
+
+    #[test]
+    fn timed_out_writer_lets_readers_in() {
+        let lock = Arc::new(RwLock::new(0));
+        let read = lock.read().unwrap();
+        // a writer gives up waiting for the reader, after which a new
+        // reader would be waiting behind a writer that's gone
+        assert!(lock.try_write_for(Duration::from_millis(10)).is_err());
+        let reader = thread::spawn({
+            let lock = lock.clone();
+            move || *lock.read().unwrap()
+        });
+        thread::sleep(Duration::from_millis(20));
+        drop(read);
+        assert_eq!(reader.join().unwrap(), 0);
+    }
+
+    #[test]
+    fn timed_gets_it_when_released_in_time() {
+        let lock = Arc::new(RwLock::new(0));
+        let guard = lock.write().unwrap();
+        let writer = thread::spawn({
+            let lock = lock.clone();
+            move || match lock.try_write_for(Duration::from_secs(30)) {
+                Ok(mut guard) => {
+                    *guard += 1;
+                    true
+                }
+                Err(_) => false,
+            }
+        });
+        let reader = thread::spawn({
+            let lock = lock.clone();
+            move || lock.try_read_for(Duration::from_secs(30)).is_ok()
+        });
+        thread::sleep(Duration::from_millis(20));
+        drop(guard);
+        assert!(writer.join().unwrap());
+        assert!(reader.join().unwrap());
+        assert_eq!(*lock.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn upgrade_after_checking() {
+        let lock = Arc::new(RwLock::new(Vec::new()));
+        let threads = if cfg!(miri) { 3 } else { 8 };
+        let keys = if cfg!(miri) { 5 } else { 100 };
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for key in 0..keys {
+                        let guard = lock.upgradable_read().unwrap();
+                        // nobody can add key between this check and the push
+                        if !guard.contains(&key) {
+                            RwLockUpgradableReadGuard::upgrade(guard).push(key);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(*lock.read().unwrap(), (0..keys).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn upgradable_shares_with_readers_only() {
+        let lock = Arc::new(RwLock::new(0));
+        let upgradable = lock.upgradable_read().unwrap();
+        assert_eq!(*lock.try_read().unwrap(), 0);
+        assert!(matches!(lock.try_write(), Err(TryLockError::WouldBlock)));
+        let second = thread::spawn({
+            let lock = lock.clone();
+            move || *lock.upgradable_read().unwrap()
+        });
+        thread::sleep(Duration::from_millis(20));
+        // the second upgradable read is still waiting for this one
+        assert!(!second.is_finished());
+        let mut write = RwLockUpgradableReadGuard::upgrade(upgradable);
+        *write = 1;
+        drop(write);
+        assert_eq!(second.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn waiting_upgradable_doesnt_keep_readers_out() {
+        let lock = Arc::new(RwLock::new(0));
+        let upgradable = lock.upgradable_read().unwrap();
+        let second = thread::spawn({
+            let lock = lock.clone();
+            move || *lock.upgradable_read().unwrap()
+        });
+        while lock.raw.upgraders.len() == 0 {
+            thread::yield_now();
+        }
+        // plain readers still come and go while the second one sleeps
+        assert_eq!(*lock.try_read().unwrap(), 0);
+        let reader = thread::spawn({
+            let lock = lock.clone();
+            move || *lock.read().unwrap()
+        });
+        assert_eq!(reader.join().unwrap(), 0);
+        drop(upgradable);
+        assert_eq!(second.join().unwrap(), 0);
+        assert_eq!(lock.raw.state.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn upgrade_waits_for_readers() {
+        let lock = Arc::new(RwLock::new(Vec::new()));
+        let upgradable = lock.upgradable_read().unwrap();
+        let (read_tx, read_rx) = std::sync::mpsc::channel();
+        let reader = thread::spawn({
+            let lock = lock.clone();
+            move || {
+                let guard = lock.read().unwrap();
+                read_tx.send(()).unwrap();
+                thread::sleep(Duration::from_millis(20));
+                // still the only writer to get in, so nothing's changed
+                assert!(guard.is_empty());
+            }
+        });
+        read_rx.recv().unwrap();
+        // a writer that comes along meanwhile waits its turn after the
+        // upgrade, instead of getting in between
+        let writer = thread::spawn({
+            let lock = lock.clone();
+            move || lock.write().unwrap().push("writer")
+        });
+        let mut write = RwLockUpgradableReadGuard::upgrade(upgradable);
+        write.push("upgraded");
+        drop(write);
+        reader.join().unwrap();
+        writer.join().unwrap();
+        assert_eq!(*lock.read().unwrap(), ["upgraded", "writer"]);
+    }
+
+    #[test]
+    fn try_upgrade_only_without_other_readers() {
+        let lock = RwLock::new(0);
+        let upgradable = lock.upgradable_read().unwrap();
+        let reader = lock.read().unwrap();
+        let upgradable = RwLockUpgradableReadGuard::try_upgrade(upgradable).unwrap_err();
+        assert_eq!(*upgradable, 0);
+        drop(reader);
+        let mut write = RwLockUpgradableReadGuard::try_upgrade(upgradable).unwrap();
+        *write += 1;
+        assert!(matches!(lock.try_read(), Err(TryLockError::WouldBlock)));
+        drop(write);
+        assert_eq!(*lock.read().unwrap(), 1);
+        assert_eq!(lock.raw.state.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn downgrade_upgradable() {
+        let lock = Arc::new(RwLock::new(0));
+        let upgradable = lock.upgradable_read().unwrap();
+        let next = thread::spawn({
+            let lock = lock.clone();
+            move || {
+                let guard = lock.upgradable_read().unwrap();
+                *RwLockUpgradableReadGuard::upgrade(guard) += 1;
+            }
+        });
+        thread::sleep(Duration::from_millis(20));
+        // the next upgradable read gets in while this is still reading, and
+        // waits to upgrade until it's done
+        let read = RwLockUpgradableReadGuard::downgrade(upgradable);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(*read, 0);
+        drop(read);
+        next.join().unwrap();
+        assert_eq!(*lock.read().unwrap(), 1);
+        assert_eq!(lock.raw.state.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn cache_never_computes_twice() {
+        let threads = if cfg!(miri) { 3 } else { 8 };
+        let keys = if cfg!(miri) { 4 } else { 50 };
+        let cache = Arc::new(RwLock::new(std::collections::HashMap::new()));
+        let computed = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let handles: Vec<_> = (0..threads)
+            .map(|i| {
+                let (cache, computed) = (cache.clone(), computed.clone());
+                thread::spawn(move || {
+                    for key in 0..keys {
+                        // plain reads first, like most lookups would be
+                        if i % 2 == 0 && cache.read().unwrap().contains_key(&key) {
+                            continue;
+                        }
+                        let guard = cache.upgradable_read().unwrap();
+                        if guard.contains_key(&key) {
+                            continue;
+                        }
+                        computed.fetch_add(1, Ordering::Relaxed);
+                        RwLockUpgradableReadGuard::upgrade(guard).insert(key, key * key);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(computed.load(Ordering::Relaxed), keys);
+        assert_eq!(cache.read().unwrap().len(), keys);
+    }
+
+    #[test]
+    fn upgradable_mixed_with_readers_and_writers() {
+        let lock = Arc::new(RwLock::new(0));
+        let threads = 6;
+        let rounds = if cfg!(miri) { 10 } else { 500 };
+        let handles: Vec<_> = (0..threads)
+            .map(|i| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for _ in 0..rounds {
+                        match i % 3 {
+                            0 => drop(lock.read().unwrap()),
+                            1 => *lock.write().unwrap() += 1,
+                            _ => {
+                                let guard = lock.upgradable_read().unwrap();
+                                let seen = *guard;
+                                let mut guard = RwLockUpgradableReadGuard::upgrade(guard);
+                                assert_eq!(*guard, seen);
+                                *guard += 1;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(*lock.read().unwrap(), 2 * 2 * rounds);
+    }
+
+    #[test]
+    fn downgrade_keeps_writers_out() {
+        let lock = Arc::new(RwLock::new(0));
+        let mut write = lock.write().unwrap();
+        let writer = thread::spawn({
+            let lock = lock.clone();
+            move || *lock.write().unwrap() = 2
+        });
+        *write = 1;
+        let read = RwLockWriteGuard::downgrade(write);
+        // the waiting writer can't have got in between
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(*read, 1);
+        assert!(matches!(lock.try_write(), Err(TryLockError::WouldBlock)));
+        drop(read);
+        writer.join().unwrap();
+        assert_eq!(*lock.read().unwrap(), 2);
+    }
+
+    #[test]
+    fn downgrade_lets_waiting_readers_in() {
+        let lock = Arc::new(RwLock::new(0));
+        let mut write = lock.write().unwrap();
+        let (read_tx, read_rx) = std::sync::mpsc::channel();
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                let lock = lock.clone();
+                let read_tx = read_tx.clone();
+                thread::spawn(move || {
+                    let guard = lock.read().unwrap();
+                    read_tx.send(*guard).unwrap();
+                })
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(20));
+        *write = 1;
+        let read = RwLockWriteGuard::downgrade(write);
+        // they all get in while this read guard is still held
+        for _ in 0..3 {
+            assert_eq!(read_rx.recv().unwrap(), 1);
+        }
+        drop(read);
+        for handle in readers {
+            handle.join().unwrap();
+        }
+        assert_eq!(lock.raw.state.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn downgrade_filled_vec() {
+        let lock = Arc::new(RwLock::new(Vec::new()));
+        let mut write = lock.write().unwrap();
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || lock.read().unwrap().len())
+            })
+            .collect();
+        write.extend(0..100);
+        let read = RwLockWriteGuard::downgrade(write);
+        assert!(matches!(lock.try_write(), Err(TryLockError::WouldBlock)));
+        for handle in readers {
+            assert_eq!(handle.join().unwrap(), 100);
+        }
+        assert!(matches!(lock.try_write(), Err(TryLockError::WouldBlock)));
+        drop(read);
+        assert!(lock.try_write().is_ok());
+    }
+
+    #[test]
+    fn downgrade_with_readers_and_writers_waiting() {
+        // the readers keep waiting behind the writer, and nobody's wakeup
+        // gets lost on the way
+        let lock = Arc::new(RwLock::new(0));
+        let write = lock.write().unwrap();
+        let writer = thread::spawn({
+            let lock = lock.clone();
+            move || *lock.write().unwrap() += 1
+        });
+        while lock.raw.state.load(Ordering::Relaxed) & WRITERS_WAITING == 0 {
+            thread::yield_now();
+        }
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || *lock.read().unwrap())
+            })
+            .collect();
+        while lock.raw.state.load(Ordering::Relaxed) & READERS_WAITING == 0 {
+            thread::yield_now();
+        }
+        let read = RwLockWriteGuard::downgrade(write);
+        assert_eq!(*read, 0);
+        drop(read);
+        writer.join().unwrap();
+        for handle in readers {
+            // the writer went first
+            assert_eq!(handle.join().unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn downgrade_mapped() {
+        let lock = config();
+        let mut limits = RwLockWriteGuard::map(lock.write().unwrap(), |c| &mut c.limits);
+        limits.1 = 20;
+        let limits = MappedRwLockWriteGuard::downgrade(limits);
+        assert_eq!(lock.try_read().unwrap().name, "default");
+        assert!(lock.try_write().is_err());
+        assert_eq!(*limits, (1, 20));
+        drop(limits);
+        assert_eq!(lock.raw.state.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn downgrade_under_contention() {
+        // every writer downgrades and checks nobody wrote in between,
+        // while others write and read around it
+        let threads = if cfg!(miri) { 3 } else { 6 };
+        let rounds = if cfg!(miri) { 10 } else { 500 };
+        let lock = Arc::new(RwLock::new(0));
+        let handles: Vec<_> = (0..threads)
+            .map(|i| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for _ in 0..rounds {
+                        if i % 2 == 0 {
+                            let mut write = lock.write().unwrap();
+                            *write += 1;
+                            let seen = *write;
+                            let read = RwLockWriteGuard::downgrade(write);
+                            thread::yield_now();
+                            assert_eq!(*read, seen);
+                        } else {
+                            drop(lock.read().unwrap());
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(*lock.read().unwrap(), (threads + 1) / 2 * rounds);
+    }
+
+    #[test]
+    fn send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        fn assert_send<T: Send>() {}
+        assert_send_sync::<RwLock<i32>>();
+        // readers share the value, so unlike Mutex it needs Sync
+        assert_send::<RwLock<std::cell::Cell<i32>>>();
+        fn assert_sync<T: Sync>() {}
+        assert_sync::<RwLockReadGuard<'_, i32>>();
+        assert_sync::<RwLockWriteGuard<'_, i32>>();
+        assert_sync::<RwLockUpgradableReadGuard<'_, i32>>();
+        assert_sync::<MappedRwLockReadGuard<'_, i32>>();
+        assert_sync::<MappedRwLockWriteGuard<'_, i32>>();
+    }
+}
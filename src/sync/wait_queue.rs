@@ -0,0 +1,151 @@
+// a queue of parked threads, which is what the locks sleep on instead of
+// spinning forever
+//
+// it works like a futex: a thread that wants to sleep passes a check, which
+// runs with the queue locked, and only goes to sleep if the check still
+// holds
+// whoever changes the state a sleeper is waiting on does so before taking
+// the queue lock to wake someone, so either the sleeper's check runs after
+// the change and sees it, or the sleeper is already queued by the time the
+// waker looks, and no wakeup gets lost in between
+//
+// the queue lock is a spin lock, but it's only ever held to push or pop a
+// waiter, never while anyone sleeps
+use super::Arc;
+use std::cell::UnsafeCell;
+use std::collections::VecDeque;
+use std::hint;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, Thread};
+use std::time::Instant;
+
+pub(crate) struct WaitQueue {
+    locked: AtomicBool,
+    waiters: UnsafeCell<VecDeque<Arc<Waiter>>>,
+}
+
+struct Waiter {
+    thread: Thread,
+    // set before unparking, since park can also return spuriously
+    woken: AtomicBool,
+}
+
+// waiters is only touched with locked held
+unsafe impl Sync for WaitQueue {}
+
+impl WaitQueue {
+    pub(crate) const fn new() -> Self {
+        WaitQueue {
+            locked: AtomicBool::new(false),
+            waiters: UnsafeCell::new(VecDeque::new()),
+        }
+    }
+
+    fn with_waiters<R>(&self, f: impl FnOnce(&mut VecDeque<Arc<Waiter>>) -> R) -> R {
+        let mut spins = 0;
+        // Acquire/Release: the queue itself is what the spin lock protects
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spins += 1;
+            if spins < 64 {
+                hint::spin_loop();
+            } else {
+                thread::yield_now();
+            }
+        }
+        // put the lock back even if f panics, e.g. on a failed allocation
+        struct Unlock<'a>(&'a AtomicBool);
+
+        impl Drop for Unlock<'_> {
+            fn drop(&mut self) {
+                self.0.store(false, Ordering::Release);
+            }
+        }
+
+        let _unlock = Unlock(&self.locked);
+        f(unsafe { &mut *self.waiters.get() })
+    }
+
+    /// park the current thread until notify wakes it, if should_sleep
+    /// (checked with the queue locked) says to, giving up at deadline
+    /// false if the deadline passed without a notify
+    pub(crate) fn wait_until(
+        &self,
+        should_sleep: impl FnOnce() -> bool,
+        deadline: Option<Instant>,
+    ) -> bool {
+        let waiter = Arc::new(Waiter {
+            thread: thread::current(),
+            woken: AtomicBool::new(false),
+        });
+        let queued = self.with_waiters(|waiters| {
+            let queued = should_sleep();
+            if queued {
+                waiters.push_back(waiter.clone());
+            }
+            queued
+        });
+        if !queued {
+            return true;
+        }
+        loop {
+            // Acquire: pairs with notify's Release, though the state the
+            // caller waits on has its own orderings anyway
+            if waiter.woken.load(Ordering::Acquire) {
+                return true;
+            }
+            match deadline {
+                None => thread::park(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        // take ourselves out of the queue, unless a notify
+                        // got there first, in which case it counts
+                        let removed = self.with_waiters(|waiters| {
+                            let i = waiters.iter().position(|w| Arc::ptr_eq(w, &waiter));
+                            i.map(|i| waiters.remove(i)).is_some()
+                        });
+                        return !removed;
+                    }
+                    thread::park_timeout(deadline - now);
+                }
+            }
+        }
+    }
+
+    /// how many threads are asleep in the queue, for tests that need one to
+    /// be before going on
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.with_waiters(|waiters| waiters.len())
+    }
+
+    /// wake the thread that has waited longest, false if none were waiting
+    pub(crate) fn notify_one(&self) -> bool {
+        match self.with_waiters(|waiters| waiters.pop_front()) {
+            Some(waiter) => {
+                wake(waiter);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// wake every waiting thread, returning how many there were
+    pub(crate) fn notify_all(&self) -> usize {
+        let waiters = self.with_waiters(std::mem::take);
+        let n = waiters.len();
+        waiters.into_iter().for_each(wake);
+        n
+    }
+}
+
+fn wake(waiter: Arc<Waiter>) {
+    // the Arc keeps the Thread around even if the waiter has already seen
+    // woken and moved on
+    waiter.woken.store(true, Ordering::Release);
+    waiter.thread.unpark();
+}
@@ -0,0 +1,390 @@
+// cycle diagnostics for graphs of Rcs
+//
+// a strong cycle never gets freed, and nothing tells you it happened
+// types implement Trace to list the Rcs they hold, and find_cycles walks
+// everything reachable from some roots, reporting each group of allocations
+// that keep each other alive through strong edges (a strongly connected
+// component, in graph terms)
+//
+// Weak edges can't keep anything alive, so Weak's Trace lists nothing
+// the walk holds a clone of every Rc it finds until it's done, so the
+// strong counts are temporarily higher while it runs
+use crate::alloc::Allocator;
+use crate::rc::{Count, Rc, Weak};
+use crate::refcell::RefCell;
+use std::collections::HashMap;
+
+/// lists the Rcs a value holds strong references to
+pub trait Trace {
+    /// call visitor with every Rc this value holds directly, i.e. the Rcs
+    /// in its fields, not the ones inside those Rcs' values
+    fn trace(&self, visitor: &mut dyn FnMut(&dyn ErasedRc));
+}
+
+/// an Rc seen through Trace, with its value's type erased
+pub trait ErasedRc {
+    /// the address of the allocation, which identifies it
+    fn address(&self) -> usize;
+
+    /// the type of the value, from std::any::type_name
+    fn type_name(&self) -> &'static str;
+
+    /// trace the value this Rc points to
+    fn trace_value(&self, visitor: &mut dyn FnMut(&dyn ErasedRc));
+
+    /// another Rc to the same allocation, for holding on to it after the
+    /// visitor returns
+    fn clone_erased(&self) -> Box<dyn ErasedRc>;
+}
+
+impl<T, A, C> ErasedRc for Rc<T, A, C>
+where
+    T: Trace + 'static,
+    A: Allocator + Clone + 'static,
+    C: Count + 'static,
+{
+    fn address(&self) -> usize {
+        Rc::as_ptr(self) as usize
+    }
+
+    fn type_name(&self) -> &'static str {
+        std::any::type_name::<T>()
+    }
+
+    fn trace_value(&self, visitor: &mut dyn FnMut(&dyn ErasedRc)) {
+        (**self).trace(visitor)
+    }
+
+    fn clone_erased(&self) -> Box<dyn ErasedRc> {
+        Box::new(self.clone())
+    }
+}
+
+impl<T, A, C> Trace for Rc<T, A, C>
+where
+    T: Trace + 'static,
+    A: Allocator + Clone + 'static,
+    C: Count + 'static,
+{
+    fn trace(&self, visitor: &mut dyn FnMut(&dyn ErasedRc)) {
+        visitor(self)
+    }
+}
+
+impl<T: ?Sized, A: Allocator, C: Count> Trace for Weak<T, A, C> {
+    fn trace(&self, _: &mut dyn FnMut(&dyn ErasedRc)) {}
+}
+
+impl<T: Trace> Trace for Option<T> {
+    fn trace(&self, visitor: &mut dyn FnMut(&dyn ErasedRc)) {
+        if let Some(value) = self {
+            value.trace(visitor)
+        }
+    }
+}
+
+impl<T: Trace> Trace for Vec<T> {
+    fn trace(&self, visitor: &mut dyn FnMut(&dyn ErasedRc)) {
+        for value in self {
+            value.trace(visitor)
+        }
+    }
+}
+
+impl<T: Trace + ?Sized> Trace for &T {
+    fn trace(&self, visitor: &mut dyn FnMut(&dyn ErasedRc)) {
+        (**self).trace(visitor)
+    }
+}
+
+impl<T: Trace + ?Sized> Trace for Box<T> {
+    fn trace(&self, visitor: &mut dyn FnMut(&dyn ErasedRc)) {
+        (**self).trace(visitor)
+    }
+}
+
+// a RefCell that's mutably borrowed while the walk runs can't be looked
+// into, so its edges are missed
+impl<T: Trace> Trace for RefCell<T> {
+    fn trace(&self, visitor: &mut dyn FnMut(&dyn ErasedRc)) {
+        if let Some(value) = self.borrow() {
+            value.trace(visitor)
+        }
+    }
+}
+
+macro_rules! trace_tuple {
+    ($($name:ident)+) => {
+        impl<$($name: Trace),+> Trace for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn trace(&self, visitor: &mut dyn FnMut(&dyn ErasedRc)) {
+                let ($($name,)+) = self;
+                $($name.trace(visitor);)+
+            }
+        }
+    };
+}
+
+trace_tuple!(A);
+trace_tuple!(A B);
+trace_tuple!(A B C);
+trace_tuple!(A B C D);
+
+/// one allocation in a cycle
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CycleMember {
+    /// the type of the value, from std::any::type_name
+    pub type_name: &'static str,
+    /// where the allocation starts, the same as Rc::as_ptr
+    pub address: usize,
+}
+
+/// a group of allocations that keep each other alive through strong
+/// references, so none of them will ever be freed on its own
+#[derive(Clone, Debug)]
+pub struct CycleReport {
+    /// in the order they were first reached from the roots
+    pub members: Vec<CycleMember>,
+}
+
+/// every strong cycle reachable from roots
+pub fn find_cycles(roots: &[&dyn Trace]) -> Vec<CycleReport> {
+    // the graph: one node per allocation, edges for strong references
+    let mut nodes: Vec<Box<dyn ErasedRc>> = Vec::new();
+    let mut edges: Vec<Vec<usize>> = Vec::new();
+    let mut index: HashMap<usize, usize> = HashMap::new();
+
+    let mut found = Vec::new();
+    for root in roots {
+        root.trace(&mut |rc| found.push(rc.clone_erased()));
+    }
+    let mut intern = |rc: Box<dyn ErasedRc>, nodes: &mut Vec<Box<dyn ErasedRc>>| {
+        *index.entry(rc.address()).or_insert_with(|| {
+            nodes.push(rc);
+            nodes.len() - 1
+        })
+    };
+    for rc in found {
+        intern(rc, &mut nodes);
+    }
+    // nodes grows as new allocations are found, so this visits them all
+    let mut next = 0;
+    while next < nodes.len() {
+        let mut children = Vec::new();
+        nodes[next].trace_value(&mut |rc| children.push(rc.clone_erased()));
+        let targets = children
+            .into_iter()
+            .map(|rc| intern(rc, &mut nodes))
+            .collect();
+        edges.push(targets);
+        next += 1;
+    }
+
+    strongly_connected(&edges)
+        .into_iter()
+        // a lone node is only a cycle if it points to itself
+        .filter(|scc| scc.len() > 1 || edges[scc[0]].contains(&scc[0]))
+        .map(|mut scc| {
+            scc.sort_unstable();
+            CycleReport {
+                members: scc
+                    .into_iter()
+                    .map(|i| CycleMember {
+                        type_name: nodes[i].type_name(),
+                        address: nodes[i].address(),
+                    })
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+// tarjan's algorithm, with an explicit stack instead of recursion so long
+// chains of Rcs don't overflow the real one
+fn strongly_connected(edges: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    const UNVISITED: usize = usize::MAX;
+    let n = edges.len();
+    let mut order = vec![UNVISITED; n];
+    let mut low = vec![0; n];
+    let mut on_stack = vec![false; n];
+    let mut stack = Vec::new();
+    let mut sccs = Vec::new();
+    let mut counter = 0;
+
+    for start in 0..n {
+        if order[start] != UNVISITED {
+            continue;
+        }
+        // each frame is a node and how many of its edges have been followed
+        let mut calls = vec![(start, 0)];
+        order[start] = counter;
+        low[start] = counter;
+        counter += 1;
+        stack.push(start);
+        on_stack[start] = true;
+
+        while let Some(&(v, followed)) = calls.last() {
+            if let Some(&w) = edges[v].get(followed) {
+                calls.last_mut().unwrap().1 += 1;
+                if order[w] == UNVISITED {
+                    order[w] = counter;
+                    low[w] = counter;
+                    counter += 1;
+                    stack.push(w);
+                    on_stack[w] = true;
+                    calls.push((w, 0));
+                } else if on_stack[w] {
+                    low[v] = low[v].min(order[w]);
+                }
+                continue;
+            }
+            // all of v's edges are done
+            calls.pop();
+            if let Some(&(parent, _)) = calls.last() {
+                low[parent] = low[parent].min(low[v]);
+            }
+            if low[v] == order[v] {
+                let mut scc = Vec::new();
+                loop {
+                    let w = stack.pop().expect("v is still on the stack");
+                    on_stack[w] = false;
+                    scc.push(w);
+                    if w == v {
+                        break;
+                    }
+                }
+                sccs.push(scc);
+            }
+        }
+    }
+    sccs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Node {
+        name: &'static str,
+        strong: RefCell<Vec<Rc<Node>>>,
+        weak: RefCell<Vec<Weak<Node>>>,
+    }
+
+    impl Trace for Node {
+        fn trace(&self, visitor: &mut dyn FnMut(&dyn ErasedRc)) {
+            (&self.strong, &self.weak).trace(visitor)
+        }
+    }
+
+    fn node(name: &'static str) -> Rc<Node> {
+        Rc::new(Node {
+            name,
+            strong: RefCell::new(Vec::new()),
+            weak: RefCell::new(Vec::new()),
+        })
+    }
+
+    fn link(from: &Rc<Node>, to: &Rc<Node>) {
+        from.strong.borrow_mut().unwrap().push(to.clone());
+    }
+
+    fn link_weak(from: &Rc<Node>, to: &Rc<Node>) {
+        from.weak.borrow_mut().unwrap().push(Rc::downgrade(to));
+    }
+
+    // so the cycles don't actually leak once the test is done
+    fn unlink(nodes: &[&Rc<Node>]) {
+        for node in nodes {
+            node.strong.borrow_mut().unwrap().clear();
+        }
+    }
+
+    #[test]
+    fn two_node_cycle() {
+        let a = node("a");
+        let b = node("b");
+        let c = node("c");
+        link(&a, &b);
+        link(&b, &a);
+        link(&b, &c);
+
+        let cycles = find_cycles(&[&a]);
+        assert_eq!(cycles.len(), 1);
+        let members = &cycles[0].members;
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[0].address, Rc::as_ptr(&a) as usize);
+        assert_eq!(members[1].address, Rc::as_ptr(&b) as usize);
+        assert!(members[0].type_name.ends_with("Node"));
+        // found from anywhere that reaches it
+        assert_eq!(find_cycles(&[&c, &b]).len(), 1);
+        // and the walk let go of all its clones
+        assert_eq!(Rc::strong_count(&a), 2);
+        assert_eq!(a.name, "a");
+
+        unlink(&[&a, &b]);
+        assert!(find_cycles(&[&a]).is_empty());
+    }
+
+    #[test]
+    fn weak_back_edge() {
+        let a = node("a");
+        let b = node("b");
+        link(&a, &b);
+        link_weak(&b, &a);
+        assert!(find_cycles(&[&a, &b]).is_empty());
+    }
+
+    #[test]
+    fn diamond() {
+        let top = node("top");
+        let left = node("left");
+        let right = node("right");
+        let bottom = node("bottom");
+        link(&top, &left);
+        link(&top, &right);
+        link(&left, &bottom);
+        link(&right, &bottom);
+        assert!(find_cycles(&[&top]).is_empty());
+    }
+
+    #[test]
+    fn self_loop_and_separate_cycles() {
+        let a = node("a");
+        link(&a, &a);
+        let (b, c, d) = (node("b"), node("c"), node("d"));
+        link(&b, &c);
+        link(&c, &d);
+        link(&d, &b);
+
+        let roots = (Some(a.clone()), vec![b.clone()]);
+        let cycles = find_cycles(&[&roots]);
+        assert_eq!(cycles.len(), 2);
+        let mut sizes: Vec<_> = cycles.iter().map(|c| c.members.len()).collect();
+        sizes.sort_unstable();
+        assert_eq!(sizes, [1, 3]);
+        unlink(&[&a, &b, &c, &d]);
+    }
+
+    #[test]
+    fn long_chain_does_not_overflow() {
+        let len = if cfg!(miri) { 100 } else { 100_000 };
+        let first = node("first");
+        let mut last = first.clone();
+        for _ in 0..len {
+            let next = node("link");
+            link(&last, &next);
+            last = next;
+        }
+        assert!(find_cycles(&[&first]).is_empty());
+        link(&last, &first);
+        assert_eq!(find_cycles(&[&first])[0].members.len(), len + 1);
+        unlink(&[&last]);
+        drop(last);
+
+        // dropping the chain in one go would recurse once per link
+        let mut next = Some(first);
+        while let Some(node) = next {
+            next = node.strong.borrow_mut().unwrap().pop();
+        }
+    }
+}
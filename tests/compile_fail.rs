@@ -0,0 +1,23 @@
+// properties that can only be checked by making sure some code doesn't
+// compile, e.g. that Rc can't be sent to another thread
+//
+// the expected compiler output lives next to each case in tests/ui
+// regenerate it with TRYBUILD=overwrite cargo test --test compile_fail
+//
+// diagnostics change between compiler versions, so the expected output in
+// tests/ui is for stable and those cases are skipped on the nightly builds
+// the ones in tests/ui-nightly need the nightly feature, so it's the other
+// way round
+#[test]
+#[cfg_attr(any(miri, feature = "nightly"), ignore)]
+fn compile_fail() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
+
+#[test]
+#[cfg_attr(any(miri, not(feature = "nightly")), ignore)]
+fn compile_fail_nightly() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui-nightly/*.rs");
+}
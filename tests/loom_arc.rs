@@ -0,0 +1,123 @@
+// loom models of the races in sync::Arc that a loop of real threads can only
+// hit by luck: loom runs every interleaving of the threads, under every
+// ordering the atomics allow, and reports an access to a loom UnsafeCell
+// that isn't ordered after the last conflicting one
+//
+// the crate has to be built with loom's atomics too:
+// RUSTFLAGS="--cfg loom" cargo test --release --test loom_arc
+#![cfg(loom)]
+
+use acell::sync::{Arc, Weak};
+use loom::cell::UnsafeCell;
+use loom::thread;
+
+#[test]
+fn get_mut_races_upgrade() {
+    loom::model(|| {
+        let mut arc = Arc::new(UnsafeCell::new(0u64));
+        let weak = Arc::downgrade(&arc);
+        let reader = thread::spawn(move || {
+            // the main thread's Arc is alive until after the join
+            let arc = weak.upgrade().unwrap();
+            arc.with(|value| unsafe { *value })
+        });
+        // a Weak or its upgraded Arc is still around, or the reader is done
+        // and get_mut has to see everything it did before the write
+        if let Some(value) = Arc::get_mut(&mut arc) {
+            value.with_mut(|value| unsafe { *value = 1 });
+        }
+        let read = reader.join().unwrap();
+        assert!(read == 0 || read == 1);
+        // nothing's left that could make another Arc
+        let value = Arc::get_mut(&mut arc).unwrap();
+        value.with_mut(|value| unsafe { *value += 1 });
+    });
+}
+
+#[test]
+fn try_unwrap_races_upgrade() {
+    loom::model(|| {
+        let arc = Arc::new(UnsafeCell::new(1u64));
+        let weak = Arc::downgrade(&arc);
+        // the upgraded Arc is handed back, so it's still around at the join
+        let other = thread::spawn(move || {
+            weak.upgrade().map(|arc| {
+                let read = arc.with(|value| unsafe { *value });
+                (arc, read)
+            })
+        });
+        // either the upgrade got in first and the unwrap fails, or the
+        // unwrap won and the upgrade finds nothing, never both
+        let unwrapped = Arc::try_unwrap(arc);
+        let upgraded = other.join().unwrap();
+        match (unwrapped, upgraded) {
+            // the move comes after anything the other thread read
+            (Ok(value), None) => value.with_mut(|value| unsafe { *value += 1 }),
+            (Err(arc), Some((other, read))) => {
+                assert_eq!(read, 1);
+                assert!(Arc::ptr_eq(&arc, &other));
+                assert_eq!(Arc::strong_count(&arc), 2);
+            }
+            (Err(_), None) => panic!("nothing else held the value"),
+            (Ok(_), Some(_)) => panic!("unwrapped a shared value"),
+        }
+    });
+}
+
+#[test]
+fn try_unwrap_races_downgrade() {
+    loom::model(|| {
+        let arc = Arc::new(UnsafeCell::new(1u64));
+        let clone = arc.clone();
+        // reads through its own Arc, swaps it for a Weak, and upgrades that
+        // again, keeping the result until the join
+        let other = thread::spawn(move || {
+            let read = clone.with(|value| unsafe { *value });
+            let weak = Arc::downgrade(&clone);
+            drop(clone);
+            (read, weak.upgrade())
+        });
+        let unwrapped = Arc::try_unwrap(arc);
+        let (read, again) = other.join().unwrap();
+        assert_eq!(read, 1);
+        match unwrapped {
+            // the unwrap came between the drop and the upgrade, so after
+            // the other thread's read, and the upgrade found nothing
+            Ok(value) => {
+                assert!(again.is_none());
+                value.with_mut(|value| unsafe { *value += 1 });
+            }
+            // the clone or the upgraded Arc was still around, and with this
+            // one handed back the upgrade can't have failed
+            Err(arc) => {
+                let again = again.unwrap();
+                assert!(Arc::ptr_eq(&arc, &again));
+                assert_eq!(Arc::strong_count(&arc), 2);
+            }
+        }
+    });
+}
+
+#[test]
+fn new_cyclic_races_upgrade() {
+    loom::model(|| {
+        let mut upgrader = None;
+        let arc = Arc::new_cyclic(|me: &Weak<UnsafeCell<u64>>| {
+            let me = me.clone();
+            // upgrades while the value is still being built, and maybe
+            // after new_cyclic has published it
+            upgrader = Some(thread::spawn(move || {
+                me.upgrade().map(|arc| arc.with(|value| unsafe { *value }))
+            }));
+            let value = UnsafeCell::new(0);
+            value.with_mut(|value| unsafe { *value = 55 });
+            value
+        });
+        // an upgrade that succeeded has to see the whole value
+        if let Some(read) = upgrader.unwrap().join().unwrap() {
+            assert_eq!(read, 55);
+        }
+        assert_eq!(Arc::strong_count(&arc), 1);
+        arc.with(|value| assert_eq!(unsafe { *value }, 55));
+    });
+}
@@ -0,0 +1,148 @@
+// the pool can only be seen from the outside by counting calls to the
+// global allocator, which needs a #[global_allocator] of its own, so these
+// live in their own test binary
+#![cfg(feature = "rc-pool")]
+
+use acell::rc::{drain_pool, set_pool_capacity, Rc};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+// counts per thread, so tests running in parallel don't see each other
+thread_local! {
+    static ALLOCS: Cell<usize> = const { Cell::new(0) };
+    static FREES: Cell<usize> = const { Cell::new(0) };
+}
+
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCS.try_with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _ = FREES.try_with(|n| n.set(n.get() + 1));
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn allocs() -> usize {
+    ALLOCS.with(Cell::get)
+}
+
+fn frees() -> usize {
+    FREES.with(Cell::get)
+}
+
+struct Node {
+    value: u64,
+    drops: std::rc::Rc<Cell<usize>>,
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.drops.set(self.drops.get() + 1);
+    }
+}
+
+#[test]
+fn churn_reuses_allocations() {
+    let drops = std::rc::Rc::new(Cell::new(0));
+    // warm up, so the pool's own bookkeeping is already allocated
+    drop(Rc::new(Node {
+        value: 0,
+        drops: drops.clone(),
+    }));
+
+    // miri is slow enough that a few rounds show the same thing
+    let rounds = if cfg!(miri) { 100 } else { 10_000 };
+    let before = allocs();
+    let mut sum = 0;
+    for i in 0..rounds {
+        let rc = Rc::new(Node {
+            value: i,
+            drops: drops.clone(),
+        });
+        let clone = rc.clone();
+        sum += clone.value;
+    }
+    assert_eq!(allocs() - before, 0);
+    assert_eq!(sum, (0..rounds).sum());
+    assert_eq!(drops.get(), rounds as usize + 1);
+    drain_pool();
+}
+
+#[test]
+fn capacity_is_respected() {
+    set_pool_capacity(2);
+    let rcs: Vec<_> = (0..5u64).map(Rc::new).collect();
+    let frees_before = frees();
+    drop(rcs);
+    // two were kept, the other three went back to the allocator
+    // (plus the Vec's own buffer)
+    assert_eq!(frees() - frees_before, 4);
+
+    let allocs_before = allocs();
+    let rcs: Vec<_> = (0..5u64).map(Rc::new).collect();
+    // the Vec, and the three that weren't in the pool
+    assert_eq!(allocs() - allocs_before, 4);
+    assert_eq!(*rcs[4], 4);
+    drop(rcs);
+
+    // shrinking frees the extras straight away
+    set_pool_capacity(0);
+    let frees_before = frees();
+    drop(Rc::new(1u64));
+    assert_eq!(frees() - frees_before, 1);
+    set_pool_capacity(256);
+}
+
+#[test]
+fn layouts_are_kept_apart() {
+    // warm up both size classes, then empty them
+    drop(Rc::new(1u8));
+    drop(Rc::new([0u64; 8]));
+    drain_pool();
+
+    let before = allocs();
+    drop(Rc::new(1u8));
+    assert_eq!(allocs() - before, 1);
+    // a different size can't reuse the u8's block
+    let big = Rc::new([1u64; 8]);
+    assert_eq!(allocs() - before, 2);
+    drop(big);
+
+    // but the next one that size can, and it's still zeroed on request
+    let zeroed = Rc::<[u64; 8]>::new_zeroed();
+    assert_eq!(allocs() - before, 2);
+    assert_eq!(*unsafe { zeroed.assume_init() }, [0; 8]);
+    assert_eq!(allocs() - before, 2);
+    drain_pool();
+}
+
+#[test]
+fn only_sized_rcs_are_pooled() {
+    use acell::alloc::{Allocator, Global};
+
+    // a slice goes straight back to the allocator, however big it is
+    let slice: Rc<[u8]> = vec![0; 1 << 16].into();
+    let frees_before = frees();
+    drop(slice);
+    assert_eq!(frees() - frees_before, 1);
+    let slice: Rc<[u8]> = Rc::from(&[1, 2, 3][..]);
+    let frees_before = frees();
+    drop(slice);
+    assert_eq!(frees() - frees_before, 1);
+
+    // and so does anything else using Global directly
+    let layout = Layout::new::<[u64; 4]>();
+    let block = Global.allocate(layout).unwrap();
+    let frees_before = frees();
+    unsafe { Global.deallocate(block, layout) };
+    assert_eq!(frees() - frees_before, 1);
+    drain_pool();
+}
@@ -0,0 +1,17 @@
+// may_dangle only lets an Rc<T> outlive T's borrows when T itself doesn't
+// look at them on drop; here it does, so this must still be rejected
+use acell::rc::Rc;
+
+struct Foo<'a>(&'a String);
+
+impl Drop for Foo<'_> {
+    fn drop(&mut self) {
+        println!("{}", self.0);
+    }
+}
+
+fn main() {
+    let _rc;
+    let s = String::from("hello");
+    _rc = Rc::new(Foo(&s));
+}
@@ -0,0 +1,11 @@
+// a Cell can be changed through &Cell, so sharing one between threads is a
+// data race, and an Arc of one mustn't be sent to another thread
+use acell::cell::Cell;
+use acell::sync::Arc;
+
+fn main() {
+    let arc = Arc::new(Cell::new(1));
+    let clone = arc.clone();
+    std::thread::spawn(move || clone.set(2));
+    arc.set(3);
+}
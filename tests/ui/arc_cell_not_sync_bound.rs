@@ -0,0 +1,10 @@
+// the same as arc_cell_not_sync, but asking for Sync directly: &Arc is
+// enough to reach the Cell from two threads
+use acell::cell::Cell;
+use acell::sync::Arc;
+
+fn assert_sync<T: Sync>() {}
+
+fn main() {
+    assert_sync::<Arc<Cell<i32>>>();
+}
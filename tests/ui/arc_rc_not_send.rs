@@ -0,0 +1,12 @@
+// Arc only makes its own counts atomic, the Rc inside would still race on
+// its counts if its last holder dropped it on another thread
+use acell::rc::Rc;
+use acell::sync::Arc;
+
+fn assert_send<T: Send>() {}
+
+fn main() {
+    let arc = Arc::new(Rc::new(5));
+    let _clone = arc.clone();
+    assert_send::<Arc<Rc<i32>>>();
+}
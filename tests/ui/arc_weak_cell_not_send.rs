@@ -0,0 +1,12 @@
+// a Weak can be upgraded on the thread it's sent to, so it has the same
+// bounds as the Arc
+use acell::cell::Cell;
+use acell::sync::{Arc, Weak};
+
+fn assert_send<T: Send>() {}
+
+fn main() {
+    let arc = Arc::new(Cell::new(5));
+    let _weak = Arc::downgrade(&arc);
+    assert_send::<Weak<Cell<i32>>>();
+}
@@ -0,0 +1,12 @@
+// a shared guard hands out &T to every thread it's shared with, so it's only
+// Sync if T is, even though the Mutex itself only needs T: Send
+use acell::cell::Cell;
+use acell::sync::{Mutex, MutexGuard};
+
+fn assert_sync<T: Sync>() {}
+
+fn main() {
+    let mutex = Mutex::new(Cell::new(5));
+    let _guard = mutex.lock().unwrap();
+    assert_sync::<MutexGuard<'_, Cell<i32>>>();
+}
@@ -0,0 +1,11 @@
+// a guard has to be dropped on the thread that locked, since whether it
+// poisons the lock depends on whether that thread is panicking
+use acell::sync::{Mutex, MutexGuard};
+
+fn assert_send<T: Send>() {}
+
+fn main() {
+    let mutex = Mutex::new(5);
+    let _guard = mutex.lock().unwrap();
+    assert_send::<MutexGuard<'_, i32>>();
+}
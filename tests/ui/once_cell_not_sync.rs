@@ -0,0 +1,9 @@
+// get hands out &T while set may be writing, which is only fine on one
+// thread
+use acell::once_cell::OnceCell;
+
+fn assert_sync<T: Sync>() {}
+
+fn main() {
+    assert_sync::<OnceCell<i32>>();
+}
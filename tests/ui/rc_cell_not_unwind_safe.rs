@@ -0,0 +1,10 @@
+// a std Cell can be left half updated by a panic, so an Rc sharing one
+// mustn't cross catch_unwind without AssertUnwindSafe, same as &Cell
+use acell::rc::Rc;
+use std::cell::Cell;
+use std::panic;
+
+fn main() {
+    let rc = Rc::new(Cell::new(1));
+    let _ = panic::catch_unwind(move || rc.set(2));
+}
@@ -0,0 +1,10 @@
+// the counts aren't atomic, so two threads holding clones of the same Rc
+// could race on them
+use acell::rc::Rc;
+use std::thread;
+
+fn main() {
+    let rc = Rc::new(5);
+    let rc2 = rc.clone();
+    thread::spawn(move || drop(rc2));
+}
@@ -0,0 +1,9 @@
+// &Rc is enough to clone it, so sharing &Rc between threads would race on
+// the counts just like sending it
+use acell::rc::Rc;
+
+fn assert_sync<T: Sync>() {}
+
+fn main() {
+    assert_sync::<Rc<i32>>();
+}
@@ -0,0 +1,10 @@
+// a Weak can be upgraded, so it has to stay on the Rc's thread too
+use acell::rc::{Rc, Weak};
+
+fn assert_send<T: Send>() {}
+
+fn main() {
+    let rc = Rc::new(5);
+    let _weak = Rc::downgrade(&rc);
+    assert_send::<Weak<i32>>();
+}